@@ -16,6 +16,10 @@ export const globalStats = writable<GlobalStat>({
 class Aria2Manager {
   private ws: WebSocket | null = null;
   private retryTimer: any = null;
+  private nextId = 0;
+  /// Last history seq we got, sent back on reconnect so nothing is missed
+  private seq: number | undefined;
+  private pending = new Map<string, (reply: { ok: boolean; status: number; data?: any }) => void>();
 
   constructor() {}
 
@@ -24,7 +28,7 @@ class Aria2Manager {
 
     const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
     const host = window.location.host;
-    const url = `${protocol}//${host}/api/ws/dl/history`;
+    const url = `${protocol}//${host}/api/ws`;
 
     connectionState.set('connecting');
     this.ws = new WebSocket(url);
@@ -32,12 +36,16 @@ class Aria2Manager {
     this.ws.onopen = () => {
       console.log("Connected to history Stream");
       connectionState.set('connected');
+      this.send({ op: 'subscribe', topic: 'ticks' });
+      this.send({ op: 'subscribe', topic: 'history', since: this.seq });
     };
 
     this.ws.onclose = () => {
       console.warn("History stream disconnected");
       connectionState.set('disconnected');
       this.ws = null;
+      this.pending.forEach(done => done({ ok: false, status: 0 }));
+      this.pending.clear();
       clearTimeout(this.retryTimer);
       this.retryTimer = setTimeout(() => this.connect(), 5000);
     };
@@ -58,8 +66,8 @@ class Aria2Manager {
           return items.map(item => {
             const update = tasks.find(t => t.gid === item.gid);
             if (update) {
-              return {
-                ...item,
+              // Deltas only carry what changed, keep the rest
+              const changed = {
                 status: update.status,
                 totalLength: update.totalLength,
                 completedLength: update.completedLength,
@@ -67,19 +75,54 @@ class Aria2Manager {
                 downloadSpeed: update.downloadSpeed,
                 uploadSpeed: update.uploadSpeed
               };
+              return {
+                ...item,
+                ...Object.fromEntries(Object.entries(changed).filter(([, v]) => v !== undefined))
+              };
             }
             return item;
           });
         });
 
       } else if (msg.type === 'event') {
+        this.seq = msg.seq;
         this.handleEvent(msg.data);
+      } else if (msg.type === 'sync') {
+        this.seq = msg.seq;
+      } else if (msg.type === 'snapshot') {
+        this.seq = msg.seq;
+        msg.data.forEach(item => this.handleEvent(item));
+      } else if (msg.type === 'reply') {
+        this.pending.get(msg.id)?.(msg);
+        this.pending.delete(msg.id);
       }
     } catch (e) {
       console.error("WS error", e);
     }
   }
 
+  private send(msg: object) {
+    this.ws?.send(JSON.stringify(msg));
+  }
+
+  /*
+   * Runs a command over the socket, falls back to the REST route when it's down
+  */
+  private command(command: string, params: object, fallback: string) {
+    if (this.ws?.readyState !== WebSocket.OPEN) {
+      return fetch(fallback, {
+        method: 'POST',
+        headers: {'Content-Type': 'application/json'},
+        body: JSON.stringify(params)
+      }).then(res => res.ok);
+    }
+    const id = String(++this.nextId);
+    return new Promise<boolean>(resolve => {
+      this.pending.set(id, reply => resolve(reply.ok));
+      this.send({ op: 'command', id, command, params });
+    });
+  }
+
   private handleEvent(newItem: ItemMetaData) {
     historyStore.update(items => {
       const index = items.findIndex(i => i.gid === newItem.gid);
@@ -186,27 +229,15 @@ class Aria2Manager {
   }
 
   async pause(gid: string) {
-    await fetch('/api/aria2/pause', { 
-      method: 'POST', 
-      headers: {'Content-Type': 'application/json'},
-      body: JSON.stringify({ gid }) 
-    });
+    return this.command('pause', { gid }, '/api/aria2/pause');
   }
 
   async resume(gid: string) {
-    await fetch('/api/aria2/resume', { 
-      method: 'POST',
-      headers: {'Content-Type': 'application/json'},
-      body: JSON.stringify({ gid }) 
-    });
+    return this.command('resume', { gid }, '/api/aria2/resume');
   }
 
   async remove(gid: string) {
-    await fetch('/api/aria2/remove', { 
-      method: 'POST',
-      headers: {'Content-Type': 'application/json'},
-      body: JSON.stringify({ gid }) 
-    });
+    return this.command('remove', { gid }, '/api/aria2/remove');
   }
 }
 
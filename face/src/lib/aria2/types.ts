@@ -156,5 +156,12 @@ export interface GlobalStat {
 
 /// the type of task would be the `Aria2Download` 
 export type WsMessage = 
-  | { type: 'tick'; global: GlobalStat; tasks: Aria2Download[] }
-  | { type: 'event'; data: ItemMetaData };
+  /// `full` has whole tasks, otherwise only the gid and the changed keys
+  | { type: 'tick'; topic?: 'ticks'; full: boolean; global: GlobalStat; instance?: GlobalStat; tasks: (Partial<Aria2Download> & { gid: string })[] }
+  | { type: 'event'; topic?: 'history'; seq: number; data: ItemMetaData }
+  /// Where the history starts, resume from `seq` after a reconnect
+  | { type: 'sync'; topic?: 'history'; seq: number }
+  /// Too far behind to replay, the newest rows instead
+  | { type: 'snapshot'; topic?: 'history'; seq: number; total: number; data: ItemMetaData[] }
+  /// Answer to a subscribe or a command on `/api/ws`, same `id` as sent
+  | { type: 'reply'; id: string; status: number; ok: boolean; data?: any };
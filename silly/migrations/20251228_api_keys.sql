@@ -0,0 +1,6 @@
+-- API KEY SCOPES & USAGE
+ALTER TABLE api_keys ADD COLUMN scope TEXT NOT NULL CHECK(scope IN ('full', 'read_only', 'add_only')) DEFAULT 'full';
+ALTER TABLE api_keys ADD COLUMN last_used_at DATETIME;                  -- Updated on every authenticated request
+
+CREATE INDEX IF NOT EXISTS idx_api_keys_prefix ON api_keys(prefix);
+CREATE INDEX IF NOT EXISTS idx_api_keys_user ON api_keys(user_id);
@@ -0,0 +1,16 @@
+-- CATEGORIES
+-- Used by the qBittorrent compatible api, *arr tools group their downloads by it
+CREATE TABLE IF NOT EXISTS categories (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,                                                 -- "tv-sonarr", "radarr"
+    save_path TEXT NOT NULL DEFAULT '',                                 -- Empty means aria2's global `dir`
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+
+    UNIQUE(user_id, name),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+ALTER TABLE download_history ADD COLUMN category TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_history_category ON download_history(user_id, category);
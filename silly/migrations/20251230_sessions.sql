@@ -0,0 +1,19 @@
+-- SESSIONS
+-- Every login is a row, the access jwt carries its id as `sid`
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,                                                -- Random session id
+    user_id INTEGER NOT NULL,
+    refresh_hash TEXT NOT NULL,                                         -- Sha256 of the current refresh secret
+    prev_refresh_hash TEXT,                                             -- Previous one, accepted shortly after a rotation
+    user_agent TEXT,
+    ip TEXT,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP,                    -- Last refresh
+    rotated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL,
+    revoked_at DATETIME,
+
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions(user_id);
@@ -0,0 +1,3 @@
+-- PASSWORD RESETS
+ALTER TABLE users ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT 0;  -- Set by admin reset, cleared by a change
+ALTER TABLE users ADD COLUMN password_changed_at DATETIME;
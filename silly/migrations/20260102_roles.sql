@@ -0,0 +1,32 @@
+-- MORE ROLES
+-- Sqlite can't alter a CHECK, so `users` gets rebuilt the way sqlite's docs do it
+-- `init_db` migrates with foreign keys off, dropping `users` doesn't cascade into
+-- the tables referencing it and checks them all once the migrations are done
+CREATE TABLE users_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    role TEXT NOT NULL CHECK(role IN ('admin', 'operator', 'user', 'uploader', 'viewer')) DEFAULT 'user',
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    must_change_password BOOLEAN NOT NULL DEFAULT 0,
+    password_changed_at DATETIME,
+    totp_secret TEXT,
+    totp_enabled BOOLEAN NOT NULL DEFAULT 0,
+    totp_last_step INTEGER
+);
+
+INSERT INTO users_new (
+    id, username, password_hash, role, created_at, updated_at,
+    must_change_password, password_changed_at, totp_secret, totp_enabled, totp_last_step
+)
+SELECT
+    id, username, password_hash, role, created_at, updated_at,
+    must_change_password, password_changed_at, totp_secret, totp_enabled, totp_last_step
+FROM users;
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE INDEX idx_users_username ON users(username);
+CREATE INDEX idx_users_role ON users(role);
@@ -0,0 +1,3 @@
+-- USER MANAGEMENT
+ALTER TABLE users ADD COLUMN disabled_at DATETIME;                      -- Set while the account is disabled, history is kept
+ALTER TABLE users ADD COLUMN last_login_at DATETIME;                    -- Every new session
@@ -0,0 +1,20 @@
+-- INVITATIONS
+-- Admin made links, the invitee picks the username and password
+CREATE TABLE IF NOT EXISTS invitations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash TEXT NOT NULL UNIQUE,                                    -- Sha256 of the token
+    hint TEXT NOT NULL,                                                 -- First chars to tell them apart
+    role TEXT NOT NULL,                                                 -- Given to whoever registers with it
+    max_uses INTEGER NOT NULL DEFAULT 1,
+    uses INTEGER NOT NULL DEFAULT 0,
+    created_by INTEGER,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL,
+    revoked_at DATETIME,
+
+    FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+-- SELF REGISTRATION
+-- Open sign ups start disabled and pending until an admin approves them
+ALTER TABLE users ADD COLUMN pending_approval BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,17 @@
+-- AUDIT LOG
+-- Who did what, rows outlive the users so no foreign keys
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actor_id INTEGER,                                                   -- NULL when nobody was logged in
+    actor TEXT,                                                         -- Username at the time
+    action TEXT NOT NULL,                                               -- 'add_download', 'delete_user', ...
+    target TEXT,                                                        -- Gid or username
+    ip TEXT,
+    outcome TEXT NOT NULL CHECK(outcome IN ('ok', 'denied', 'failed')),
+    detail TEXT,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_created ON audit_log(created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_actor ON audit_log(actor);
+CREATE INDEX IF NOT EXISTS idx_audit_action ON audit_log(action);
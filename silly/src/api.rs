@@ -1,17 +1,25 @@
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 use axum::{
     Router,
     middleware,
     routing::{get, post, delete},
     response::{IntoResponse, Response},
     extract::{
-        State, ws,
+        State, Query, ws,
         ws::{WebSocket, WebSocketUpgrade, Message},
     },
 };
+use tokio::sync::broadcast::error::RecvError;
 use tower_http::cors::CorsLayer;
+use tracing::warn;
 
-use crate::{web, api, his, his::DdlWsMessage, aria2, auth, app::AppState, auth::types::AuthenticatedUser}; 
+use crate::{web, api, his, audit, aria2, auth, qbit, transmission, app::AppState};
+use crate::{
+    his::{DdlWsMessage, EventCursor, GidStatus, TickDeltas},
+    aria2::types::Aria2JsonRpcResp,
+    middleware::require_permission,
+    auth::types::{AuthenticatedUser, Permission},
+};
 
 #[derive(Serialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -52,68 +60,171 @@ pub async fn sys_status(
     }
 }
 
-/// Event ws handler
+#[derive(Deserialize)]
+pub struct EventWsQuery {
+    /// Everyone's downloads, needs `ManageAllDownloads`
+    pub all: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryWsQuery {
+    /// Last seq the client got, it gets what came after or a snapshot
+    pub since: Option<u64>,
+}
+
+/// An aria2 notification as the caller sees it
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadEvent {
+    /// `downloadStart`, `downloadComplete`, ...
+    pub event: String,
+    pub gid: String,
+    pub name: Option<String>,
+    pub status: Option<GidStatus>,
+    /// Owner, `None` for downloads silly didn't add
+    pub user_id: Option<i64>,
+}
+
+impl DownloadEvent {
+    pub(crate) fn from_notification(state: &AppState, msg: &Aria2JsonRpcResp) -> Option<Self> {
+        let method = msg.method.as_deref()?;
+        let gid = msg.params.as_ref()?
+            .first()?
+            .get("gid")?
+            .as_str()?;
+        let event = method.strip_prefix("aria2.on")?;
+        let event = event[..1].to_lowercase() + &event[1..];
+
+        let known = state.registry.get(gid).map(|d| d.meta);
+
+        Some(DownloadEvent {
+            event,
+            gid: gid.to_string(),
+            name: known.as_ref().and_then(|m| m.name.clone()),
+            status: GidStatus::from_notification(method)
+                .or_else(|| known.as_ref().map(|m| m.status.clone())),
+            user_id: known.as_ref().map(|m| m.user_id),
+        })
+    }
+}
+
+/// Event ws handler, `?all=true` streams every user's downloads
 async fn event_ws(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Query(params): Query<EventWsQuery>,
 ) -> Response {
-    ws.on_upgrade(|socket| event(socket, state))
+    let all = params.all.unwrap_or(false);
+    let perm = if all { Permission::ManageAllDownloads } else { Permission::ViewDownloads };
+    if let Err(e) = require_permission(&user, perm) {
+        return e.into_response();
+    }
+    ws.on_upgrade(move |socket| event(socket, state, user, all))
 }
 
-/// Handle events
+/// Handle events, only the caller's gids unless `all`
 async fn event(
     mut socket: WebSocket,
-    state: AppState
+    state: AppState,
+    user: AuthenticatedUser,
+    all: bool,
 ) {
     let mut rx = state.aria2.events.subscribe();
     
-    while let Ok(msg) = rx.recv().await {
-        // Broadcast the aria2 events
-        let json_text = serde_json::to_string(&msg).unwrap_or_default();
-        if socket.send(ws::Message::Text(json_text.into())).await.is_err() {
+    loop {
+        let msg = match rx.recv().await {
+            Ok(msg) => msg,
+            Err(RecvError::Lagged(n)) => {
+                // Notifications aren't kept, the client has to refetch what it cares about
+                warn!("event socket of '{}' lagged {} notifications", user.username, n);
+                let json = serde_json::json!({ "type": "lagged", "missed": n });
+                if socket.send(ws::Message::Text(json.to_string().into())).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let Some(data) = DownloadEvent::from_notification(&state, &msg) else {
+            continue;
+        };
+        if !all && data.user_id != Some(user.id) {
+            continue;
+        }
+
+        let json = serde_json::json!({
+            "type": "event",
+            "data": data
+        });
+        if socket.send(ws::Message::Text(json.to_string().into())).await.is_err() {
             break;
         }
     }
 }
 
+/// History ws handler, `?since=<seq>` resumes after a reconnect
 pub async fn history_ws(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
     user: AuthenticatedUser,
+    Query(params): Query<HistoryWsQuery>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| history(socket, state, user))
+    ws.on_upgrade(move |socket| history(socket, state, user, params.since))
 }
 
 async fn history(
     mut socket: WebSocket, 
     state: AppState, 
-    user: AuthenticatedUser
+    user: AuthenticatedUser,
+    since: Option<u64>,
 ) {
     let mut rx = state.history_tx.subscribe();
-    while let Ok(msg) = rx.recv().await {
-        match msg {
-            DdlWsMessage::Tick { user_id, global, tasks } => {
-                if user_id == user.id {
-                    let json = serde_json::json!({
-                        "type": "tick",
-                        "global": global,
-                        "tasks": tasks
-                    });
-                    if socket.send(
-                        Message::Text(json.to_string().into())
-                    ).await.is_err() { break; }
+    let _watch = state.watchers.watch(&user);
+    let mut deltas = TickDeltas::default();
+    let (mut cursor, missed) = EventCursor::resume(&state, user.id, since);
+    for json in missed {
+        if socket.send(Message::Text(json.to_string().into())).await.is_err() { return; }
+    }
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(msg) => msg,
+                Err(RecvError::Lagged(n)) => {
+                    // Replay from the log instead of dropping the client, ticks start over whole
+                    warn!("history socket of '{}' lagged {} messages", user.username, n);
+                    deltas = TickDeltas::default();
+                    for json in cursor.catch_up(&state) {
+                        if socket.send(Message::Text(json.to_string().into())).await.is_err() { return; }
+                    }
+                    continue;
                 }
+                Err(RecvError::Closed) => break,
+            },
+            // Only here to notice the close, ticks may be skipped for a long time
+            incoming = socket.recv() => match incoming {
+                Some(Ok(_)) => continue,
+                _ => break,
             },
-            DdlWsMessage::Event { user_id, data } => {
+        };
+        match msg {
+            DdlWsMessage::Tick { user_id, global, instance, tasks } => {
                 if user_id == user.id {
-                    let json = serde_json::json!({
-                        "type": "event",
-                        "data": data
-                    });
+                    let mut stats = serde_json::json!({ "global": global });
+                    if user.can(Permission::ManageServer) {
+                        stats["instance"] = serde_json::json!(instance);
+                    }
+                    let Some(json) = deltas.next(stats, tasks) else { continue };
                     if socket.send(
                         Message::Text(json.to_string().into())
                     ).await.is_err() { break; }
                 }
+            },
+            DdlWsMessage::Event { seq, user_id, data } => {
+                let Some(json) = cursor.event(seq, user_id, &data) else { continue };
+                if socket.send(
+                    Message::Text(json.to_string().into())
+                ).await.is_err() { break; }
             }
         }
     }
@@ -126,13 +237,45 @@ pub fn routes(state: AppState) -> Router {
         .nest("/api/auth", Router::new()
             .route("/me", get(auth::handler::get_me))
             .route("/login", post(auth::handler::login))
+            .route("/login/totp", post(auth::handler::login_totp))
             .route("/logout", post(auth::handler::logout))
+            .route("/refresh", post(auth::handler::refresh))
             .route("/reg/admin", post(auth::handler::reg_admin))
+            .route("/register", post(auth::handler::register))
+            .route("/registration", get(auth::handler::registration_status).post(auth::handler::set_registration))
+            .route("/invitations", get(auth::handler::list_invitations))
+            .route("/invitations/create", post(auth::handler::create_invitation))
+            .route("/invitations/revoke", post(auth::handler::revoke_invitation))
+            .route("/users", get(auth::handler::list_users))
             .route("/user/create", post(auth::handler::create_user))
             .route("/user/delete", post(auth::handler::delete_user))
+            .route("/user/role", post(auth::handler::set_user_role))
+            .route("/user/disable", post(auth::handler::disable_user))
+            .route("/user/enable", post(auth::handler::enable_user))
+            .route("/user/approve", post(auth::handler::approve_user))
+            .route("/user/rename", post(auth::handler::rename_user))
+            .route("/user/downloads/transfer", post(auth::handler::transfer_downloads))
+            .route("/user/password/reset", post(auth::handler::reset_password))
+            .route("/user/lockouts", get(auth::handler::list_lockouts))
+            .route("/user/unlock", post(auth::handler::unlock_login))
+            .route("/user/totp/reset", post(auth::handler::reset_totp))
+            .route("/password/change", post(auth::handler::change_password))
+            .route("/totp/setup", post(auth::handler::totp_setup))
+            .route("/totp/confirm", post(auth::handler::totp_confirm))
+            .route("/totp/disable", post(auth::handler::totp_disable))
+            .route("/keys", get(auth::handler::list_api_keys))
+            .route("/keys/create", post(auth::handler::create_api_key))
+            .route("/keys/revoke", post(auth::handler::revoke_api_key))
+            .route("/sessions", get(auth::handler::list_sessions))
+            .route("/sessions/revoke", post(auth::handler::revoke_session))
+            .route("/sessions/revoke/others", post(auth::handler::revoke_other_sessions))
+            .route("/jwt/rotate", post(auth::handler::rotate_jwt_key))
+            .route("/audit", get(audit::get_audit_log))
+            .route("/audit/retention", get(audit::get_audit_retention).post(audit::set_audit_retention))
             .route("/user/dl/history", get(his::get_history))
             .route("/user/dl/history/delete", delete(his::delete_history))
             .route("/user/dl/history/purge", delete(his::delete_history))
+            .route("/dl/writer", get(his::get_writer_stats))
         )
         
         .nest("/api/aria2", Router::new()
@@ -146,16 +289,58 @@ pub fn routes(state: AppState) -> Router {
             .route("/purge", post(aria2::proxy::purge_results))
             .route("/move", post(aria2::proxy::move_position))
             .route("/global", post(aria2::proxy::change_global_option))
+            .route("/option", post(aria2::proxy::change_option))
+            .route("/stats", get(aria2::proxy::get_rpc_stats))
             .layer(
                 middleware::from_fn_with_state(state.clone(),
                 crate::middleware::auth_guard)
             )
         )
+        /* qBittorrent WebUI api for Sonarr/Radarr/Lidarr */
+        .route("/api/v2/auth/login", post(qbit::handler::login))
+        .nest("/api/v2", Router::new()
+            .route("/auth/logout", post(qbit::handler::logout))
+            .route("/app/version", get(qbit::handler::app_version))
+            .route("/app/webapiVersion", get(qbit::handler::webapi_version))
+            .route("/app/preferences", get(qbit::handler::preferences))
+            .route("/torrents/info", get(qbit::handler::torrents_info))
+            .route("/torrents/properties", get(qbit::handler::torrents_properties))
+            .route("/torrents/files", get(qbit::handler::torrents_files))
+            .route("/torrents/add", post(qbit::handler::torrents_add))
+            .route("/torrents/delete", post(qbit::handler::torrents_delete))
+            .route("/torrents/pause", post(qbit::handler::torrents_pause))
+            .route("/torrents/stop", post(qbit::handler::torrents_pause))
+            .route("/torrents/resume", post(qbit::handler::torrents_resume))
+            .route("/torrents/start", post(qbit::handler::torrents_resume))
+            .route("/torrents/topPrio", post(qbit::handler::torrents_top_prio))
+            .route("/torrents/bottomPrio", post(qbit::handler::torrents_bottom_prio))
+            .route("/torrents/setShareLimits", post(qbit::handler::torrents_set_share_limits))
+            .route("/torrents/categories", get(qbit::handler::categories))
+            .route("/torrents/createCategory", post(qbit::handler::create_category))
+            .route("/torrents/editCategory", post(qbit::handler::edit_category))
+            .route("/torrents/removeCategories", post(qbit::handler::remove_categories))
+            .route("/torrents/setCategory", post(qbit::handler::set_category))
+            .layer(
+                middleware::from_fn_with_state(state.clone(),
+                qbit::handler::qbit_guard)
+            )
+        )
+        /* Transmission rpc, handles its own basic auth and session id */
+        .route("/transmission/rpc", post(transmission::handler::rpc))
+        /* aria2 json-rpc for existing frontends, token is a silly api key */
+        .route("/jsonrpc", get(aria2::rpc::rpc_ws).post(aria2::rpc::rpc_http))
         /* Websockets */ 
+        .route("/api/ws", get(crate::ws::unified_ws))
         .route("/api/ws/event", get(api::event_ws))
         .route("/api/ws/dl/history", get(api::history_ws))
         .route("/api/ws/silly/status", get(api::status_ws))
+        /* Server-sent events, for what can't do websockets */
+        .route("/api/sse/silly/status", get(crate::sse::status_sse))
+        .route("/api/sse/dl/ticks", get(crate::sse::ticks_sse))
+        .route("/api/sse/dl/history", get(crate::sse::history_sse))
         .fallback(web::static_handler)
+        /* Outermost, so every guard sees a fresh access token */
+        .layer(middleware::from_fn_with_state(state.clone(), crate::middleware::session_refresh))
         .layer(CorsLayer::permissive()) // FIX: later fix this
         .with_state(state)
 }
@@ -4,16 +4,26 @@ use tokio::sync::broadcast;
 use sqlx::{Pool, Sqlite};
 
 use crate::{
-    his::DdlWsMessage,
+    his::{DdlWsMessage, EventLog, ProgressWriter, Watchers},
+    registry::DownloadRegistry,
     api::SysStatus,
     aria2::types::Aria2Client,
+    auth::types::{JwtKeys, LoginThrottle, PasswordPolicy, ProxyAuth, SetupToken},
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Pool<Sqlite>,
     pub aria2: Arc<Aria2Client>,
-    pub jwt_secret: String,
+    pub jwt: Arc<JwtKeys>,
+    pub password_policy: PasswordPolicy,
+    pub login_throttle: Arc<LoginThrottle>,
+    pub proxy_auth: Arc<ProxyAuth>,
+    pub setup_token: Arc<SetupToken>,
     pub status_tx: Arc<watch::Sender<SysStatus>>,
     pub history_tx: Arc<broadcast::Sender<DdlWsMessage>>,
+    pub history_log: Arc<EventLog>,
+    pub watchers: Arc<Watchers>,
+    pub progress: Arc<ProgressWriter>,
+    pub registry: Arc<DownloadRegistry>,
 }
@@ -5,8 +5,9 @@ use tokio::time::sleep;
 use std::time::Duration;
 use std::collections::HashMap;
 use futures_util::{SinkExt, StreamExt};
-use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
-use std::sync::{atomic::{AtomicU64, Ordering}, Arc };
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, Semaphore};
+use tokio::sync::mpsc::error::TrySendError;
+use std::sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc };
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use tracing::{error, info, warn};
 
@@ -16,71 +17,169 @@ use crate::{
 
 use super::types::{
     Command,
+    Priority,
+    RpcLimits,
+    RpcStats,
+    LaneStats,
+    Aria2Lanes,
     Aria2Client, 
     Aria2Worker,
     Aria2JsonRpcReq,
     Aria2JsonRpcResp,
 };
 
+/// Queued calls per lane of a connection before `call` has to wait
+const LANE_CAPACITY: usize = 64;
+/// Calls in flight at once, the background ones are few but big
+const INTERACTIVE_IN_FLIGHT: usize = 64;
+const BACKGROUND_IN_FLIGHT: usize = 4;
+
+/// What woke the worker up
+enum Next {
+    Aria2(Option<Result<Message, tokio_tungstenite::tungstenite::Error>>),
+    Call(Option<Command>),
+}
+
+impl RpcLimits {
+    fn lane(&self, priority: Priority) -> &Semaphore {
+        match priority {
+            Priority::Interactive => &self.interactive,
+            Priority::Background => &self.background,
+        }
+    }
+
+    fn count(&self, priority: Priority, f: impl FnOnce(&mut LaneStats)) {
+        let mut stats = self.stats.lock().unwrap();
+        match priority {
+            Priority::Interactive => f(&mut stats.interactive),
+            Priority::Background => f(&mut stats.background),
+        }
+    }
+
+    pub fn stats(&self) -> RpcStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.interactive.in_flight = INTERACTIVE_IN_FLIGHT - self.interactive.available_permits();
+        stats.background.in_flight = BACKGROUND_IN_FLIGHT - self.background.available_permits();
+        stats
+    }
+}
+
 impl Aria2Client {
     pub fn new(
         url: String,
         secret: Option<String>,
+        connections: usize,
         status_tx: Arc<watch::Sender<SysStatus>>,
         resp_tx: broadcast::Sender<Aria2JsonRpcResp>,
     ) -> Self {
-        let (command_tx, command_rx) = mpsc::channel(32);
-
-        let client = Self {
-            command_tx,
-            events: resp_tx.clone(),
-        };
+        let connections = (0..connections.max(1)).map(|i| {
+            let (interactive, interactive_rx) = mpsc::channel(LANE_CAPACITY);
+            let (background, background_rx) = mpsc::channel(LANE_CAPACITY);
+            let url = url.clone();
+            let secret = secret.clone();
+            let resp_tx = resp_tx.clone();
+            let status_tx = status_tx.clone();
 
-        // Spawn the background worker
-        tokio::spawn(async move {
-            Aria2Worker::run(url, secret, command_rx, resp_tx, status_tx).await;
-        });
+            // Spawn the background worker
+            tokio::spawn(async move {
+                Aria2Worker::run(url, secret, i == 0, interactive_rx, background_rx, resp_tx, status_tx).await;
+            });
+            Aria2Lanes { interactive, background }
+        }).collect();
 
-        client
+        Self {
+            connections,
+            next: Arc::new(AtomicUsize::new(0)),
+            limits: Arc::new(RpcLimits {
+                interactive: Semaphore::new(INTERACTIVE_IN_FLIGHT),
+                background: Semaphore::new(BACKGROUND_IN_FLIGHT),
+                stats: std::sync::Mutex::new(RpcStats::default()),
+            }),
+            events: resp_tx,
+        }
     }
 
-    /// For making RPC calls
+    /// For making RPC calls, someone is waiting on these
     pub async fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, String> {
+        self.call_with(Priority::Interactive, method, params).await
+    }
+
+    /// Polling and syncing, never ahead of a user's call
+    pub async fn call_background(&self, method: &str, params: Vec<Value>) -> Result<Value, String> {
+        self.call_with(Priority::Background, method, params).await
+    }
+
+    pub async fn call_with(&self, priority: Priority, method: &str, params: Vec<Value>) -> Result<Value, String> {
+        let limit = self.limits.lane(priority);
+        let _permit = match limit.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.limits.count(priority, |s| s.throttled += 1);
+                limit.acquire().await.map_err(|_| "Aria2 client is closed".to_string())?
+            }
+        };
+        self.limits.count(priority, |s| s.calls += 1);
+
         let (reply_tx, reply_rx) = oneshot::channel();
+        let cmd = Command::Call {
+            method: method.to_string(),
+            params,
+            reply: reply_tx,
+        };
 
-        self.command_tx
-            .send(Command::Call {
-                method: method.to_string(),
-                params,
-                reply: reply_tx,
-            })
-            .await
-            .map_err(|_| "Aria2 worker is dead".to_string())?;
+        let lane = self.lane(priority);
+        match lane.try_send(cmd) {
+            Ok(()) => {}
+            Err(TrySendError::Full(cmd)) => {
+                self.limits.count(priority, |s| s.saturated += 1);
+                warn!("aria2 {:?} queue is full, '{}' has to wait", priority, method);
+                lane.send(cmd).await.map_err(|_| "Aria2 worker is dead".to_string())?;
+            }
+            Err(TrySendError::Closed(_)) => return Err("Aria2 worker is dead".to_string()),
+        }
 
         reply_rx.await.map_err(|_| "Response channel closed".to_string())?
     }
+
+    fn lane(&self, priority: Priority) -> &mpsc::Sender<Command> {
+        let n = self.connections.len();
+        match priority {
+            Priority::Background => &self.connections[n - 1].background,
+            Priority::Interactive => {
+                let shared = if n > 1 { n - 1 } else { 1 };
+                let i = self.next.fetch_add(1, Ordering::Relaxed) % shared;
+                &self.connections[i].interactive
+            }
+        }
+    }
 }
 
 impl Aria2Worker {
     async fn run(
         url: String,
         secret: Option<String>,
-        mut command_rx: mpsc::Receiver<Command>,
+        primary: bool,
+        mut interactive_rx: mpsc::Receiver<Command>,
+        mut background_rx: mpsc::Receiver<Command>,
         resp_tx: broadcast::Sender<Aria2JsonRpcResp>,
         status_tx: Arc<watch::Sender<SysStatus>>,
     ) {
         let worker = Arc::new(Self {
             url: url.clone(),
             secret: secret.clone(),
+            primary,
             id_counter: AtomicU64::new(1),
             pending_requests: Arc::new(Mutex::new(HashMap::new())),
         });
+        let set_alive = |alive: bool| {
+            if primary {
+                status_tx.send_modify(|s| s.aria2_alive = alive);
+            }
+        };
 
         loop {
             /* False before trying to connect */
-            status_tx.send_modify(
-                |s| s.aria2_alive = false
-            );
+            set_alive(false);
 
             info!("Connecting Silly to Aria2 at: {:?}", worker.url);
             let parsed_url = Url::parse(&worker.url).map_err(|e| error!("Invalid url: {:?}", e));
@@ -89,103 +188,56 @@ impl Aria2Worker {
                 Ok((ws_stream, _)) => {
                     info!("Connected to Aria2 daemon!");
                     /* Make it true */
-                    status_tx.send_modify(
-                        |s| s.aria2_alive = true
-                    );
+                    set_alive(true);
                     let (mut write, mut read) = ws_stream.split();
 
                     loop {
-                        tokio::select! {
+                        /* Replies first, then interactive calls, background ones when nothing else waits */
+                        let next = tokio::select! {
+                            biased;
+                            msg = read.next() => Next::Aria2(msg),
+                            cmd = interactive_rx.recv() => Next::Call(cmd),
+                            cmd = background_rx.recv() => Next::Call(cmd),
+                        };
+
+                        match next {
                             /* Sending commands from app to aria2 daemon */
-                            cmd = command_rx.recv() => {
-                                match cmd {
-                                    Some(Command::Call { method, mut params, reply }) => {
-                                        let id = worker.id_counter.fetch_add(1, Ordering::SeqCst).to_string();
-                                        
-                                        /* Inject secret token if needed */
-                                        if let Some(ref s) = worker.secret {
-                                            let token = Value::String(format!("token:{}", s));
-
-                                            if method == "system.multicall" {
-                                                /* 
-                                                  *  Injecting token into every inner call inside the array 
-                                                  *  params[0] is the array of calls: [ {methodName:..., params:[...]}, ... ]
-                                                */
-                                                if let Some(calls_arr) = params.get_mut(0).and_then(|v| v.as_array_mut()) {
-                                                    for call in calls_arr {
-                                                        /* Each 'call' is a json object */
-                                                        if let Some(obj) = call.as_object_mut() {
-                                                            /* Check if inner method needs auth */
-                                                            let needs_token = obj.get("methodName")
-                                                                .and_then(|n| n.as_str())
-                                                                .map(|name| !name.starts_with("system."))
-                                                                .unwrap_or(true);
-
-                                                            if needs_token {
-                                                                /* Inject token at index `0` of the inner params */
-                                                                if let Some(inner_params) = obj.get_mut("params").and_then(|p| p.as_array_mut()) {
-                                                                    inner_params.insert(0, token.clone());
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            } else if !method.starts_with("system.") {
-                                                /* Standard case; single call!! */
-                                                params.insert(0, token);
-                                            }
-                                        }
-
-                                        let method_name = if method.starts_with("system.") {
-                                            method
-                                        } else {
-                                            format!("aria2.{}", method)
-                                        };
-
-                                        let req = Aria2JsonRpcReq {
-                                            id: id.clone(),
-                                            params: params,
-                                            method: method_name,
-                                            jsonrpc: "2.0".into(),
-                                        };
-
-                                        /* Store the reply channel */
-                                        worker.pending_requests.lock().await.insert(id, reply);
-
-                                        let json = serde_json::to_string(&req).unwrap();
-                                        if let Err(e) = write.send(Message::Text(json.into())).await {
-                                            error!("Failed to send to Aria2: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    None => return, /* Channel closed, shutdown */
+                            Next::Call(Some(Command::Call { method, params, reply })) => {
+                                let (id, json) = worker.request(method, params);
+
+                                /* Store the reply channel */
+                                worker.pending_requests.lock().await.insert(id, reply);
+
+                                if let Err(e) = write.send(Message::Text(json.into())).await {
+                                    error!("Failed to send to Aria2: {}", e);
+                                    break;
                                 }
                             }
+                            Next::Call(None) => return, /* Channel closed, shutdown */
 
                             /* Receiving messages from aria2 to app */
-                            msg = read.next() => {
-                                match msg {
-                                    Some(Ok(Message::Text(text))) => {
-                                        worker.handle_message(&text, &resp_tx).await;
-                                    }
-                                    Some(Err(e)) => {
-                                        error!("Aria2 socket error: {}", e);
-                                        break;
-                                    }
-                                    None => {
-                                        warn!("Aria2 socket is closed");
-                                        break;
-                                    }
-                                    _ => {} /* Ignore binary/ping/pong for now */
-                                }
+                            Next::Aria2(Some(Ok(Message::Text(text)))) => {
+                                worker.handle_message(&text, &resp_tx).await;
+                            }
+                            Next::Aria2(Some(Err(e))) => {
+                                error!("Aria2 socket error: {}", e);
+                                break;
                             }
+                            Next::Aria2(None) => {
+                                warn!("Aria2 socket is closed");
+                                break;
+                            }
+                            Next::Aria2(_) => {} /* Ignore binary/ping/pong for now */
                         }
                     }
+
+                    /* Nobody will answer these anymore */
+                    for (_, reply) in worker.pending_requests.lock().await.drain() {
+                        let _ = reply.send(Err("Aria2 connection lost".to_string()));
+                    }
                 }
                 Err(e) => {
-                    status_tx.send_modify(
-                        |s| s.aria2_alive = false
-                    );
+                    set_alive(false);
                     error!("Failed to connect to Aria2: {}. Retrying in 10s...", e);
                     sleep(Duration::from_secs(10)).await;
                 }
@@ -193,6 +245,59 @@ impl Aria2Worker {
         }
     }
 
+    /// The json-rpc request with its id, secret injected
+    fn request(&self, method: String, mut params: Vec<Value>) -> (String, String) {
+        let id = self.id_counter.fetch_add(1, Ordering::SeqCst).to_string();
+
+        /* Inject secret token if needed */
+        if let Some(ref s) = self.secret {
+            let token = Value::String(format!("token:{}", s));
+
+            if method == "system.multicall" {
+                /* 
+                  *  Injecting token into every inner call inside the array 
+                  *  params[0] is the array of calls: [ {methodName:..., params:[...]}, ... ]
+                */
+                if let Some(calls_arr) = params.get_mut(0).and_then(|v| v.as_array_mut()) {
+                    for call in calls_arr {
+                        /* Each 'call' is a json object */
+                        if let Some(obj) = call.as_object_mut() {
+                            /* Check if inner method needs auth */
+                            let needs_token = obj.get("methodName")
+                                .and_then(|n| n.as_str())
+                                .map(|name| !name.starts_with("system."))
+                                .unwrap_or(true);
+
+                            if needs_token {
+                                /* Inject token at index `0` of the inner params */
+                                if let Some(inner_params) = obj.get_mut("params").and_then(|p| p.as_array_mut()) {
+                                    inner_params.insert(0, token.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+            } else if !method.starts_with("system.") {
+                /* Standard case; single call!! */
+                params.insert(0, token);
+            }
+        }
+
+        let method_name = if method.starts_with("system.") {
+            method
+        } else {
+            format!("aria2.{}", method)
+        };
+
+        let req = Aria2JsonRpcReq {
+            id: id.clone(),
+            params,
+            method: method_name,
+            jsonrpc: "2.0".into(),
+        };
+        (id, serde_json::to_string(&req).unwrap())
+    }
+
     async fn handle_message(&self, text: &str, resp_tx: &broadcast::Sender<Aria2JsonRpcResp>) {
         if let Ok(resp) = serde_json::from_str::<Aria2JsonRpcResp>(text) {
             /* Response to request */
@@ -206,8 +311,10 @@ impl Aria2Worker {
                     }
                 }
             }
-            /* Notification event */
-            else if let Some(method) = &resp.method.clone() {
+            /* Notification event, the other connections get the same ones */
+            else if let Some(method) = &resp.method.clone()
+                && self.primary
+            {
                 /* Broadcast it... */
                 let _ = resp_tx.send(resp);
                 info!("Aria2 event: {:?}", method);
@@ -1,3 +1,4 @@
 pub mod aria2;
 pub mod proxy;
 pub mod types;
+pub mod rpc;
@@ -6,26 +6,49 @@ use axum::{
 };
 use serde_json::{json, Value};
 use super::types::{
+    Aria2Passthrough,
     GidRequest,
     MoveReq,
     AddUriReq,
     AddTorrentReq,
     GlobalOptionReq,
+    GidOptionReq,
     BatchAddTorrentRequest,
 };
 use tracing::{info, debug, error};
 use crate::{
     app::AppState,
     his::History,
-    auth::types::AuthenticatedUser
+    audit::{Audit, AuditAction, AuditOutcome},
+    middleware::{require_permission, AuthRejection},
+    auth::types::{AuthenticatedUser, Permission, SessionMeta},
 }; 
 
+/// Role check plus ownership of `gid`, unknown and foreign gids look the same
+async fn require_gid(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    perm: Permission,
+    gid: &str,
+) -> Result<(), AuthRejection> {
+    require_permission(user, perm)?;
+    if !Aria2Passthrough::can_touch(state, user, gid).await {
+        return Err((StatusCode::NOT_FOUND, Json(json!({ "error": format!("GID {} is not found", gid) }))));
+    }
+    Ok(())
+}
+
 /// Add all most all types of URIs
 pub async fn add_uris(
     State(state): State<AppState>,
     user: AuthenticatedUser,
+    meta: SessionMeta,
     Json(payload): Json<AddUriReq>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_permission(&user, Permission::AddDownloads) {
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, None, AuditOutcome::Denied, None).await;
+        return e;
+    }
     /* 
       * Convert list of URIs into a Batch Multicall 
       * addUri([a,b,c]), do [addUri(a), addUri(b), addUri(c)]
@@ -48,7 +71,7 @@ pub async fn add_uris(
         Ok(gids) => {
             info!("addind gids: {:?}", gids);
             if let Some(arr) = gids.as_array() {
-                for (_i, res) in arr.iter().enumerate() {
+                for (res, uri) in arr.iter().zip(&payload.uris) {
                     let gid = res.as_array()
                         .and_then(|g| g.first())
                         .and_then(|v| v.as_str());
@@ -58,6 +81,7 @@ pub async fn add_uris(
                         if let Err(e) = History::uri_his(&state, gid, user.id).await {
                             error!("Failed to create history for user {}, role {}: {}", user.username, user.role, e);
                         }
+                        Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, Some(gid), AuditOutcome::Ok, Some(uri)).await;
                     }
                 }
             }
@@ -66,6 +90,7 @@ pub async fn add_uris(
         },
         Err(e) => {
             error!("Failed to add uri: {}", e);
+            Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, None, AuditOutcome::Failed, None).await;
             (StatusCode::BAD_GATEWAY, Json(json!({ "error": e })))
         }
     }
@@ -77,8 +102,14 @@ pub async fn add_uris(
 #[deprecated(note = "Please use `add_torrents` instead")]
 pub async fn add_torrent(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
     Json(payload): Json<AddTorrentReq>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_permission(&user, Permission::AddDownloads) {
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, None, AuditOutcome::Denied, None).await;
+        return e;
+    }
     let options = Value::Object(payload.options.unwrap_or_default());
     /* 
       *  The empty array is for web seeding URIs,
@@ -94,10 +125,12 @@ pub async fn add_torrent(
     match state.aria2.call("addTorrent", params).await {
         Ok(gid) => {
             info!("`add_torrent` Successfully executed multicall: {}", gid);
+            Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, gid.as_str(), AuditOutcome::Ok, Some("torrent")).await;
             (StatusCode::OK, Json(json!({ "gid": gid })))
         },
         Err(e) => {
             error!("`add_torrent` Failed to executed multicall: {}", e);
+            Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, None, AuditOutcome::Failed, Some("torrent")).await;
             (StatusCode::BAD_GATEWAY, Json(json!({ "error": e })))
         }
     }
@@ -107,8 +140,13 @@ pub async fn add_torrent(
 pub async fn add_torrents(
     State(state): State<AppState>,
     user: AuthenticatedUser,
+    meta: SessionMeta,
     Json(payload): Json<BatchAddTorrentRequest>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_permission(&user, Permission::AddDownloads) {
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, None, AuditOutcome::Denied, None).await;
+        return e;
+    }
     if payload.torrents.is_empty() {
         return (StatusCode::BAD_REQUEST, Json(json!({ "error": "No torrents provided" })));
     }
@@ -145,6 +183,7 @@ pub async fn add_torrents(
                         if let Err(e) = History::torrent_his(&state, gid, user.id).await {
                             error!("Failed to create history for user {}, role {}: {}", user.username, user.role, e);
                         }
+                        Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, Some(gid), AuditOutcome::Ok, Some("torrent")).await;
                     }
                 }
             }
@@ -153,6 +192,7 @@ pub async fn add_torrents(
         }
         Err(e) => { 
             error!("`add_torrents_batch` Failed to batch torrents: {}", e);
+            Audit::record(&state.db, Some(&user), &meta, AuditAction::AddDownload, None, AuditOutcome::Failed, Some("torrent")).await;
             (StatusCode::BAD_GATEWAY, Json(json!({ "error": e })))
         }
     }
@@ -162,8 +202,12 @@ pub async fn add_torrents(
 /// Ref: https://aria2.github.io/manual/en/html/aria2c.html#aria2.forcePause
 pub async fn pause_download(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
     Json(payload): Json<GidRequest>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_gid(&state, &user, Permission::ManageDownloads, &payload.gid).await {
+        return e;
+    }
 
     match state.aria2.call("pause", vec![json!(payload.gid)]).await {
         Ok(_) => {
@@ -180,8 +224,12 @@ pub async fn pause_download(
 /// Resume downloads `--continue` arg
 pub async fn resume_download(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
     Json(payload): Json<GidRequest>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_gid(&state, &user, Permission::ManageDownloads, &payload.gid).await {
+        return e;
+    }
     match state.aria2.call("unpause", vec![json!(payload.gid)]).await {
         Ok(_) => (StatusCode::OK, Json(json!({ "status": "resumed" }))),
         Err(e) => (StatusCode::BAD_GATEWAY, Json(json!({ "error": e }))),
@@ -191,20 +239,32 @@ pub async fn resume_download(
 /// Removing downloads, might later delete that file too!?
 pub async fn remove_download(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
     Json(payload): Json<GidRequest>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_gid(&state, &user, Permission::ManageDownloads, &payload.gid).await {
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::RemoveDownload, Some(&payload.gid), e.0.into(), None).await;
+        return e;
+    }
     // Usually we want 'forceRemove' + 'removeDownloadResult'
     // But for basic API, let's just forceRemove
-    match state.aria2.call("forceRemove", vec![json!(payload.gid)]).await {
+    let res = match state.aria2.call("forceRemove", vec![json!(payload.gid)]).await {
         Ok(_) => (StatusCode::OK, Json(json!({ "status": "removed" }))),
         Err(e) => (StatusCode::BAD_GATEWAY, Json(json!({ "error": e }))),
-    }
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::RemoveDownload, Some(&payload.gid), res.0.into(), None).await;
+    res
 }
 
 pub async fn get_details(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
     Json(payload): Json<GidRequest>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_gid(&state, &user, Permission::ViewDownloads, &payload.gid).await {
+        return e;
+    }
     let params = vec![json!([
         { "methodName": "aria2.getFiles", "params": [payload.gid.clone()] },
         { "methodName": "aria2.getPeers", "params": [payload.gid.clone()] },
@@ -217,17 +277,32 @@ pub async fn get_details(
     }
 }
 
-pub async fn purge_results(State(state): State<AppState>) -> impl IntoResponse {
-    match state.aria2.call("purgeDownloadResult", vec![]).await {
+/// Purges every user's results, not just the caller's
+pub async fn purge_results(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+) -> impl IntoResponse {
+    if let Err(e) = require_permission(&user, Permission::ManageAllDownloads) {
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::PurgeResults, None, AuditOutcome::Denied, None).await;
+        return e;
+    }
+    let res = match state.aria2.call("purgeDownloadResult", vec![]).await {
         Ok(_) => (StatusCode::OK, Json(json!({ "status": "purged" }))),
         Err(e) => (StatusCode::BAD_GATEWAY, Json(json!({ "error": e }))),
-    }
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::PurgeResults, None, res.0.into(), None).await;
+    res
 }
 
 pub async fn move_position(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
     Json(payload): Json<MoveReq>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_gid(&state, &user, Permission::ManageDownloads, &payload.gid).await {
+        return e;
+    }
     let params = vec![
         json!(payload.gid),
         json!(payload.pos),
@@ -244,12 +319,50 @@ pub async fn move_position(
 
 pub async fn change_global_option(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
     Json(payload): Json<GlobalOptionReq>,
 ) -> impl IntoResponse {
+    let detail = json!(payload.options).to_string();
+    if let Err(e) = require_permission(&user, Permission::ManageServer) {
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::ChangeGlobalOption, None, AuditOutcome::Denied, Some(&detail)).await;
+        return e;
+    }
     let params = vec![json!(payload.options)];
 
-    match state.aria2.call("changeGlobalOption", params).await {
+    let res = match state.aria2.call("changeGlobalOption", params).await {
         Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
         Err(e) => (StatusCode::BAD_GATEWAY, Json(json!({ "error": e }))),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::ChangeGlobalOption, None, res.0.into(), Some(&detail)).await;
+    res
+}
+
+/// Options of one download, like `max-download-limit`
+pub async fn change_option(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Json(payload): Json<GidOptionReq>,
+) -> impl IntoResponse {
+    if let Err(e) = require_gid(&state, &user, Permission::ManageDownloads, &payload.gid).await {
+        return e;
+    }
+    match state.aria2.call("changeOption", vec![json!(payload.gid), json!(payload.options)]).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(e) => (StatusCode::BAD_GATEWAY, Json(json!({ "error": e }))),
+    }
+}
+
+/// Calls per priority, in flight and how often they waited, admin only
+pub async fn get_rpc_stats(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    if let Err(e) = require_permission(&user, Permission::ManageServer) {
+        return e.into_response();
     }
+    Json(json!({
+        "connections": state.aria2.connections.len(),
+        "stats": state.aria2.limits.stats(),
+    })).into_response()
 }
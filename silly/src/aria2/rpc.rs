@@ -0,0 +1,442 @@
+use axum::{
+    extract::{
+        Json, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    app::AppState,
+    his::History,
+    audit::{Audit, AuditAction, AuditOutcome},
+    auth::types::{ApiKeyScope, AuthController, AuthenticatedUser, Permission, SessionMeta},
+};
+use super::types::{
+    Aria2Passthrough,
+    RpcCaller,
+    RpcError,
+};
+
+/// Only look at the caller's gids
+const READ_METHODS: &[&str] = &[
+    "tellStatus", "getUris", "getFiles", "getPeers", "getServers",
+    "tellActive", "tellWaiting", "tellStopped", "getOption",
+    "getGlobalOption", "getGlobalStat", "getVersion", "getSessionInfo",
+];
+const ADD_METHODS: &[&str] = &["addUri", "addTorrent", "addMetalink"];
+/// First param is a gid the caller must own
+const GID_METHODS: &[&str] = &[
+    "remove", "forceRemove", "pause", "forcePause", "unpause",
+    "tellStatus", "getUris", "getFiles", "getPeers", "getServers",
+    "changePosition", "changeUri", "getOption", "changeOption",
+    "removeDownloadResult",
+];
+/// Touch the whole daemon
+const ADMIN_METHODS: &[&str] = &["changeGlobalOption", "saveSession", "shutdown", "forceShutdown"];
+/// Big enough to fetch every waiting/stopped download before filtering
+const TELL_ALL: i64 = 100000;
+
+impl Aria2Passthrough {
+    /// Single request or a batch array
+    pub async fn handle_payload(state: &AppState, caller: &mut RpcCaller, payload: Value) -> Value {
+        match payload {
+            Value::Array(reqs) => {
+                let mut out = Vec::with_capacity(reqs.len());
+                for req in reqs {
+                    out.push(Self::handle_request(state, caller, req).await);
+                }
+                Value::Array(out)
+            }
+            req => Self::handle_request(state, caller, req).await,
+        }
+    }
+
+    async fn handle_request(state: &AppState, caller: &mut RpcCaller, req: Value) -> Value {
+        let id = req.get("id").cloned().unwrap_or(Value::Null);
+        let method = req.get("method").and_then(|m| m.as_str()).unwrap_or_default().to_string();
+        let params = req.get("params")
+            .and_then(|p| p.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        let result = if method == "system.multicall" {
+            Self::multicall(state, caller, params).await
+        } else {
+            Self::call(state, caller, &method, params).await
+        };
+
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message }
+            }),
+        }
+    }
+
+    /// Every inner call goes through the same checks, results are `[result]` or an error object
+    async fn multicall(state: &AppState, caller: &mut RpcCaller, params: Vec<Value>) -> Result<Value, RpcError> {
+        let calls = params.first()
+            .and_then(|c| c.as_array())
+            .ok_or_else(|| RpcError::new("Bad input"))?;
+
+        let mut out = Vec::with_capacity(calls.len());
+        for call in calls {
+            let method = call.get("methodName").and_then(|m| m.as_str()).unwrap_or_default();
+            let inner = call.get("params").and_then(|p| p.as_array()).cloned().unwrap_or_default();
+            if method == "system.multicall" {
+                out.push(json!({ "code": 1, "message": "Recursive system.multicall forbidden." }));
+                continue;
+            }
+            match Self::call(state, caller, method, inner).await {
+                Ok(res) => out.push(json!([res])),
+                Err(e) => out.push(json!({ "code": e.code, "message": e.message })),
+            }
+        }
+        Ok(Value::Array(out))
+    }
+
+    /// Swap the `token:` param for the caller, a connection stays bound to its first user
+    async fn authorize(state: &AppState, caller: &mut RpcCaller, params: &mut Vec<Value>) -> Result<(), RpcError> {
+        let token = params.first()
+            .and_then(|t| t.as_str())
+            .and_then(|t| t.strip_prefix("token:"))
+            .map(|t| t.to_string());
+
+        let Some(token) = token else {
+            return Err(RpcError::new("Unauthorized"));
+        };
+        params.remove(0);
+
+        if caller.token.as_deref() == Some(token.as_str()) {
+            return Ok(());
+        }
+
+        let (user, scope) = AuthController::verify_api_key(&state.db, &token)
+            .await
+            .map_err(|_| RpcError::new("Unauthorized"))?;
+
+        if caller.user.as_ref().is_some_and(|u| u.id != user.id) {
+            return Err(RpcError::new("Unauthorized"));
+        }
+
+        caller.token = Some(token);
+        caller.user = Some(user);
+        caller.scope = scope;
+        Ok(())
+    }
+
+    async fn call(
+        state: &AppState,
+        caller: &mut RpcCaller,
+        method: &str,
+        mut params: Vec<Value>,
+    ) -> Result<Value, RpcError> {
+        /* Harmless, aria2 doesn't want a token either */
+        if matches!(method, "system.listMethods" | "system.listNotifications") {
+            return Ok(state.aria2.call(method, params).await?);
+        }
+
+        let name = method.strip_prefix("aria2.").ok_or_else(|| RpcError::new("No such method"))?;
+        Self::authorize(state, caller, &mut params).await?;
+        let Some(user) = caller.user.clone() else {
+            return Err(RpcError::new("Unauthorized"));
+        };
+
+        let read = READ_METHODS.contains(&name);
+        let allowed = match caller.scope {
+            ApiKeyScope::Full => true,
+            ApiKeyScope::ReadOnly => read,
+            ApiKeyScope::AddOnly => read || ADD_METHODS.contains(&name),
+        };
+        if !allowed {
+            return Err(RpcError::new("Api key scope does not allow this method"));
+        }
+
+        let needed = if ADMIN_METHODS.contains(&name) {
+            Permission::ManageServer
+        } else if read {
+            Permission::ViewDownloads
+        } else if ADD_METHODS.contains(&name) {
+            Permission::AddDownloads
+        } else {
+            Permission::ManageDownloads
+        };
+        if !user.can(needed) {
+            return Err(RpcError::new(format!("Role '{}' can't {}", user.role, needed.as_str())));
+        }
+
+        if GID_METHODS.contains(&name) {
+            let gid = params.first()
+                .and_then(|g| g.as_str())
+                .ok_or_else(|| RpcError::new("Bad input"))?;
+            if !Self::can_touch(state, &user, gid).await {
+                /* Same message as aria2, don't leak other's gids */
+                return Err(RpcError::new(format!("GID {} is not found", gid)));
+            }
+        }
+
+        match name {
+            "tellActive" | "tellWaiting" | "tellStopped" => {
+                Self::tell_list(state, user.id, name, params).await
+            }
+            "getGlobalStat" => Self::global_stat(state, user.id).await,
+            "pauseAll" | "forcePauseAll" | "unpauseAll" => {
+                let single = match name {
+                    "pauseAll" => "pause",
+                    "forcePauseAll" => "forcePause",
+                    _ => "unpause",
+                };
+                for gid in Self::owned(state, user.id).await {
+                    let _ = state.aria2.call(single, vec![json!(gid)]).await;
+                }
+                Ok(json!("OK"))
+            }
+            "purgeDownloadResult" => {
+                for gid in Self::owned(state, user.id).await {
+                    let _ = state.aria2.call("removeDownloadResult", vec![json!(gid)]).await;
+                }
+                Audit::record(&state.db, Some(&user), &caller.meta, AuditAction::PurgeResults, None, AuditOutcome::Ok, None).await;
+                Ok(json!("OK"))
+            }
+            "addUri" | "addTorrent" | "addMetalink" => {
+                let res = state.aria2.call(name, params).await?;
+                let gids: Vec<&str> = match &res {
+                    Value::String(gid) => vec![gid.as_str()],
+                    Value::Array(arr) => arr.iter().filter_map(|g| g.as_str()).collect(),
+                    _ => vec![],
+                };
+                for gid in gids {
+                    let his = if name == "addTorrent" {
+                        History::torrent_his(state, gid, user.id).await
+                    } else {
+                        History::uri_his(state, gid, user.id).await
+                    };
+                    if let Err(e) = his {
+                        error!("Failed to create history for user {}, role {}: {}", user.username, user.role, e);
+                    }
+                }
+                info!("passthrough {} by {}: {}", name, user.username, res);
+                Ok(res)
+            }
+            "remove" | "forceRemove" | "removeDownloadResult" => {
+                let action = if name == "removeDownloadResult" {
+                    AuditAction::PurgeResults
+                } else {
+                    AuditAction::RemoveDownload
+                };
+                let gid = params.first().and_then(|g| g.as_str()).map(str::to_string);
+                let res = state.aria2.call(name, params).await;
+                let outcome = if res.is_ok() { AuditOutcome::Ok } else { AuditOutcome::Failed };
+                Audit::record(&state.db, Some(&user), &caller.meta, action, gid.as_deref(), outcome, None).await;
+                Ok(res?)
+            }
+            _ => Ok(state.aria2.call(name, params).await?),
+        }
+    }
+
+    /// Own downloads, or anyone's with `ManageAllDownloads`
+    pub(crate) async fn can_touch(state: &AppState, user: &AuthenticatedUser, gid: &str) -> bool {
+        user.can(Permission::ManageAllDownloads) || Self::owns(state, user.id, gid).await
+    }
+
+    async fn owns(state: &AppState, user_id: i64, gid: &str) -> bool {
+        state.registry.owner(gid) == Some(user_id)
+    }
+
+    async fn owned(state: &AppState, user_id: i64) -> HashSet<String> {
+        state.registry.owned(user_id).into_iter().collect()
+    }
+
+    /*
+      * Fetch everything, keep the caller's gids, then paginate like aria2
+      * Negative offset counts from the end and returns in reverse order
+    */
+    async fn tell_list(
+        state: &AppState,
+        user_id: i64,
+        method: &str,
+        params: Vec<Value>,
+    ) -> Result<Value, RpcError> {
+        let (offset, num, keys) = if method == "tellActive" {
+            (0, i64::MAX, params.first().cloned())
+        } else {
+            (
+                params.first().and_then(|o| o.as_i64()).unwrap_or(0),
+                params.get(1).and_then(|n| n.as_i64()).unwrap_or(0),
+                params.get(2).cloned(),
+            )
+        };
+
+        /* Need `gid` and `belongsTo` to filter, drop them after if not asked */
+        let asked: Option<Vec<String>> = keys
+            .and_then(|k| serde_json::from_value(k).ok())
+            .filter(|k: &Vec<String>| !k.is_empty());
+        let mut fetch_keys = asked.clone();
+        if let Some(k) = fetch_keys.as_mut() {
+            for key in ["gid", "belongsTo"] {
+                if !k.iter().any(|x| x == key) {
+                    k.push(key.to_string());
+                }
+            }
+        }
+
+        let forward = match (method, &fetch_keys) {
+            ("tellActive", Some(k)) => vec![json!(k)],
+            ("tellActive", None) => vec![],
+            (_, Some(k)) => vec![json!(0), json!(TELL_ALL), json!(k)],
+            (_, None) => vec![json!(0), json!(TELL_ALL)],
+        };
+        let res = state.aria2.call(method, forward).await?;
+
+        let owned = Self::owned(state, user_id).await;
+        let mut items = Vec::new();
+        for item in res.as_array().cloned().unwrap_or_default() {
+            let gid = item.get("gid").and_then(|g| g.as_str()).unwrap_or_default();
+            if owned.contains(gid) {
+                items.push(item);
+                continue;
+            }
+            /* Real download of a magnet the caller added */
+            let parent = item.get("belongsTo").and_then(|g| g.as_str()).unwrap_or_default();
+            if owned.contains(parent) {
+                debug!("passthrough adopting {} of {}", gid, parent);
+                History::adopt_followed(state, user_id, parent, &[gid.to_string()]).await;
+                items.push(item);
+            }
+        }
+
+        if offset < 0 {
+            items.reverse();
+        }
+        let skip = if offset < 0 { (-offset - 1) as usize } else { offset as usize };
+        let mut items: Vec<Value> = items.into_iter()
+            .skip(skip)
+            .take(num.max(0) as usize)
+            .collect();
+
+        if let Some(asked) = &asked {
+            for item in items.iter_mut().filter_map(|i| i.as_object_mut()) {
+                item.retain(|k, _| asked.contains(k));
+            }
+        }
+
+        Ok(Value::Array(items))
+    }
+
+    /// Same shape as aria2's, numbers of the caller's downloads only
+    async fn global_stat(state: &AppState, user_id: i64) -> Result<Value, RpcError> {
+        let keys = || json!(["gid", "belongsTo", "downloadSpeed", "uploadSpeed"]);
+        let active = Self::tell_list(state, user_id, "tellActive", vec![keys()]).await?;
+        let waiting = Self::tell_list(state, user_id, "tellWaiting", vec![json!(0), json!(TELL_ALL), keys()]).await?;
+        let stopped = Self::tell_list(state, user_id, "tellStopped", vec![json!(0), json!(TELL_ALL), keys()]).await?;
+
+        let count = |v: &Value| v.as_array().map(|a| a.len()).unwrap_or(0);
+        let speed = |key: &str| active.as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|i| i.get(key).and_then(|s| s.as_str()).and_then(|s| s.parse::<i64>().ok()))
+            .sum::<i64>();
+
+        Ok(json!({
+            "downloadSpeed": speed("downloadSpeed").to_string(),
+            "uploadSpeed": speed("uploadSpeed").to_string(),
+            "numActive": count(&active).to_string(),
+            "numWaiting": count(&waiting).to_string(),
+            "numStopped": count(&stopped).to_string(),
+            "numStoppedTotal": count(&stopped).to_string(),
+        }))
+    }
+}
+
+/// HTTP POST `/jsonrpc`, frontends don't always send a json content type
+pub async fn rpc_http(
+    State(state): State<AppState>,
+    meta: SessionMeta,
+    body: String,
+) -> impl IntoResponse {
+    let Ok(payload) = serde_json::from_str::<Value>(&body) else {
+        return Json(json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": { "code": -32700, "message": "Parse error." }
+        }));
+    };
+    let mut caller = RpcCaller { meta, ..Default::default() };
+    Json(Aria2Passthrough::handle_payload(&state, &mut caller, payload).await)
+}
+
+/// WebSocket `/jsonrpc`, notifications only for the bound user's gids
+pub async fn rpc_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    meta: SessionMeta,
+) -> Response {
+    ws.on_upgrade(|socket| rpc_socket(socket, state, meta))
+}
+
+async fn rpc_socket(mut socket: WebSocket, state: AppState, meta: SessionMeta) {
+    let mut caller = RpcCaller { meta, ..Default::default() };
+    let mut events = state.aria2.events.subscribe();
+
+    loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                    Some(Ok(_)) => continue,
+                };
+
+                let reply = match serde_json::from_str::<Value>(&text) {
+                    Ok(payload) => Aria2Passthrough::handle_payload(&state, &mut caller, payload).await,
+                    Err(_) => json!({
+                        "jsonrpc": "2.0",
+                        "id": null,
+                        "error": { "code": -32700, "message": "Parse error." }
+                    }),
+                };
+                if socket.send(Message::Text(reply.to_string().into())).await.is_err() {
+                    break;
+                }
+            }
+
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("passthrough socket lagged {} notifications", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let (Some(user), Some(method)) = (&caller.user, &event.method) else {
+                    continue;
+                };
+                let gid = event.params.as_ref()
+                    .and_then(|p| p.first())
+                    .and_then(|p| p.get("gid"))
+                    .and_then(|g| g.as_str())
+                    .unwrap_or_default();
+                if !Aria2Passthrough::owns(&state, user.id, gid).await {
+                    continue;
+                }
+
+                let note = json!({
+                    "jsonrpc": "2.0",
+                    "method": method,
+                    "params": event.params,
+                });
+                if socket.send(Message::Text(note.to_string().into())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
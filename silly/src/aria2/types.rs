@@ -1,8 +1,9 @@
 use serde_json::Value;
 use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
-use std::sync::{atomic::{AtomicU64}, Arc };
-use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use crate::auth::types::{ApiKeyScope, AuthenticatedUser, SessionMeta};
+use std::sync::{atomic::{AtomicU64, AtomicUsize}, Arc };
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, Semaphore};
 
 #[derive(Deserialize, Debug)]
 pub struct GidRequest {
@@ -41,6 +42,12 @@ pub struct GlobalOptionReq {
     pub options: serde_json::Map<String, Value>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct GidOptionReq {
+    pub gid: String,
+    pub options: serde_json::Map<String, Value>,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MoveReq {
@@ -77,18 +84,101 @@ pub enum Command {
     },
 }
 
+/// Interactive calls go ahead of the background polling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Interactive,
+    Background,
+}
+
+/// Queues into one aria2 connection
+#[derive(Clone)]
+pub struct Aria2Lanes {
+    pub interactive: mpsc::Sender<Command>,
+    pub background: mpsc::Sender<Command>,
+}
+
 #[derive(Clone)]
 pub struct Aria2Client {
-    pub command_tx: mpsc::Sender<Command>,
+    /// One per aria2 connection, with more than one the last is left to the background
+    pub connections: Vec<Aria2Lanes>,
+    pub next: Arc<AtomicUsize>,
+    pub limits: Arc<RpcLimits>,
     /// Broadcast channel for aria2 events
     pub events: broadcast::Sender<Aria2JsonRpcResp>, 
 }
 
+/// Calls in flight per priority, and how often they had to wait
+#[derive(Debug)]
+pub struct RpcLimits {
+    pub interactive: Semaphore,
+    pub background: Semaphore,
+    pub stats: std::sync::Mutex<RpcStats>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcStats {
+    pub interactive: LaneStats,
+    pub background: LaneStats,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LaneStats {
+    pub calls: u64,
+    pub in_flight: usize,
+    /// Waited for the concurrency limit
+    pub throttled: u64,
+    /// Waited because the connection's queue was full
+    pub saturated: u64,
+}
+
 #[derive(Debug)]
 pub struct Aria2Worker {
     pub url: String,
     pub secret: Option<String>,
+    /// Only the first connection reports the status and broadcasts events, aria2 sends them to all
+    pub primary: bool,
     pub id_counter: AtomicU64,
     /// Maps request id to reply channel
     pub pending_requests: Arc<Mutex<HashMap<String, oneshot::Sender<Result<Value, String>>>>>,
 }
+
+/// aria2 compatible json-rpc for AriaNg & co, the `token:` is a silly api key
+pub struct Aria2Passthrough;
+
+/// Who is talking on a passthrough connection, bound by the first valid token
+#[derive(Debug, Clone, Default)]
+pub struct RpcCaller {
+    pub token: Option<String>,
+    pub user: Option<AuthenticatedUser>,
+    pub scope: ApiKeyScope,
+    pub meta: SessionMeta,
+}
+
+/// aria2 always answers errors with code 1
+#[derive(Debug, Clone)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self { code: 1, message: message.into() }
+    }
+}
+
+/// `Aria2Client::call` errors are aria2's error object as a string
+impl From<String> for RpcError {
+    fn from(err: String) -> Self {
+        match serde_json::from_str::<Value>(&err) {
+            Ok(v) => Self {
+                code: v.get("code").and_then(|c| c.as_i64()).unwrap_or(1),
+                message: v.get("message").and_then(|m| m.as_str()).unwrap_or(&err).to_string(),
+            },
+            Err(_) => Self::new(err),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use axum::{
+    Json,
+    extract::{State, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::NaiveDateTime;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use sqlx::{Pool, Sqlite, QueryBuilder};
+use tracing::{info, warn};
+
+use crate::{
+    AppState,
+    db::{get_setting, set_setting},
+    middleware::require_permission,
+    auth::types::{AuthenticatedUser, Permission, SessionMeta},
+};
+
+pub struct Audit;
+
+const RETENTION_KEY: &str = "audit_retention_days";
+const DEFAULT_RETENTION_DAYS: i64 = 90;
+const MAX_PAGE_SIZE: u32 = 500;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type, PartialEq)]
+#[sqlx(type_name = "TEXT")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    RegisterAdmin,
+    Register,
+    CreateUser,
+    DeleteUser,
+    SetRole,
+    DisableUser,
+    EnableUser,
+    ApproveUser,
+    RenameUser,
+    TransferDownloads,
+    ChangePassword,
+    ResetPassword,
+    UnlockLogin,
+    EnableTotp,
+    DisableTotp,
+    ResetTotp,
+    CreateApiKey,
+    RevokeApiKey,
+    CreateInvitation,
+    RevokeInvitation,
+    SetRegistration,
+    RotateJwtKey,
+    AddDownload,
+    RemoveDownload,
+    PurgeResults,
+    ChangeGlobalOption,
+    DeleteHistory,
+    SetAuditRetention,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type, PartialEq)]
+#[sqlx(type_name = "TEXT")]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Ok,
+    /// Not logged in or not allowed
+    Denied,
+    Failed,
+}
+
+/// Handlers answer with a status, that's the outcome
+impl From<StatusCode> for AuditOutcome {
+    fn from(status: StatusCode) -> Self {
+        if status.is_success() {
+            AuditOutcome::Ok
+        } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            AuditOutcome::Denied
+        } else {
+            AuditOutcome::Failed
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor_id: Option<i64>,
+    pub actor: Option<String>,
+    pub action: AuditAction,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub outcome: AuditOutcome,
+    pub detail: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    pub target: Option<String>,
+    pub outcome: Option<AuditOutcome>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub page: Option<u32>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditRetentionReq {
+    /// 0 keeps everything
+    pub days: i64,
+}
+
+impl Audit {
+    /// Never fails the action, a lost row only gets logged
+    pub async fn record(
+        pool: &Pool<Sqlite>,
+        actor: Option<&AuthenticatedUser>,
+        meta: &SessionMeta,
+        action: AuditAction,
+        target: Option<&str>,
+        outcome: AuditOutcome,
+        detail: Option<&str>,
+    ) {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO audit_log (actor_id, actor, action, target, ip, outcome, detail)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#
+        )
+        .bind(actor.map(|u| u.id))
+        .bind(actor.map(|u| u.username.as_str()))
+        .bind(action)
+        .bind(target)
+        .bind(meta.ip.as_deref())
+        .bind(outcome)
+        .bind(detail)
+        .execute(pool)
+        .await;
+
+        if let Err(e) = res {
+            warn!("Failed to write audit log {:?} on {:?}: {}", action, target, e);
+        }
+    }
+
+    pub async fn retention_days(pool: &Pool<Sqlite>) -> Result<i64, sqlx::Error> {
+        Ok(get_setting(pool, RETENTION_KEY)
+            .await?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_RETENTION_DAYS))
+    }
+
+    /// Drop rows older than the retention, returns how many
+    pub async fn prune(pool: &Pool<Sqlite>) -> Result<u64, sqlx::Error> {
+        let days = Self::retention_days(pool).await?;
+        if days <= 0 {
+            return Ok(0);
+        }
+        let res = sqlx::query("DELETE FROM audit_log WHERE created_at < datetime('now', ?)")
+            .bind(format!("-{} days", days))
+            .execute(pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    fn push_filters<'a>(qb: &mut QueryBuilder<'a, Sqlite>, q: &'a AuditQuery) {
+        qb.push(" WHERE 1 = 1");
+        if let Some(actor) = &q.actor {
+            qb.push(" AND actor = ").push_bind(actor);
+        }
+        if let Some(action) = q.action {
+            qb.push(" AND action = ").push_bind(action);
+        }
+        if let Some(target) = &q.target {
+            qb.push(" AND target = ").push_bind(target);
+        }
+        if let Some(outcome) = q.outcome {
+            qb.push(" AND outcome = ").push_bind(outcome);
+        }
+        if let Some(since) = q.since {
+            qb.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = q.until {
+            qb.push(" AND created_at < ").push_bind(until);
+        }
+    }
+}
+
+/// Newest first, every filter is optional
+pub async fn get_audit_log(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Query(params): Query<AuditQuery>,
+) -> Response {
+    if let Err(e) = require_permission(&user, Permission::ManageServer) {
+        return e.into_response();
+    }
+    let page = params.page.unwrap_or(1).max(1);
+    let limit = params.limit.unwrap_or(50).clamp(1, MAX_PAGE_SIZE);
+    /* In i64, a huge `page` would overflow the u32 */
+    let offset = i64::from(page - 1) * i64::from(limit);
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM audit_log");
+    Audit::push_filters(&mut count, &params);
+    let total_items: i64 = match count.build_query_scalar().fetch_one(&state.db).await {
+        Ok(n) => n,
+        Err(e) => return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e.to_string() }))
+        ).into_response(),
+    };
+
+    let mut select = QueryBuilder::new(
+        "SELECT id, actor_id, actor, action, target, ip, outcome, detail, created_at FROM audit_log"
+    );
+    Audit::push_filters(&mut select, &params);
+    select.push(" ORDER BY id DESC LIMIT ").push_bind(limit).push(" OFFSET ").push_bind(offset);
+    let entries: Vec<AuditEntry> = match select.build_query_as().fetch_all(&state.db).await {
+        Ok(rows) => rows,
+        Err(e) => return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e.to_string() }))
+        ).into_response(),
+    };
+
+    let total_pages = (total_items as f64 / limit as f64).ceil() as u32;
+    Json(json!({
+        "data": entries,
+        "meta": {
+            "currentPage": page,
+            "perPage": limit,
+            "totalItems": total_items,
+            "totalPages": total_pages
+        }
+    })).into_response()
+}
+
+pub async fn get_audit_retention(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> Response {
+    if let Err(e) = require_permission(&user, Permission::ManageServer) {
+        return e.into_response();
+    }
+    match Audit::retention_days(&state.db).await {
+        Ok(days) => Json(json!({ "days": days })).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e.to_string() }))
+        ).into_response(),
+    }
+}
+
+/// Prunes right away with the new value
+pub async fn set_audit_retention(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<AuditRetentionReq>,
+) -> Response {
+    if let Err(e) = require_permission(&user, Permission::ManageServer) {
+        return e.into_response();
+    }
+    if payload.days < 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Retention can't be negative" }))
+        ).into_response();
+    }
+
+    let detail = payload.days.to_string();
+    let res = async {
+        set_setting(&state.db, RETENTION_KEY, &detail).await?;
+        Audit::prune(&state.db).await
+    }.await;
+    let outcome = if res.is_ok() { AuditOutcome::Ok } else { AuditOutcome::Failed };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::SetAuditRetention, None, outcome, Some(&detail)).await;
+
+    match res {
+        Ok(pruned) => {
+            info!("Audit retention set to {} day(s) by '{}', pruned {}", payload.days, user.username, pruned);
+            Json(json!({ "status": "ok", "days": payload.days, "pruned": pruned })).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e.to_string() }))
+        ).into_response(),
+    }
+}
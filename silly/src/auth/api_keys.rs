@@ -0,0 +1,156 @@
+use rand::Rng;
+use tracing::{info, warn};
+use axum::http::Method;
+use sqlx::{Pool, Sqlite, Row};
+use super::types::{
+    ApiKey, ApiKeyScope,
+    AuthController, AuthError,
+    AuthenticatedUser,
+};
+
+/// Every key looks like `silly_<prefix><secret>`
+pub const API_KEY_TAG: &str = "silly_";
+const API_KEY_PREFIX_LEN: usize = 8;
+const API_KEY_SECRET_LEN: usize = 32;
+
+impl ApiKeyScope {
+    /// `path` must be the full uri path, not the one stripped by `nest`
+    pub fn allows(&self, method: &Method, path: &str) -> bool {
+        let read = *method == Method::GET || path == "/api/aria2/details";
+        match self {
+            ApiKeyScope::Full => true,
+            ApiKeyScope::ReadOnly => read,
+            ApiKeyScope::AddOnly => {
+                read || path.starts_with("/api/aria2/add") || path == "/api/v2/torrents/add"
+            },
+        }
+    }
+}
+
+impl AuthController {
+    pub(super) fn random_string(len: usize) -> String {
+        rand::rng()
+            .sample_iter(&rand::distr::Alphanumeric)
+            .take(len)
+            .map(char::from)
+            .collect()
+    }
+
+    /// Returns the stored key and the plain key, plain one is shown only once!!
+    pub async fn create_api_key(
+        pool: &Pool<Sqlite>,
+        user_id: i64,
+        label: &str,
+        scope: ApiKeyScope,
+    ) -> Result<(ApiKey, String), AuthError> {
+        let prefix = Self::random_string(API_KEY_PREFIX_LEN);
+        let secret = Self::random_string(API_KEY_SECRET_LEN);
+        let plain = format!("{}{}{}", API_KEY_TAG, prefix, secret);
+        let key_hash = Self::hash_password(&plain)?;
+
+        let key = sqlx::query_as::<_, ApiKey>(
+            r#"
+            INSERT INTO api_keys (user_id, label, key_hash, prefix, scope)
+            VALUES (?, ?, ?, ?, ?)
+            RETURNING id, label, prefix, scope, created_at, last_used_at
+            "#
+        )
+        .bind(user_id)
+        .bind(label)
+        .bind(key_hash)
+        .bind(&prefix)
+        .bind(scope)
+        .fetch_one(pool)
+        .await?;
+
+        info!("Api key '{}' ({}) created for user id {}", label, prefix, user_id);
+        Ok((key, plain))
+    }
+
+    pub async fn list_api_keys(
+        pool: &Pool<Sqlite>,
+        user_id: i64,
+    ) -> Result<Vec<ApiKey>, AuthError> {
+        let keys = sqlx::query_as::<_, ApiKey>(
+            r#"
+            SELECT id, label, prefix, scope, created_at, last_used_at
+            FROM api_keys WHERE user_id = ?
+            ORDER BY created_at DESC
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(keys)
+    }
+
+    /// Users can only revoke their own keys
+    pub async fn revoke_api_key(
+        pool: &Pool<Sqlite>,
+        user_id: i64,
+        key_id: i64,
+    ) -> Result<(), AuthError> {
+        let res = sqlx::query("DELETE FROM api_keys WHERE id = ? AND user_id = ?")
+            .bind(key_id)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(AuthError::ApiKeyNotFound);
+        }
+
+        info!("Api key {} revoked by user id {}", key_id, user_id);
+        Ok(())
+    }
+
+    /// Lookup by prefix, then verify the argon2 hash
+    pub async fn verify_api_key(
+        pool: &Pool<Sqlite>,
+        plain: &str,
+    ) -> Result<(AuthenticatedUser, ApiKeyScope), AuthError> {
+        let prefix = plain.strip_prefix(API_KEY_TAG)
+            .and_then(|rest| rest.get(..API_KEY_PREFIX_LEN))
+            .ok_or(AuthError::InvalidApiKey)?;
+
+        let rows = sqlx::query(
+            r#"
+            SELECT k.id, k.key_hash, k.scope, u.id AS uid, u.username, u.role
+            FROM api_keys k
+            JOIN users u ON u.id = k.user_id
+            WHERE k.prefix = ? AND u.disabled_at IS NULL
+            "#
+        )
+        .bind(prefix)
+        .fetch_all(pool)
+        .await?;
+
+        for row in rows {
+            let key_hash: String = row.get("key_hash");
+            if !Self::verify_password(plain, &key_hash)? {
+                continue;
+            }
+
+            let key_id: i64 = row.get("id");
+            let scope: ApiKeyScope = row.get("scope");
+            sqlx::query("UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP WHERE id = ?")
+                .bind(key_id)
+                .execute(pool)
+                .await?;
+
+            let user = AuthenticatedUser {
+                id: row.get("uid"),
+                role: row.get("role"),
+                username: row.get("username"),
+                session_id: None,
+                must_change_password: false,
+                scope,
+            };
+            return Ok((user, scope));
+        }
+
+        warn!("Rejected api key with prefix '{}'", prefix);
+        Err(AuthError::InvalidApiKey)
+    }
+}
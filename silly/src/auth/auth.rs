@@ -8,22 +8,54 @@ use argon2::{
         rand_core::OsRng,
     },
 };
-use jsonwebtoken::{
-    encode, Header,
-    EncodingKey,
-};
 use tracing::{info, warn};
 use sqlx::{Pool, Sqlite, Row};
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use super::types::{
     AuthController, Role,
     Claims, User, AuthError,
+    AuthenticatedUser, ApiKeyScope, JwtKeys,
+    PasswordPolicy, LoginThrottle,
+    SessionMeta, LoginOutcome,
+    Permission, SetupToken,
 };
 
+/// Lifetime of a session, slides on every refresh
 pub const COOKIE_VAILDITY_DURATION: usize = 24 * 60 * 60 * 15; 
+const SETUP_TOKEN_LEN: usize = 32;
+
+impl SetupToken {
+    /// Fresh token, for a server without an admin
+    pub fn generate() -> Self {
+        SetupToken {
+            token: Mutex::new(Some(AuthController::random_string(SETUP_TOKEN_LEN))),
+        }
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.token.lock().unwrap().clone()
+    }
+
+    /// Takes the token if it matches, so only one request gets to use it
+    fn redeem(&self, token: &str) -> bool {
+        let mut current = self.token.lock().unwrap();
+        if current.as_deref().is_some_and(|t| t == token.trim()) {
+            *current = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Creating the admin failed, hand it back
+    fn restore(&self, token: &str) {
+        *self.token.lock().unwrap() = Some(token.trim().to_string());
+    }
+}
 
 impl AuthController {
-    fn hash_password(password: &str) -> Result<String, AuthError> {
+    pub(super) fn hash_password(password: &str) -> Result<String, AuthError> {
         let salt = SaltString::generate(&mut OsRng);
         let argon2 = Argon2::default();
         argon2
@@ -33,7 +65,7 @@ impl AuthController {
     }
 
     /// Just verify the string duh!! :D
-    fn verify_password(password: &str, password_hash: &str) -> Result<bool, AuthError> {
+    pub(super) fn verify_password(password: &str, password_hash: &str) -> Result<bool, AuthError> {
         let parsed_hash = PasswordHash::new(password_hash)
             .map_err(|_| AuthError::PasswordHashError)?;
         
@@ -42,43 +74,49 @@ impl AuthController {
             .is_ok())
     }
 
-    /// Pass it directly from main -> state 
-    fn create_token(username: &str, uid: i64, role: Role, secret: &[u8]) -> Result<String, AuthError> {
+    /// Signed with the current key of the ring, `ttl` in seconds
+    pub(super) fn create_token(
+        username: &str,
+        uid: i64,
+        role: Role,
+        sid: &str,
+        jwt: &JwtKeys,
+        ttl: usize,
+    ) -> Result<String, AuthError> {
         let expiration = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_secs() as usize + COOKIE_VAILDITY_DURATION;
+            .as_secs() as usize + ttl;
 
         let claims = Claims {
             uid: uid,
             role: role,
             exp: expiration,
             sub: username.to_string(),
+            sid: sid.to_string(),
         };
 
-        encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(secret),
-        )
-        .map_err(|_| AuthError::TokenCreationError)
+        jwt.sign(&claims)
     }
 
-    /// Verification of Credentials and return a jwt string
-    pub async fn login(
+    /// Verification of Credentials, for basic auth and login
+    pub async fn verify_credentials(
         pool: &Pool<Sqlite>,
         username: &str,
         password: &str,
-        jwt_secret: &str,
-    ) -> Result<String, AuthError> {
-        let row = sqlx::query("SELECT id, password_hash, role FROM users WHERE username = ?")
+    ) -> Result<AuthenticatedUser, AuthError> {
+        let row = sqlx::query("SELECT id, password_hash, role, must_change_password, pending_approval, disabled_at IS NOT NULL AS disabled FROM users WHERE username = ?")
             .bind(username)
             .fetch_optional(pool)
             .await?;
 
         let row = match row {
             Some(r) => r,
-            None => return Err(AuthError::InvalidCredentials),
+            None => {
+                /* Don't answer faster for unknown usernames */
+                Self::burn_dummy_verify(password);
+                return Err(AuthError::InvalidCredentials);
+            }
         };
 
         let uid: i64 = row.get("id");
@@ -90,18 +128,55 @@ impl AuthController {
         if !Self::verify_password(password, &stored_hash)? {
             return Err(AuthError::InvalidCredentials);
         }
+        /* Only after the password, don't tell strangers the account exists */
+        if row.get::<bool, _>("pending_approval") {
+            return Err(AuthError::AccountPending);
+        }
+        if row.get::<bool, _>("disabled") {
+            return Err(AuthError::AccountDisabled);
+        }
 
-        let token = Self::create_token(username, uid, role, jwt_secret.as_bytes())?;
+        Ok(AuthenticatedUser {
+            id: uid,
+            role: role.to_string(),
+            username: username.to_string(),
+            session_id: None,
+            must_change_password: row.get("must_change_password"),
+            scope: ApiKeyScope::Full,
+        })
+    }
+
+    /*
+      * Verification of Credentials, opens a session and returns its tokens
+      * With two factor auth on, only a challenge for `login_totp` comes back
+    */
+    pub async fn login(
+        pool: &Pool<Sqlite>,
+        throttle: &LoginThrottle,
+        username: &str,
+        password: &str,
+        jwt: &JwtKeys,
+        meta: &SessionMeta,
+        access_ttl: usize,
+    ) -> Result<LoginOutcome, AuthError> {
+        let user = Self::attempt_credentials(pool, throttle, username, password, meta.ip.as_deref()).await?;
+        if Self::has_totp(pool, user.id).await? {
+            return Ok(LoginOutcome::TotpRequired(Self::totp_challenge(jwt, &user)?));
+        }
+        let tokens = Self::open_session(pool, &user, jwt, meta, access_ttl).await?;
         
         info!("User '{}' logged in successfully", username);
-        Ok(token)
+        Ok(LoginOutcome::Session(user, tokens))
     }
 
     /// Create initial admin (only allow if table is empty)
     pub async fn init_admin(
         pool: &Pool<Sqlite>,
+        policy: &PasswordPolicy,
+        setup: &SetupToken,
         username: &str,
         password: &str,
+        token: &str,
     ) -> Result<User, AuthError> {
         let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE role = 'admin'")
             .fetch_one(pool)
@@ -112,40 +187,54 @@ impl AuthController {
             return Err(AuthError::AdminAlreadyRegistered);
         }
 
+        if !setup.redeem(token) {
+            warn!("Admin registration with a wrong setup token");
+            return Err(AuthError::InvalidSetupToken);
+        }
+        let id = Self::insert_admin(pool, policy, username, password)
+            .await
+            .inspect_err(|_| setup.restore(token))?;
+
+        info!("Initial admin created: {:?}", username);
+
+        Ok(User {
+            id,
+            username: username.to_string(),
+            role: Role::Admin,
+        })
+    }
+
+    async fn insert_admin(
+        pool: &Pool<Sqlite>,
+        policy: &PasswordPolicy,
+        username: &str,
+        password: &str,
+    ) -> Result<i64, AuthError> {
+        policy.check(username, password)?;
         let password_hash = Self::hash_password(password)?;
-        let role = Role::Admin;
-        
+
         let id = sqlx::query(
             "INSERT INTO users (username, password_hash, role) VALUES (?, ?, ?)"
         )
         .bind(username)
         .bind(password_hash)
-        .bind(role.to_string())
+        .bind(Role::Admin.to_string())
         .execute(pool)
         .await?
         .last_insert_rowid();
-
-        info!("Initial admin created: {:?}", username);
-
-        Ok(User {
-            id: id,
-            username: username.to_string(),
-            role: role,
-        })
+        Ok(id)
     }
 
-    /// Create standard user, can be only invoked by admin
+    /// Create a user of any role, needs `ManageUsers`
     pub async fn create_user(
         pool: &Pool<Sqlite>,
-        requester_username: &str,
+        policy: &PasswordPolicy,
+        requester: &AuthenticatedUser,
         username: &str,
         password: &str,
+        role: Role,
     ) -> Result<User, AuthError> {
-        let requester_role = Self::get_user_role(pool, requester_username).await?;
-
-        if requester_role != Role::Admin {
-            return Err(AuthError::Unauthorized);
-        }
+        requester.require(Permission::ManageUsers)?;
 
         let exists: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE username = ?")
             .bind(username)
@@ -156,8 +245,8 @@ impl AuthController {
             return Err(AuthError::UserAlreadyExists);
         }
 
+        policy.check(username, password)?;
         let password_hash = Self::hash_password(password)?;
-        let role = Role::User;
 
         let result = sqlx::query(
             "INSERT INTO users (username, password_hash, role) VALUES (?, ?, ?)"
@@ -170,7 +259,7 @@ impl AuthController {
 
         match result.map_err(AuthError::from) {
             Ok(res) => {
-                info!("User created: {:?}", username.to_string());
+                info!("User created: {:?} ({}) by '{}'", username.to_string(), role, requester.username);
                 Ok(User {
                     id: res.last_insert_rowid(),
                     username: username.to_string(),
@@ -189,60 +278,32 @@ impl AuthController {
     /// Called by admin to delete users
     pub async fn delete_user(
         pool: &Pool<Sqlite>,
-        requester_username: &str,
+        requester: &AuthenticatedUser,
         target_username: &str,
-    ) -> Result<(), AuthError> {
-        let requester_role = Self::get_user_role(pool, requester_username).await?;
-        if requester_role != Role::Admin {
-            return Err(AuthError::Unauthorized);
-        }
+    ) -> Result<Option<i64>, AuthError> {
+        requester.require(Permission::ManageUsers)?;
         /* 
           * Prevent admin from deleting itself
           * Don't delete user if a single admin exists
         */
-        if requester_username == target_username {
+        if requester.username == target_username {
             return Err(AuthError::CannotDeleteSelf);
         }
 
-        let admin_count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE role = 'admin'")
-            .fetch_one(pool)
-            .await?;
-
-        let target_is_admin: Option<(i64,)> = sqlx::query_as(
-            "SELECT COUNT(*) FROM users WHERE username = ? AND role = 'admin'"
-        )
-        .bind(target_username)
-        .fetch_optional(pool)
-        .await?;
-
-        if let Some((count,)) = target_is_admin {
-            if count > 0 && admin_count.0 <= 1 {
-                return Err(AuthError::CannotDeleteLastAdmin);
-            }
+        /* Disabled admins don't count, they can't log in */
+        if Self::is_last_admin(pool, target_username).await? {
+            return Err(AuthError::CannotDeleteLastAdmin);
         }
 
-        sqlx::query("DELETE FROM users WHERE username = ?")
+        /* Id back, so the caller can drop the downloads that cascaded */
+        let id: Option<i64> = sqlx::query_scalar("DELETE FROM users WHERE username = ? RETURNING id")
             .bind(target_username)
-            .execute(pool)
-            .await?;
-            
-        Ok(())
-    }
-
-    async fn get_user_role(
-        pool: &Pool<Sqlite>,
-        username: &str,
-    ) -> Result<Role, AuthError> {
-        let role: Option<String> = sqlx::query_scalar(
-            "SELECT role FROM users WHERE username = ?"
-        )
-            .bind(username)
             .fetch_optional(pool)
-        .await?;
+            .await?;
 
-        match role {
-            Some(r) => Role::try_from(r.as_str()).map_err(|_| AuthError::InvalidRole),
-            None => Err(AuthError::UserNotFound),
+        if id.is_some() {
+            info!("User deleted: {:?} by '{}'", target_username, requester.username);
         }
+        Ok(id)
     }
 }
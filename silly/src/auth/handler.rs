@@ -1,3 +1,4 @@
+use std::convert::Infallible;
 use serde_json::json;
 use axum::{
     extract,
@@ -7,8 +8,8 @@ use axum::{
         FromRef,
         FromRequestParts,
     },
-    http::{request::Parts, StatusCode},
-    response::{IntoResponse},
+    http::{request::Parts, HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
     
 };
 use axum_extra::extract::cookie::{
@@ -16,69 +17,151 @@ use axum_extra::extract::cookie::{
     SameSite,
     CookieJar
 };
-use jsonwebtoken::{
-    decode,
-    Validation,
-    DecodingKey
-};
 
 use crate::{
     app::AppState,
+    audit::{Audit, AuditAction, AuditOutcome},
+    middleware::{authenticate, client_ip, decode_claims, full_path, proxy_user, require_permission, user_from_token, AuthRejection},
     auth::{
         types::{
-            Claims,
             AuthController,
             AuthError,
             AuthenticatedUser,
+            Permission,
+            Role,
             LoginRequest,
             RegAdminRequest,
             CreateUserReq,
             DeleteUserReq,
+            CreateApiKeyReq,
+            RevokeApiKeyReq,
+            RevokeSessionReq,
+            ChangePasswordReq,
+            ResetPasswordReq,
+            UnlockReq,
+            TotpLoginReq,
+            TotpConfirmReq,
+            TotpDisableReq,
+            ResetTotpReq,
+            SetRoleReq,
+            DisableUserReq,
+            RenameUserReq,
+            TransferDownloadsReq,
+            CreateInvitationReq,
+            RevokeInvitationReq,
+            RegisterReq,
+            RegistrationSettingsReq,
+            LoginOutcome,
+            ChallengeClaims,
+            SessionMeta,
+            SessionTokens,
         },
         auth::COOKIE_VAILDITY_DURATION,
+        sessions::{ACCESS_COOKIE, REFRESH_COOKIE, ACCESS_TOKEN_DURATION},
     },
 }; 
 
-/// Make an initial admin
+/// Add the access cookie, and the refresh one when it was rotated
+pub fn session_cookies(jar: CookieJar, tokens: &SessionTokens) -> CookieJar {
+    let access = Cookie::build((ACCESS_COOKIE, tokens.access.clone()))
+        .path("/")
+        .secure(false)                  /* TODO: SSL implementation */
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(time::Duration::seconds(ACCESS_TOKEN_DURATION as i64))
+        .build();
+    let jar = jar.add(access);
+
+    match &tokens.refresh {
+        Some(refresh) => jar.add(
+            Cookie::build((REFRESH_COOKIE, refresh.clone()))
+                .path("/")
+                .secure(false)
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .max_age(time::Duration::seconds(COOKIE_VAILDITY_DURATION as i64))
+                .build()
+        ),
+        None => jar,
+    }
+}
+
+/// Make an initial admin, needs the setup token from the log
 pub async fn reg_admin(
     State(state): State<AppState>,
+    meta: SessionMeta,
     Json(payload): Json<RegAdminRequest>,
 ) -> impl IntoResponse {
-    match AuthController::init_admin(&state.db, &payload.username, &payload.password).await {
-        Ok(user) => (StatusCode::OK, Json(json!({ "status": "ok", "user": user.username }))),
+    let res = match AuthController::init_admin(
+        &state.db,
+        &state.password_policy,
+        &state.setup_token,
+        &payload.username,
+        &payload.password,
+        &payload.setup_token,
+    ).await {
+        Ok(user) => {
+            state.status_tx.send_modify(|s| s.admin_exists = true);
+            (StatusCode::OK, Json(json!({ "status": "ok", "user": user.username })))
+        }
         Err(AuthError::AdminAlreadyRegistered) => (
             StatusCode::FORBIDDEN, 
             Json(json!({ "error": "Admin already registered" }))
         ),
+        Err(AuthError::InvalidSetupToken) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Invalid setup token, check the server log" }))
+        ),
+        Err(AuthError::WeakPassword(msg)) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": msg }))
+        ),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR, 
             Json(json!({ "error": format!("{:?}", e) }))
         ),
-    }
+    };
+    Audit::record(&state.db, None, &meta, AuditAction::RegisterAdmin, Some(&payload.username), res.0.into(), None).await;
+    res
 }
 
 
-/// create user, FIX: later create user with admin rights
+/// Create user, the caller must be allowed to manage users
 pub async fn create_user(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
     Json(payload): Json<CreateUserReq>,
 ) -> impl IntoResponse {
-    match AuthController::create_user(
+    let role = payload.role.clone().unwrap_or(Role::User);
+    let res = match AuthController::create_user(
         &state.db,
-        &payload.requester_username,
+        &state.password_policy,
+        &user,
         &payload.username,
-        &payload.password
+        &payload.password,
+        role.clone(),
     ).await {
-        Ok(user) => (StatusCode::OK, Json(json!({ "status": "ok", "user": user.username }))),
+        Ok(user) => (StatusCode::OK, Json(json!({ "status": "ok", "user": user.username, "role": user.role }))),
         Err(AuthError::Unauthorized) => (
             StatusCode::FORBIDDEN, 
             Json(json!({ "error": "Failed to create the username" }))
         ),
+        Err(AuthError::UserAlreadyExists) => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Username already exists" }))
+        ),
+        Err(AuthError::WeakPassword(msg)) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": msg }))
+        ),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR, 
             Json(json!({ "error": format!("{:?}", e) }))
         ),
-    }
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::CreateUser, Some(&payload.username), res.0.into(), Some(&role.to_string())).await;
+    res
 }
 
 
@@ -86,23 +169,27 @@ pub async fn create_user(
 /// delete delete delete!!
 pub async fn delete_user(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
     Json(payload): Json<DeleteUserReq>,
 ) -> impl IntoResponse {
-    match AuthController::delete_user(
+    let res = match AuthController::delete_user(
         &state.db,
-        &payload.requester_username,
+        &user,
         &payload.target_username,
     ).await {
-        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok"} ))),
-        Err(AuthError::Unauthorized) => (
-            StatusCode::FORBIDDEN, 
-            Json(json!({ "error": "Failed to delete the username" }))
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR, 
-            Json(json!({ "error": format!("{:?}", e) }))
-        ),
-    }
+        Ok(Some(id)) => {
+            // Their downloads went with them
+            for gid in state.registry.remove_owned(id) {
+                state.progress.forget(&gid);
+            }
+            (StatusCode::OK, Json(json!({ "status": "ok"} )))
+        }
+        Ok(None) => user_admin_error(AuthError::UserNotFound),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::DeleteUser, Some(&payload.target_username), res.0.into(), None).await;
+    res
 }
 
 
@@ -110,40 +197,135 @@ pub async fn delete_user(
 pub async fn login(
     State(state): State<AppState>,
     jar: CookieJar,
+    meta: SessionMeta,
     Json(payload): Json<LoginRequest>,
-) -> impl IntoResponse {
-    match AuthController::login(&state.db, &payload.username, &payload.password, &state.jwt_secret).await {
-        Ok(token) => {
-            let cookie = Cookie::build(("auth_token", token))
-                .path("/")
-                .secure(false)                  /* TODO: SSL implementation */
-                .http_only(true)
-                .same_site(SameSite::Strict)
-                .max_age(time::Duration::days(COOKIE_VAILDITY_DURATION as i64))
-                .build();
+) -> Response {
+    let outcome = AuthController::login(
+        &state.db,
+        &state.login_throttle,
+        &payload.username,
+        &payload.password,
+        &state.jwt,
+        &meta,
+        ACCESS_TOKEN_DURATION,
+    ).await;
+    let actor = match &outcome {
+        Ok(LoginOutcome::Session(user, _)) => Some(user.clone()),
+        _ => None,
+    };
 
+    let res = match outcome {
+        Ok(LoginOutcome::Session(user, tokens)) => {
             (
                 StatusCode::OK,
-                jar.add(cookie),
+                session_cookies(jar, &tokens),
                 Json(json!({ 
                     "status": "ok", 
-                    "username": payload.username
+                    "username": payload.username,
+                    "mustChangePassword": user.must_change_password,
                 }))
-            )
+            ).into_response()
         },
+        /* No cookie yet, the code goes to `/login/totp` with the challenge */
+        Ok(LoginOutcome::TotpRequired(challenge)) => (
+            StatusCode::OK,
+            Json(json!({ "status": "totpRequired", "challenge": challenge }))
+        ).into_response(),
+        Err(AuthError::TooManyAttempts(secs)) => too_many_attempts(secs),
+        Err(AuthError::AccountDisabled) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Account disabled" }))
+        ).into_response(),
+        Err(AuthError::AccountPending) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Waiting for an admin to approve the account" }))
+        ).into_response(),
         Err(_) => (
             StatusCode::UNAUTHORIZED,
             jar, 
             Json(json!({ "error": "Invalid username or password" }))
-        ),
+        ).into_response(),
+    };
+    /* The password step of a two factor login isn't one yet, `login_totp` records it */
+    if actor.is_some() || res.status() != StatusCode::OK {
+        Audit::record(&state.db, actor.as_ref(), &meta, AuditAction::Login, Some(&payload.username), res.status().into(), None).await;
     }
+    res
+}
+
+fn too_many_attempts(secs: u64) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string())],
+        Json(json!({ "error": "Too many failed attempts, try again later", "retryAfter": secs }))
+    ).into_response()
+}
+
+/// Second login step, totp or recovery code
+pub async fn login_totp(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    meta: SessionMeta,
+    Json(payload): Json<TotpLoginReq>,
+) -> Response {
+    let outcome = AuthController::login_totp(
+        &state.db,
+        &state.login_throttle,
+        &state.jwt,
+        &payload.challenge,
+        &payload.code,
+        &meta,
+        ACCESS_TOKEN_DURATION,
+    ).await;
+    let actor = outcome.as_ref().ok().map(|(user, _)| user.clone());
+
+    let res = match outcome {
+        Ok((user, tokens)) => (
+            StatusCode::OK,
+            session_cookies(jar, &tokens),
+            Json(json!({ 
+                "status": "ok", 
+                "username": user.username,
+                "mustChangePassword": user.must_change_password,
+            }))
+        ).into_response(),
+        Err(AuthError::TooManyAttempts(secs)) => too_many_attempts(secs),
+        Err(AuthError::InvalidTotp) => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Invalid code" }))
+        ).into_response(),
+        Err(_) => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Login expired, start over" }))
+        ).into_response(),
+    };
+    /* A wrong code has no user yet, the challenge names it */
+    let username = state.jwt.verify::<ChallengeClaims>(&payload.challenge).map(|c| c.sub);
+    Audit::record(&state.db, actor.as_ref(), &meta, AuditAction::Login, username.as_deref(), res.status().into(), Some("totp")).await;
+    res
 }
 
-/// Just remove the cookie
-pub async fn logout(jar: CookieJar) -> impl IntoResponse {
+/// Revoke the session and remove the cookies
+pub async fn logout(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    /* Refresh cookie outlives the access one, try it first, both only count when they verify */
+    let refreshed = match jar.get(REFRESH_COOKIE) {
+        Some(c) => AuthController::refresh_sid(&state.db, c.value()).await.ok().flatten(),
+        None => None,
+    };
+    let sid = refreshed.or_else(|| jar.get(ACCESS_COOKIE)
+        .and_then(|c| decode_claims(&state, c.value()))
+        .map(|claims| claims.sid));
+
+    if let Some(sid) = sid {
+        let _ = AuthController::end_session(&state.db, &sid).await;
+    }
+
     (
         StatusCode::OK,
-        jar.remove(Cookie::from("auth_token")),
+        jar.remove(Cookie::from(ACCESS_COOKIE)).remove(Cookie::from(REFRESH_COOKIE)),
         Json(json!({ "status": "loggedOut" }))
     )
 }
@@ -151,28 +333,33 @@ pub async fn logout(jar: CookieJar) -> impl IntoResponse {
 /// Vaildate the cookie
 pub async fn get_me(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    meta: SessionMeta,
     jar: CookieJar,
 ) -> impl IntoResponse {
-    if let Some(token_cookie) = jar.get("auth_token") {
-        let token = token_cookie.value();
-        /* Verify manually */
-        let validation = Validation::default();
-        let key = DecodingKey::from_secret(state.jwt_secret.as_bytes());
-        match decode::<Claims>(token, &key, &validation) {
-            Ok(token_data) => {
-                return (
-                    StatusCode::OK,
-                    Json(json!({ 
-                        "authenticated": true, 
-                        "role": token_data.claims.role,
-                        "username": token_data.claims.sub,
-                        // "uid": token_data.claims.uid,       /* why pass uid */
-                    }))
-                );
-            }
-            // token invalid or expired
-            Err(_) => {}
-        }
+    /* Behind the auth proxy there's no cookie, the header is the login */
+    let proxied = proxy_user(&state, &headers, meta.ip.as_deref()).await.ok().flatten();
+    let user = match proxied {
+        Some(user) => Some(user),
+        /* Verify manually, the session must still be live */
+        None => match jar.get(ACCESS_COOKIE) {
+            Some(token_cookie) => user_from_token(&state, token_cookie.value()).await,
+            None => None,
+        },
+    };
+
+    if let Some(user) = user {
+        return (
+            StatusCode::OK,
+            Json(json!({ 
+                "authenticated": true, 
+                "role": user.role,
+                "username": user.username,
+                "mustChangePassword": user.must_change_password,
+                "totpEnabled": AuthController::has_totp(&state.db, user.id).await.unwrap_or(false),
+                "proxyAuth": user.session_id.is_none(),
+            }))
+        );
     }
 
     (
@@ -181,34 +368,690 @@ pub async fn get_me(
     )
 }
 
+/// Explicit refresh, `session_refresh` does the same for every request
+pub async fn refresh(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    let Some(refresh) = jar.get(REFRESH_COOKIE).map(|c| c.value().to_string()) else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            jar,
+            Json(json!({ "error": "Missing refresh cookie" }))
+        );
+    };
+
+    match AuthController::refresh_session(&state.db, &state.jwt, &refresh).await {
+        Ok(tokens) => (
+            StatusCode::OK,
+            session_cookies(jar, &tokens),
+            Json(json!({ "status": "ok" }))
+        ),
+        Err(_) => (
+            StatusCode::UNAUTHORIZED,
+            jar.remove(Cookie::from(ACCESS_COOKIE)).remove(Cookie::from(REFRESH_COOKIE)),
+            Json(json!({ "error": "Invalid or expired session" }))
+        ),
+    }
+}
+
+/// User agent and peer ip of the request, for the sessions list
+impl<S> FromRequestParts<S> for SessionMeta
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(SessionMeta {
+            user_agent: parts.headers
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            ip: client_ip(&parts.extensions),
+        })
+    }
+}
+
 /// Extract the authenticated infos
 impl<S> FromRequestParts<S> for AuthenticatedUser
 where
     S: Send + Sync,
     AppState: extract::FromRef<S>,
 {
-    type Rejection = (StatusCode, Json<serde_json::Value>);
+    type Rejection = AuthRejection;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        /* Already resolved by `auth_guard` */
+        if let Some(user) = parts.extensions.get::<AuthenticatedUser>() {
+            return Ok(user.clone());
+        }
+
         let state = AppState::from_ref(state);
         let jar = CookieJar::from_request_parts(parts, &state).await.map_err(|_| {
             (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Cookie error"})))
         })?;
 
-        let token = jar.get("auth_token")
-            .map(|c| c.value())
-            .ok_or((StatusCode::UNAUTHORIZED, Json(json!({"error": "Missing token"}))))?;
+        let path = full_path(&parts.extensions, &parts.uri);
+        let peer = client_ip(&parts.extensions);
+        authenticate(&state, &parts.headers, &jar, peer.as_deref(), &parts.method, &path).await
+    }
+}
 
-        let validation = Validation::default();
-        let key = DecodingKey::from_secret(state.jwt_secret.as_bytes());
+/// List caller's api keys, hashes are never returned
+pub async fn list_api_keys(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    match AuthController::list_api_keys(&state.db, user.id).await {
+        Ok(keys) => (StatusCode::OK, Json(json!({ "keys": keys }))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    }
+}
 
-        let token_data = decode::<Claims>(token, &key, &validation)
-            .map_err(|_| (StatusCode::UNAUTHORIZED, Json(json!({"error": "Invalid token"}))))?;
+/// The plain key is only in this response, store it!!
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<CreateApiKeyReq>,
+) -> impl IntoResponse {
+    if payload.label.trim().is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Label is required" }))
+        );
+    }
 
-        Ok(AuthenticatedUser {
-            id: token_data.claims.uid, 
-            role: token_data.claims.role.to_string(),
-            username: token_data.claims.sub,
-        })
+    let res = match AuthController::create_api_key(&state.db, user.id, payload.label.trim(), payload.scope).await {
+        Ok((key, plain)) => (
+            StatusCode::OK,
+            Json(json!({ "status": "ok", "key": plain, "apiKey": key }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::CreateApiKey, Some(payload.label.trim()), res.0.into(), None).await;
+    res
+}
+
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<RevokeApiKeyReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::revoke_api_key(&state.db, user.id, payload.id).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(AuthError::ApiKeyNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Api key not found" }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::RevokeApiKey, Some(&payload.id.to_string()), res.0.into(), None).await;
+    res
+}
+
+/// Live sessions of the caller, `current` marks this one
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    match AuthController::list_sessions(&state.db, user.id, user.session_id.as_deref()).await {
+        Ok(sessions) => (StatusCode::OK, Json(json!({ "sessions": sessions }))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    }
+}
+
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Json(payload): Json<RevokeSessionReq>,
+) -> impl IntoResponse {
+    match AuthController::revoke_session(&state.db, user.id, &payload.id).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(AuthError::SessionNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Session not found" }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    }
+}
+
+/// Logout everywhere but here
+pub async fn revoke_other_sessions(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    match AuthController::revoke_sessions(&state.db, user.id, user.session_id.as_deref()).await {
+        Ok(n) => (StatusCode::OK, Json(json!({ "status": "ok", "revoked": n }))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    }
+}
+
+/// Admin only, sessions signed by the old key keep working until they expire
+pub async fn rotate_jwt_key(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+) -> impl IntoResponse {
+    if let Err(e) = require_permission(&user, Permission::ManageServer) {
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::RotateJwtKey, None, AuditOutcome::Denied, None).await;
+        return e;
     }
+
+    let res = match state.jwt.rotate() {
+        Ok(kid) => (StatusCode::OK, Json(json!({ "status": "ok", "kid": kid }))),
+        Err(AuthError::SigningKeyPinned) => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Signing key is pinned by --jwt-secret" }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::RotateJwtKey, None, res.0.into(), None).await;
+    res
+}
+
+/// Needs the old password, the only thing a temporary password can do
+pub async fn change_password(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<ChangePasswordReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::change_password(
+        &state.db,
+        &state.password_policy,
+        &state.login_throttle,
+        &user,
+        &payload.old_password,
+        &payload.new_password,
+        meta.ip.as_deref(),
+    ).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))).into_response(),
+        Err(AuthError::TooManyAttempts(secs)) => too_many_attempts(secs),
+        Err(AuthError::InvalidCredentials) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Old password is wrong" }))
+        ).into_response(),
+        Err(AuthError::WeakPassword(msg)) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": msg }))
+        ).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ).into_response(),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::ChangePassword, Some(&user.username), res.status().into(), None).await;
+    res
+}
+
+/// Admin sets a temporary password, shown only in this response
+pub async fn reset_password(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<ResetPasswordReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::reset_password(&state.db, &user, &payload.username).await {
+        Ok(temp) => (
+            StatusCode::OK,
+            Json(json!({ "status": "ok", "username": payload.username, "temporaryPassword": temp }))
+        ),
+        Err(AuthError::Unauthorized) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Only admins can reset passwords" }))
+        ),
+        Err(AuthError::UserNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "User not found" }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::ResetPassword, Some(&payload.username), res.0.into(), None).await;
+    res
+}
+
+/// Admin only, usernames and ips currently locked out
+pub async fn list_lockouts(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    if let Err(e) = require_permission(&user, Permission::ManageUsers) {
+        return e;
+    }
+    (StatusCode::OK, Json(json!({ "lockouts": state.login_throttle.lockouts() })))
+}
+
+pub async fn unlock_login(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<UnlockReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::unlock_login(&state.login_throttle, &user, &payload.username) {
+        Ok(true) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Nothing to unlock" }))
+        ),
+        Err(_) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Only admins can unlock logins" }))
+        ),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::UnlockLogin, Some(&payload.username), res.0.into(), None).await;
+    res
+}
+
+/// New secret + `otpauth://` uri, stays off until confirmed with a code
+pub async fn totp_setup(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    match AuthController::totp_setup(&state.db, &user).await {
+        Ok((secret, url)) => (
+            StatusCode::OK,
+            Json(json!({ "secret": secret, "otpauthUrl": url }))
+        ),
+        Err(AuthError::TotpAlreadyEnabled) => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Two factor auth is already enabled" }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    }
+}
+
+/// Recovery codes are only in this response
+pub async fn totp_confirm(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<TotpConfirmReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::totp_confirm(&state.db, &user, &payload.code).await {
+        Ok(codes) => (
+            StatusCode::OK,
+            Json(json!({ "status": "ok", "recoveryCodes": codes }))
+        ),
+        Err(AuthError::InvalidTotp) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid code" }))
+        ),
+        Err(AuthError::TotpAlreadyEnabled) => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Two factor auth is already enabled" }))
+        ),
+        Err(AuthError::TotpNotEnabled) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Run setup first" }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::EnableTotp, Some(&user.username), res.0.into(), None).await;
+    res
+}
+
+pub async fn totp_disable(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<TotpDisableReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::totp_disable(
+        &state.db,
+        &state.login_throttle,
+        &user,
+        &payload.password,
+        meta.ip.as_deref(),
+    ).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))).into_response(),
+        Err(AuthError::TooManyAttempts(secs)) => too_many_attempts(secs),
+        Err(AuthError::InvalidCredentials) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Password is wrong" }))
+        ).into_response(),
+        Err(AuthError::TotpNotEnabled) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Two factor auth is not enabled" }))
+        ).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ).into_response(),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::DisableTotp, Some(&user.username), res.status().into(), None).await;
+    res
+}
+
+/// Admin turns it off for a user locked out of their device
+pub async fn reset_totp(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<ResetTotpReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::totp_reset(&state.db, &user, &payload.username).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(AuthError::Unauthorized) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Only admins can reset two factor auth" }))
+        ),
+        Err(AuthError::UserNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "User not found" }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::ResetTotp, Some(&payload.username), res.0.into(), None).await;
+    res
+}
+
+/// Admin only, every account with its last login and download count
+pub async fn list_users(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    match AuthController::list_users(&state.db, &user).await {
+        Ok(users) => (StatusCode::OK, Json(json!({ "users": users }))),
+        Err(AuthError::Unauthorized) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Only admins can list users" }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    }
+}
+
+pub async fn set_user_role(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<SetRoleReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::set_role(&state.db, &user, &payload.username, payload.role.clone()).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok", "role": payload.role }))),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::SetRole, Some(&payload.username), res.0.into(), Some(&payload.role.to_string())).await;
+    res
+}
+
+/// Keeps the account and its downloads, logs it out everywhere
+pub async fn disable_user(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<DisableUserReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::disable_user(&state.db, &user, &payload.username).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::DisableUser, Some(&payload.username), res.0.into(), None).await;
+    res
+}
+
+pub async fn enable_user(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<DisableUserReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::enable_user(&state.db, &user, &payload.username).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::EnableUser, Some(&payload.username), res.0.into(), None).await;
+    res
+}
+
+pub async fn approve_user(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<DisableUserReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::approve_user(&state.db, &user, &payload.username).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(AuthError::UserNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "No pending user with that name" }))
+        ),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::ApproveUser, Some(&payload.username), res.0.into(), None).await;
+    res
+}
+
+pub async fn rename_user(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<RenameUserReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::rename_user(&state.db, &user, &payload.username, &payload.new_username).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::RenameUser, Some(&payload.username), res.0.into(), Some(&payload.new_username)).await;
+    res
+}
+
+/// Move a departing user's downloads before deleting the account
+pub async fn transfer_downloads(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<TransferDownloadsReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::transfer_downloads(&state.db, &user, &payload.from, &payload.to).await {
+        Ok((moved, from_id, to_id)) => {
+            state.registry.reassign(from_id, to_id);
+            (StatusCode::OK, Json(json!({ "status": "ok", "moved": moved })))
+        }
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::TransferDownloads, Some(&payload.from), res.0.into(), Some(&format!("to {}", payload.to))).await;
+    res
+}
+
+/// Shared by the user management handlers
+fn user_admin_error(e: AuthError) -> (StatusCode, Json<serde_json::Value>) {
+    match e {
+        AuthError::Unauthorized => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Only admins can manage users" }))
+        ),
+        AuthError::UserNotFound => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "User not found" }))
+        ),
+        AuthError::UserAlreadyExists => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Username already exists" }))
+        ),
+        AuthError::InvalidUsername => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Username can't be empty" }))
+        ),
+        AuthError::CannotDeleteSelf => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Can't do that to your own account" }))
+        ),
+        AuthError::CannotDeleteLastAdmin | AuthError::CannotDemoteLastAdmin => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "At least one enabled admin must remain" }))
+        ),
+        e => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    }
+}
+
+/// Sign up with an invitation, or without one when registration is open
+pub async fn register(
+    State(state): State<AppState>,
+    meta: SessionMeta,
+    Json(payload): Json<RegisterReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::register(
+        &state.db,
+        &state.password_policy,
+        &payload.username,
+        &payload.password,
+        payload.invitation.as_deref(),
+    ).await {
+        Ok((user, pending)) => (
+            StatusCode::OK,
+            Json(json!({ "status": "ok", "user": user.username, "role": user.role, "pending": pending }))
+        ),
+        Err(AuthError::InvalidInvitation) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Invitation is invalid, used up or expired" }))
+        ),
+        Err(AuthError::RegistrationClosed) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Registration is closed, ask an admin for an invitation" }))
+        ),
+        Err(AuthError::UserAlreadyExists) => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Username already exists" }))
+        ),
+        Err(AuthError::InvalidUsername) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Username can't be empty" }))
+        ),
+        Err(AuthError::WeakPassword(msg)) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": msg }))
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    };
+    Audit::record(&state.db, None, &meta, AuditAction::Register, Some(&payload.username), res.0.into(), payload.invitation.is_some().then_some("invitation")).await;
+    res
+}
+
+/// Public, so the login page knows whether to offer a sign up
+pub async fn registration_status(
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match AuthController::registration_open(&state.db).await {
+        Ok(open) => (StatusCode::OK, Json(json!({ "open": open }))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR, 
+            Json(json!({ "error": format!("{:?}", e) }))
+        ),
+    }
+}
+
+pub async fn set_registration(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<RegistrationSettingsReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::set_registration_open(&state.db, &user, payload.open).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok", "open": payload.open }))),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::SetRegistration, None, res.0.into(), Some(if payload.open { "open" } else { "closed" })).await;
+    res
+}
+
+pub async fn list_invitations(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    match AuthController::list_invitations(&state.db, &user).await {
+        Ok(invitations) => (StatusCode::OK, Json(json!({ "invitations": invitations }))),
+        Err(e) => user_admin_error(e),
+    }
+}
+
+/// The token comes back only here, once
+pub async fn create_invitation(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<CreateInvitationReq>,
+) -> impl IntoResponse {
+    let role = payload.role.clone().unwrap_or(Role::User);
+    let res = match AuthController::create_invitation(
+        &state.db,
+        &user,
+        role.clone(),
+        payload.max_uses,
+        payload.expires_in_hours,
+    ).await {
+        Ok((invitation, token)) => (
+            StatusCode::OK,
+            Json(json!({ "status": "ok", "invitation": invitation, "token": token }))
+        ),
+        Err(AuthError::InvalidInvitation) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Uses and expiry must be at least 1" }))
+        ),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::CreateInvitation, None, res.0.into(), Some(&role.to_string())).await;
+    res
+}
+
+pub async fn revoke_invitation(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Json(payload): Json<RevokeInvitationReq>,
+) -> impl IntoResponse {
+    let res = match AuthController::revoke_invitation(&state.db, &user, payload.id).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(AuthError::InvalidInvitation) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Invitation not found or already revoked" }))
+        ),
+        Err(e) => user_admin_error(e),
+    };
+    Audit::record(&state.db, Some(&user), &meta, AuditAction::RevokeInvitation, Some(&payload.id.to_string()), res.0.into(), None).await;
+    res
 }
@@ -0,0 +1,224 @@
+use tracing::{info, warn};
+use sqlx::{Pool, Sqlite};
+use crate::db::{get_setting, set_setting};
+use super::types::{
+    AuthController, AuthError,
+    AuthenticatedUser, Invitation,
+    PasswordPolicy, Permission,
+    Role, User,
+};
+
+const INVITATION_LEN: usize = 32;
+const INVITATION_HINT_LEN: usize = 6;
+const DEFAULT_INVITATION_HOURS: i64 = 7 * 24;
+const OPEN_REGISTRATION_KEY: &str = "open_registration";
+const INVITATION_COLUMNS: &str = r#"
+    SELECT i.id, i.hint, i.role, i.max_uses, i.uses, u.username AS created_by,
+           i.created_at, i.expires_at, i.revoked_at
+    FROM invitations i
+    LEFT JOIN users u ON u.id = i.created_by
+"#;
+
+impl AuthController {
+    /// Returns the stored invitation and the plain token, plain one is shown only once!!
+    pub async fn create_invitation(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        role: Role,
+        max_uses: Option<i64>,
+        expires_in_hours: Option<i64>,
+    ) -> Result<(Invitation, String), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let max_uses = max_uses.unwrap_or(1);
+        let hours = expires_in_hours.unwrap_or(DEFAULT_INVITATION_HOURS);
+        if max_uses < 1 || hours < 1 {
+            return Err(AuthError::InvalidInvitation);
+        }
+
+        let token = Self::random_string(INVITATION_LEN);
+        let id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO invitations (token_hash, hint, role, max_uses, created_by, expires_at)
+            VALUES (?, ?, ?, ?, ?, datetime('now', ?))
+            RETURNING id
+            "#
+        )
+        .bind(Self::sha256(&token))
+        .bind(&token[..INVITATION_HINT_LEN])
+        .bind(role.to_string())
+        .bind(max_uses)
+        .bind(requester.id)
+        .bind(format!("+{} hours", hours))
+        .fetch_one(pool)
+        .await?;
+
+        let invitation = sqlx::query_as::<_, Invitation>(&format!("{} WHERE i.id = ?", INVITATION_COLUMNS))
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        info!("Admin '{}' invited a {} ({} use(s), {}h)", requester.username, role, max_uses, hours);
+        Ok((invitation, token))
+    }
+
+    pub async fn list_invitations(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+    ) -> Result<Vec<Invitation>, AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let invitations = sqlx::query_as::<_, Invitation>(&format!("{} ORDER BY i.id DESC", INVITATION_COLUMNS))
+            .fetch_all(pool)
+            .await?;
+        Ok(invitations)
+    }
+
+    /// Accounts made with it stay
+    pub async fn revoke_invitation(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        id: i64,
+    ) -> Result<(), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let res = sqlx::query(
+            "UPDATE invitations SET revoked_at = CURRENT_TIMESTAMP WHERE id = ? AND revoked_at IS NULL"
+        )
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(AuthError::InvalidInvitation);
+        }
+        info!("Admin '{}' revoked invitation {}", requester.username, id);
+        Ok(())
+    }
+
+    pub async fn registration_open(pool: &Pool<Sqlite>) -> Result<bool, AuthError> {
+        Ok(get_setting(pool, OPEN_REGISTRATION_KEY).await?.as_deref() == Some("true"))
+    }
+
+    pub async fn set_registration_open(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        open: bool,
+    ) -> Result<(), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        set_setting(pool, OPEN_REGISTRATION_KEY, if open { "true" } else { "false" }).await?;
+        warn!("Admin '{}' {} self registration", requester.username, if open { "opened" } else { "closed" });
+        Ok(())
+    }
+
+    /// Role of a usable invitation, doesn't use it up
+    async fn invitation_role(pool: &Pool<Sqlite>, token_hash: &str) -> Result<Role, AuthError> {
+        let role: String = sqlx::query_scalar(
+            r#"
+            SELECT role FROM invitations
+            WHERE token_hash = ? AND revoked_at IS NULL AND expires_at > datetime('now') AND uses < max_uses
+            "#
+        )
+        .bind(token_hash)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AuthError::InvalidInvitation)?;
+        Role::try_from(role.as_str()).map_err(|_| AuthError::InvalidRole)
+    }
+
+    /*
+      * New account picked by the user
+      * With an invitation it gets the invitation's role right away
+      * Otherwise, if the admin opened registration, a pending `user` to approve
+      * Returns the user and if it waits for approval
+    */
+    pub async fn register(
+        pool: &Pool<Sqlite>,
+        policy: &PasswordPolicy,
+        username: &str,
+        password: &str,
+        invitation: Option<&str>,
+    ) -> Result<(User, bool), AuthError> {
+        let username = username.trim();
+        if username.is_empty() {
+            return Err(AuthError::InvalidUsername);
+        }
+        let token_hash = invitation.map(|t| Self::sha256(t.trim()));
+
+        /* Cheap checks before hashing the password */
+        let (role, pending) = match &token_hash {
+            Some(hash) => (Self::invitation_role(pool, hash).await?, false),
+            None if Self::registration_open(pool).await? => (Role::User, true),
+            None => return Err(AuthError::RegistrationClosed),
+        };
+        policy.check(username, password)?;
+        let password_hash = Self::hash_password(password)?;
+
+        let mut tx = pool.begin().await?;
+        if let Some(hash) = &token_hash {
+            /* Someone else may have used the last one meanwhile */
+            let res = sqlx::query(
+                r#"
+                UPDATE invitations SET uses = uses + 1
+                WHERE token_hash = ? AND revoked_at IS NULL AND expires_at > datetime('now') AND uses < max_uses
+                "#
+            )
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?;
+            if res.rows_affected() == 0 {
+                return Err(AuthError::InvalidInvitation);
+            }
+        }
+
+        let res = sqlx::query(
+            r#"
+            INSERT INTO users (username, password_hash, role, pending_approval, disabled_at)
+            VALUES (?, ?, ?, ?, CASE WHEN ? THEN CURRENT_TIMESTAMP END)
+            "#
+        )
+        .bind(username)
+        .bind(password_hash)
+        .bind(role.to_string())
+        .bind(pending)
+        .bind(pending)
+        .execute(&mut *tx)
+        .await;
+
+        let id = match res {
+            Ok(res) => res.last_insert_rowid(),
+            Err(sqlx::Error::Database(db_err)) if db_err.message().contains("UNIQUE") => {
+                return Err(AuthError::UserAlreadyExists);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        tx.commit().await?;
+
+        if pending {
+            warn!("'{}' signed up, waiting for an admin to approve", username);
+        } else {
+            info!("'{}' registered as {} with an invitation", username, role);
+        }
+        Ok((User { id, username: username.to_string(), role }, pending))
+    }
+
+    pub async fn approve_user(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        username: &str,
+    ) -> Result<(), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let res = sqlx::query(
+            r#"
+            UPDATE users SET pending_approval = 0, disabled_at = NULL, updated_at = CURRENT_TIMESTAMP
+            WHERE username = ? AND pending_approval = 1
+            "#
+        )
+        .bind(username)
+        .execute(pool)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(AuthError::UserNotFound);
+        }
+        info!("Admin '{}' approved '{}'", requester.username, username);
+        Ok(())
+    }
+}
@@ -1,3 +1,13 @@
 pub mod auth;
 pub mod types;
 pub mod handler;
+pub mod api_keys;
+pub mod sessions;
+pub mod signing;
+pub mod password;
+pub mod throttle;
+pub mod totp;
+pub mod proxy;
+pub mod rbac;
+pub mod users;
+pub mod invites;
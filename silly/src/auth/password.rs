@@ -0,0 +1,132 @@
+use tracing::info;
+use sqlx::{Pool, Sqlite};
+use super::types::{
+    Permission,
+    AuthController, AuthError,
+    AuthenticatedUser, LoginThrottle, PasswordPolicy,
+};
+
+const TEMP_PASSWORD_LEN: usize = 16;
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            require_digit: false,
+            require_symbol: false,
+            require_mixed_case: false,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let weak = |msg: String| Err(AuthError::WeakPassword(msg));
+
+        if password.chars().count() < self.min_length {
+            return weak(format!("Password must be at least {} characters", self.min_length));
+        }
+        if password.eq_ignore_ascii_case(username) {
+            return weak("Password can't be the username".to_string());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return weak("Password must contain a digit".to_string());
+        }
+        if self.require_symbol && password.chars().all(|c| c.is_alphanumeric()) {
+            return weak("Password must contain a symbol".to_string());
+        }
+        if self.require_mixed_case
+            && !(password.chars().any(|c| c.is_lowercase()) && password.chars().any(|c| c.is_uppercase())) {
+            return weak("Password must contain upper and lower case letters".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl AuthController {
+    /// Store a new hash, `must_change` marks it temporary
+    pub(super) async fn set_password(
+        pool: &Pool<Sqlite>,
+        user_id: i64,
+        password: &str,
+        must_change: bool,
+    ) -> Result<(), AuthError> {
+        let password_hash = Self::hash_password(password)?;
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET password_hash = ?, must_change_password = ?,
+                password_changed_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?
+            "#
+        )
+        .bind(password_hash)
+        .bind(must_change)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Self service, other sessions of the user are logged out, wrong old passwords count against the login throttle
+    pub async fn change_password(
+        pool: &Pool<Sqlite>,
+        policy: &PasswordPolicy,
+        throttle: &LoginThrottle,
+        user: &AuthenticatedUser,
+        old_password: &str,
+        new_password: &str,
+        ip: Option<&str>,
+    ) -> Result<(), AuthError> {
+        Self::attempt_credentials(pool, throttle, &user.username, old_password, ip).await?;
+        if old_password == new_password {
+            return Err(AuthError::WeakPassword("New password must differ from the old one".to_string()));
+        }
+        policy.check(&user.username, new_password)?;
+
+        Self::set_password(pool, user.id, new_password, false).await?;
+        Self::revoke_sessions(pool, user.id, user.session_id.as_deref()).await?;
+
+        info!("User '{}' changed the password", user.username);
+        Ok(())
+    }
+
+    /*
+      * Admin reset, returns a temporary password the user must change on next login
+      * Every session of the target is revoked
+    */
+    pub async fn reset_password(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        username: &str,
+    ) -> Result<String, AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let temp = Self::temp_password();
+        let user_id = Self::force_password(pool, username, &temp, true).await?;
+
+        info!("Admin '{}' reset the password of '{}' (id {})", requester.username, username, user_id);
+        Ok(temp)
+    }
+
+    /// No requester, for the offline `reset-password` command and admin resets
+    pub async fn force_password(
+        pool: &Pool<Sqlite>,
+        username: &str,
+        password: &str,
+        must_change: bool,
+    ) -> Result<i64, AuthError> {
+        let user_id: i64 = sqlx::query_scalar("SELECT id FROM users WHERE username = ?")
+            .bind(username)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(AuthError::UserNotFound)?;
+
+        Self::set_password(pool, user_id, password, must_change).await?;
+        Self::revoke_sessions(pool, user_id, None).await?;
+        Ok(user_id)
+    }
+
+    pub fn temp_password() -> String {
+        Self::random_string(TEMP_PASSWORD_LEN)
+    }
+}
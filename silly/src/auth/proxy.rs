@@ -0,0 +1,173 @@
+use std::net::IpAddr;
+use axum::http::HeaderMap;
+use tracing::{info, warn};
+use sqlx::{Pool, Sqlite, Row};
+use super::types::{
+    AuthController, AuthError,
+    AuthenticatedUser, ApiKeyScope, ProxyAuth,
+    Role, TrustedProxy,
+};
+
+/// Never typed by anyone, proxy users don't log in with a password
+const PROVISION_PASSWORD_LEN: usize = 48;
+
+impl TryFrom<&str> for TrustedProxy {
+    type Error = String;
+
+    /// `10.0.0.1`, `172.16.0.0/12` or `fd00::/8`
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse()
+            .map_err(|_| format!("Invalid trusted proxy address '{}'", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("Invalid trusted proxy prefix '{}'", s))?,
+            None => max,
+        };
+        Ok(TrustedProxy { addr, prefix })
+    }
+}
+
+impl TrustedProxy {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        /* Dual stack sockets report ipv4 peers as `::ffff:a.b.c.d` */
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl ProxyAuth {
+    pub fn enabled(&self) -> bool {
+        !self.trusted.is_empty()
+    }
+
+    /// Only the direct peer counts, `X-Forwarded-For` is as forgeable as the user header
+    pub fn trusts(&self, peer: Option<&str>) -> bool {
+        let Some(ip) = peer.and_then(|p| p.parse::<IpAddr>().ok()) else {
+            return false;
+        };
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Username and groups, `None` unless a trusted peer sent the user header
+    pub fn identity(&self, headers: &HeaderMap, peer: Option<&str>) -> Option<(String, Option<Vec<String>>)> {
+        if !self.enabled() {
+            return None;
+        }
+        let username = headers.get(self.user_header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())?;
+
+        if !self.trusts(peer) {
+            warn!("Ignoring '{}' header from untrusted peer {}", self.user_header, peer.unwrap_or("unknown"));
+            return None;
+        }
+
+        let groups = headers.get(self.groups_header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(',')
+                .map(|g| g.trim().to_string())
+                .filter(|g| !g.is_empty())
+                .collect());
+        Some((username.to_string(), groups))
+    }
+
+    /// `None` when no mapped group matches, the stored role stays
+    fn role_for(&self, groups: Option<&[String]>) -> Option<Role> {
+        let groups = groups?;
+        self.role_groups.iter()
+            .find(|(_, mapped)| groups.iter().any(|g| mapped.contains(g)))
+            .map(|(role, _)| role.clone())
+    }
+}
+
+impl AuthController {
+    /// Map a proxy identity to a silly user, provisioning and syncing the role if configured
+    pub async fn proxy_user(
+        pool: &Pool<Sqlite>,
+        proxy: &ProxyAuth,
+        username: &str,
+        groups: Option<&[String]>,
+    ) -> Result<AuthenticatedUser, AuthError> {
+        let mapped = proxy.role_for(groups);
+
+        let row = sqlx::query("SELECT id, role, disabled_at IS NOT NULL AS disabled FROM users WHERE username = ?")
+            .bind(username)
+            .fetch_optional(pool)
+            .await?;
+
+        let (id, role) = match row {
+            Some(row) if row.get::<bool, _>("disabled") => return Err(AuthError::AccountDisabled),
+            Some(row) => {
+                let id: i64 = row.get("id");
+                let stored: String = row.get("role");
+                match mapped {
+                    Some(role) if role != Role::Admin && stored == Role::Admin.to_string()
+                        && Self::is_last_admin(pool, username).await? =>
+                    {
+                        warn!("Proxy groups would demote '{}', the last admin, to {}; keeping admin", username, role);
+                        (id, stored)
+                    }
+                    Some(role) if role.to_string() != stored => {
+                        sqlx::query("UPDATE users SET role = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+                            .bind(role.to_string())
+                            .bind(id)
+                            .execute(pool)
+                            .await?;
+                        info!("Proxy groups changed the role of '{}' from {} to {}", username, stored, role);
+                        (id, role.to_string())
+                    }
+                    _ => (id, stored),
+                }
+            }
+            None if proxy.auto_provision => {
+                let role = mapped.unwrap_or(Role::User);
+                let password_hash = Self::hash_password(&Self::random_string(PROVISION_PASSWORD_LEN))?;
+                let id = sqlx::query(
+                    "INSERT INTO users (username, password_hash, role) VALUES (?, ?, ?)"
+                )
+                .bind(username)
+                .bind(password_hash)
+                .bind(role.to_string())
+                .execute(pool)
+                .await?
+                .last_insert_rowid();
+
+                info!("Provisioned {} '{}' from the auth proxy", role, username);
+                (id, role.to_string())
+            }
+            None => return Err(AuthError::UserNotFound),
+        };
+
+        Ok(AuthenticatedUser {
+            id,
+            username: username.to_string(),
+            role,
+            session_id: None,
+            /* The proxy did the login, silly's password doesn't matter */
+            must_change_password: false,
+            scope: ApiKeyScope::Full,
+        })
+    }
+}
@@ -0,0 +1,50 @@
+use super::types::{AuthError, AuthenticatedUser, Permission, Role};
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Admin => &[
+                ViewDownloads, AddDownloads, ManageDownloads,
+                ManageAllDownloads, ManageServer, ManageUsers,
+            ],
+            Role::Operator => &[ViewDownloads, AddDownloads, ManageDownloads, ManageAllDownloads],
+            Role::User => &[ViewDownloads, AddDownloads, ManageDownloads],
+            Role::Uploader => &[ViewDownloads, AddDownloads],
+            Role::Viewer => &[ViewDownloads],
+        }
+    }
+
+    pub fn can(&self, perm: Permission) -> bool {
+        self.permissions().contains(&perm)
+    }
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::ViewDownloads => "view downloads",
+            Permission::AddDownloads => "add downloads",
+            Permission::ManageDownloads => "manage downloads",
+            Permission::ManageAllDownloads => "manage all downloads",
+            Permission::ManageServer => "manage the server",
+            Permission::ManageUsers => "manage users",
+        }
+    }
+}
+
+impl AuthenticatedUser {
+    /// Unknown roles in the db get nothing
+    pub fn can(&self, perm: Permission) -> bool {
+        Role::try_from(self.role.as_str()).is_ok_and(|role| role.can(perm))
+    }
+
+    /// `Unauthorized` unless the role has `perm`
+    pub fn require(&self, perm: Permission) -> Result<(), AuthError> {
+        if self.can(perm) {
+            Ok(())
+        } else {
+            Err(AuthError::Unauthorized)
+        }
+    }
+}
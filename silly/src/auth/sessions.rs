@@ -0,0 +1,304 @@
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use sqlx::{Pool, Sqlite, Row};
+use super::{
+    auth::COOKIE_VAILDITY_DURATION,
+    types::{
+        AuthController, AuthError,
+        AuthenticatedUser, ApiKeyScope, JwtKeys, Role,
+        Session, SessionMeta, SessionTokens,
+    },
+};
+
+pub const ACCESS_COOKIE: &str = "auth_token";
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// Short lived, the refresh token keeps the session going
+pub const ACCESS_TOKEN_DURATION: usize = 15 * 60;
+/// qbit `SID`, clients log in again on a 403, same as qbittorrent's own session timeout
+pub const QBIT_SID_DURATION: usize = 60 * 60;
+/// Parallel requests race with the same refresh token, the old one stays valid this long
+const REFRESH_GRACE: i64 = 30;
+const SESSION_ID_LEN: usize = 24;
+const REFRESH_SECRET_LEN: usize = 48;
+
+impl AuthController {
+    pub(super) fn sha256(secret: &str) -> String {
+        format!("{:x}", Sha256::digest(secret.as_bytes()))
+    }
+
+    /*
+      * Session of a refresh token `<sid>.<secret>`, only if the secret matches
+      * The previous secret still counts during `REFRESH_GRACE`, same as refreshing
+    */
+    pub async fn refresh_sid(pool: &Pool<Sqlite>, refresh: &str) -> Result<Option<String>, AuthError> {
+        let Some((sid, secret)) = refresh.split_once('.') else {
+            return Ok(None);
+        };
+        let found = sqlx::query(
+            r#"
+            SELECT 1 FROM sessions
+            WHERE id = ? AND (
+                refresh_hash = ?
+                OR (prev_refresh_hash = ? AND rotated_at > datetime('now', ?))
+            )
+            "#
+        )
+        .bind(sid)
+        .bind(Self::sha256(secret))
+        .bind(Self::sha256(secret))
+        .bind(format!("-{} seconds", REFRESH_GRACE))
+        .fetch_optional(pool)
+        .await?;
+        Ok(found.map(|_| sid.to_string()))
+    }
+
+    /// New session row + its first token pair
+    pub(super) async fn open_session(
+        pool: &Pool<Sqlite>,
+        user: &AuthenticatedUser,
+        jwt: &JwtKeys,
+        meta: &SessionMeta,
+        access_ttl: usize,
+    ) -> Result<SessionTokens, AuthError> {
+        let role = Role::try_from(user.role.as_str())
+            .map_err(|_| AuthError::InvalidRole)?;
+        let sid = Self::random_string(SESSION_ID_LEN);
+        let secret = Self::random_string(REFRESH_SECRET_LEN);
+
+        sqlx::query(
+            r#"
+            INSERT INTO sessions (id, user_id, refresh_hash, user_agent, ip, expires_at)
+            VALUES (?, ?, ?, ?, ?, datetime('now', ?))
+            "#
+        )
+        .bind(&sid)
+        .bind(user.id)
+        .bind(Self::sha256(&secret))
+        .bind(&meta.user_agent)
+        .bind(&meta.ip)
+        .bind(format!("+{} seconds", COOKIE_VAILDITY_DURATION))
+        .execute(pool)
+        .await?;
+        sqlx::query("UPDATE users SET last_login_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(user.id)
+            .execute(pool)
+            .await?;
+
+        let access = Self::create_token(&user.username, user.id, role, &sid, jwt, access_ttl)?;
+        Ok(SessionTokens {
+            access,
+            refresh: Some(format!("{}.{}", sid, secret)),
+        })
+    }
+
+    /// The user behind a live session, role is read fresh from the db
+    pub async fn session_user(
+        pool: &Pool<Sqlite>,
+        sid: &str,
+    ) -> Result<Option<AuthenticatedUser>, AuthError> {
+        let row = sqlx::query(
+            r#"
+            SELECT u.id, u.username, u.role, u.must_change_password
+            FROM sessions s
+            JOIN users u ON u.id = s.user_id
+            WHERE s.id = ? AND s.revoked_at IS NULL AND s.expires_at > datetime('now')
+                AND u.disabled_at IS NULL
+            "#
+        )
+        .bind(sid)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(|row| AuthenticatedUser {
+            id: row.get("id"),
+            username: row.get("username"),
+            role: row.get("role"),
+            session_id: Some(sid.to_string()),
+            must_change_password: row.get("must_change_password"),
+            scope: ApiKeyScope::Full,
+        }))
+    }
+
+    /*
+      * Swap a refresh token for a new access token and rotate the refresh secret
+      * The previous secret is accepted for `REFRESH_GRACE` seconds without rotating again,
+      * after that replaying it means it leaked, so the whole session is revoked
+      * A secret that was never issued is only rejected, it doesn't prove anything leaked
+    */
+    pub async fn refresh_session(
+        pool: &Pool<Sqlite>,
+        jwt: &JwtKeys,
+        refresh: &str,
+    ) -> Result<SessionTokens, AuthError> {
+        let (sid, secret) = refresh.split_once('.')
+            .ok_or(AuthError::InvalidRefreshToken)?;
+        let hash = Self::sha256(secret);
+
+        let row = sqlx::query(
+            r#"
+            SELECT
+                s.refresh_hash, s.prev_refresh_hash,
+                s.rotated_at > datetime('now', ?) AS in_grace,
+                u.id, u.username, u.role
+            FROM sessions s
+            JOIN users u ON u.id = s.user_id
+            WHERE s.id = ? AND s.revoked_at IS NULL AND s.expires_at > datetime('now')
+                AND u.disabled_at IS NULL
+            "#
+        )
+        .bind(format!("-{} seconds", REFRESH_GRACE))
+        .bind(sid)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AuthError::InvalidRefreshToken)?;
+
+        let current: String = row.get("refresh_hash");
+        let previous: Option<String> = row.get("prev_refresh_hash");
+        let in_grace: bool = row.get("in_grace");
+        let uid: i64 = row.get("id");
+        let username: String = row.get("username");
+        let role = Role::try_from(row.get::<String, _>("role").as_str())
+            .map_err(|_| AuthError::InvalidRole)?;
+
+        let refresh = if hash == current {
+            let secret = Self::random_string(REFRESH_SECRET_LEN);
+            let res = sqlx::query(
+                r#"
+                UPDATE sessions
+                SET prev_refresh_hash = refresh_hash, refresh_hash = ?,
+                    rotated_at = CURRENT_TIMESTAMP, last_used_at = CURRENT_TIMESTAMP,
+                    expires_at = datetime('now', ?)
+                WHERE id = ? AND refresh_hash = ?
+                "#
+            )
+            .bind(Self::sha256(&secret))
+            .bind(format!("+{} seconds", COOKIE_VAILDITY_DURATION))
+            .bind(sid)
+            .bind(&hash)
+            .execute(pool)
+            .await?;
+
+            if res.rows_affected() == 1 {
+                Some(format!("{}.{}", sid, secret))
+            } else if Self::rotated_from(pool, sid, &hash).await? {
+                /* A parallel request rotated it first, its cookie is the one to keep */
+                None
+            } else {
+                return Err(AuthError::InvalidRefreshToken);
+            }
+        } else if previous.as_deref() == Some(hash.as_str()) {
+            if !in_grace {
+                warn!("Refresh token reused for session of '{}', revoking it", username);
+                Self::end_session(pool, sid).await?;
+                return Err(AuthError::InvalidRefreshToken);
+            }
+            None
+        } else {
+            /* Never issued for this session, just a guess at the sid */
+            return Err(AuthError::InvalidRefreshToken);
+        };
+
+        let access = Self::create_token(&username, uid, role, sid, jwt, ACCESS_TOKEN_DURATION)?;
+        Ok(SessionTokens { access, refresh })
+    }
+
+    /// `hash` was just rotated out and is still in its grace period
+    async fn rotated_from(pool: &Pool<Sqlite>, sid: &str, hash: &str) -> Result<bool, AuthError> {
+        let found = sqlx::query(
+            r#"
+            SELECT 1 FROM sessions
+            WHERE id = ? AND prev_refresh_hash = ? AND rotated_at > datetime('now', ?)
+                AND revoked_at IS NULL
+            "#
+        )
+        .bind(sid)
+        .bind(hash)
+        .bind(format!("-{} seconds", REFRESH_GRACE))
+        .fetch_optional(pool)
+        .await?;
+        Ok(found.is_some())
+    }
+
+    /// Live sessions of the user, newest first
+    pub async fn list_sessions(
+        pool: &Pool<Sqlite>,
+        user_id: i64,
+        current: Option<&str>,
+    ) -> Result<Vec<Session>, AuthError> {
+        let mut sessions = sqlx::query_as::<_, Session>(
+            r#"
+            SELECT id, user_agent, ip, created_at, last_used_at, expires_at
+            FROM sessions
+            WHERE user_id = ? AND revoked_at IS NULL AND expires_at > datetime('now')
+            ORDER BY last_used_at DESC
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        for session in sessions.iter_mut() {
+            session.current = current == Some(session.id.as_str());
+        }
+        Ok(sessions)
+    }
+
+    pub async fn revoke_session(
+        pool: &Pool<Sqlite>,
+        user_id: i64,
+        sid: &str,
+    ) -> Result<(), AuthError> {
+        let res = sqlx::query(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE id = ? AND user_id = ? AND revoked_at IS NULL"
+        )
+        .bind(sid)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(AuthError::SessionNotFound);
+        }
+        Ok(())
+    }
+
+    /// Logout, callers verify the token first
+    pub async fn end_session(pool: &Pool<Sqlite>, sid: &str) -> Result<(), AuthError> {
+        sqlx::query("UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(sid)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Kill every session of the user, `except` keeps the caller logged in
+    pub async fn revoke_sessions(
+        pool: &Pool<Sqlite>,
+        user_id: i64,
+        except: Option<&str>,
+    ) -> Result<u64, AuthError> {
+        let res = sqlx::query(
+            r#"
+            UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+            WHERE user_id = ? AND revoked_at IS NULL AND id IS NOT ?
+            "#
+        )
+        .bind(user_id)
+        .bind(except)
+        .execute(pool)
+        .await?;
+
+        info!("Revoked {} session(s) of user id {}", res.rows_affected(), user_id);
+        Ok(res.rows_affected())
+    }
+
+    /// Drop rows that can't be used anymore
+    pub async fn prune_sessions(pool: &Pool<Sqlite>) -> Result<u64, AuthError> {
+        let res = sqlx::query(
+            "DELETE FROM sessions WHERE expires_at <= datetime('now') OR revoked_at <= datetime('now', '-1 day')"
+        )
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+}
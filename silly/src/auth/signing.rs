@@ -0,0 +1,183 @@
+use std::{
+    io::Write,
+    fs::OpenOptions,
+    path::Path,
+    sync::RwLock,
+};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    Header, Validation,
+    DecodingKey, EncodingKey,
+};
+use sha2::{Digest, Sha256};
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::{info, warn};
+use super::{
+    sessions::{ACCESS_TOKEN_DURATION, QBIT_SID_DURATION},
+    totp::CHALLENGE_DURATION,
+    types::{AuthController, AuthError, JwtKeys, KeyRing, SigningKey},
+};
+
+const KEYS_FILE: &str = "jwt_keys.json";
+const SECRET_LEN: usize = 64;
+/// Longest ttl of anything signed, a retired key is trusted that long and no longer
+const ROTATION_GRACE: i64 = {
+    let mut longest = ACCESS_TOKEN_DURATION;
+    if QBIT_SID_DURATION > longest {
+        longest = QBIT_SID_DURATION;
+    }
+    if CHALLENGE_DURATION > longest {
+        longest = CHALLENGE_DURATION;
+    }
+    longest as i64
+};
+
+impl SigningKey {
+    fn new(secret: String) -> Self {
+        /* Same secret, same kid, so a pinned secret is recognized across restarts */
+        let kid = format!("{:x}", Sha256::digest(secret.as_bytes()))[..12].to_string();
+        SigningKey {
+            kid,
+            secret,
+            created_at: chrono::Utc::now().timestamp(),
+            retired_at: None,
+        }
+    }
+}
+
+impl KeyRing {
+    /// Make `key` the current one, the old current starts its grace
+    fn promote(&mut self, key: SigningKey) {
+        if let Some(mut old) = self.current.take()
+            && old.kid != key.kid {
+            old.retired_at = Some(chrono::Utc::now().timestamp());
+            self.previous.push(old);
+        }
+        self.previous.retain(|k| k.kid != key.kid);
+        self.current = Some(key);
+    }
+
+    fn prune(&mut self) {
+        let now = chrono::Utc::now().timestamp();
+        self.previous.retain(|k| k.retired_at.is_some_and(|t| now - t < ROTATION_GRACE));
+    }
+}
+
+impl JwtKeys {
+    /*
+      * Load `jwt_keys.json` from the data dir, creating it on first run
+      * `pinned` (`--jwt-secret`) always becomes the current key, the generated one retires
+      * `rotate` generates a fresh key, old tokens keep working for `ROTATION_GRACE`
+    */
+    pub fn load(data_dir: &Path, pinned: Option<String>, rotate: bool) -> Result<Self, AuthError> {
+        let path = data_dir.join(KEYS_FILE);
+        let mut ring: KeyRing = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                warn!("Unreadable {}, starting a new key ring: {}", KEYS_FILE, e);
+                KeyRing::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => KeyRing::default(),
+            Err(e) => return Err(AuthError::KeyStoreError(e)),
+        };
+
+        let is_pinned = pinned.is_some();
+        match pinned {
+            Some(secret) => {
+                if rotate {
+                    warn!("Ignoring key rotation, the jwt secret is pinned");
+                }
+                ring.promote(SigningKey::new(secret));
+            }
+            None if rotate || ring.current.is_none() => {
+                ring.promote(SigningKey::new(AuthController::random_string(SECRET_LEN)));
+                info!("Generated a new jwt signing key");
+            }
+            None => {}
+        }
+        ring.prune();
+
+        let keys = JwtKeys {
+            path,
+            pinned: is_pinned,
+            ring: RwLock::new(ring),
+        };
+        keys.save()?;
+        Ok(keys)
+    }
+
+    /// Owner read/write only, it signs every session
+    fn save(&self) -> Result<(), AuthError> {
+        let raw = {
+            let ring = self.ring.read().unwrap();
+            serde_json::to_string_pretty(&*ring).unwrap_or_default()
+        };
+
+        let mut opts = OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        let mut file = opts.open(&self.path).map_err(AuthError::KeyStoreError)?;
+        file.write_all(raw.as_bytes()).map_err(AuthError::KeyStoreError)?;
+
+        /* `mode` only applies on create, fix files from older runs */
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o600))
+                .map_err(AuthError::KeyStoreError)?;
+        }
+        Ok(())
+    }
+
+    /// Runtime rotation, returns the new `kid`
+    pub fn rotate(&self) -> Result<String, AuthError> {
+        if self.pinned {
+            return Err(AuthError::SigningKeyPinned);
+        }
+        let key = SigningKey::new(AuthController::random_string(SECRET_LEN));
+        let kid = key.kid.clone();
+        {
+            let mut ring = self.ring.write().unwrap();
+            ring.promote(key);
+            ring.prune();
+        }
+        self.save()?;
+        info!("Rotated jwt signing key, new kid '{}'", kid);
+        Ok(kid)
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, AuthError> {
+        let ring = self.ring.read().unwrap();
+        let key = ring.current.as_ref().ok_or(AuthError::TokenCreationError)?;
+
+        let header = Header {
+            kid: Some(key.kid.clone()),
+            ..Default::default()
+        };
+        encode(&header, claims, &EncodingKey::from_secret(key.secret.as_bytes()))
+            .map_err(|_| AuthError::TokenCreationError)
+    }
+
+    /// Pick the key by `kid`, retired ones only within their grace
+    pub fn verify<T: DeserializeOwned>(&self, tok: &str) -> Option<T> {
+        let kid = decode_header(tok).ok()?.kid?;
+        let secret = {
+            let ring = self.ring.read().unwrap();
+            let now = chrono::Utc::now().timestamp();
+            ring.current.iter()
+                .chain(ring.previous.iter().filter(|k| {
+                    k.retired_at.is_some_and(|t| now - t < ROTATION_GRACE)
+                }))
+                .find(|k| k.kid == kid)?
+                .secret
+                .clone()
+        };
+
+        decode::<T>(tok, &DecodingKey::from_secret(secret.as_bytes()), &Validation::default())
+            .ok()
+            .map(|token_data| token_data.claims)
+    }
+}
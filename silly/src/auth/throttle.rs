@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+use sqlx::{Pool, Sqlite};
+use super::types::{
+    Permission,
+    AuthController, AuthError,
+    AuthenticatedUser, Lockout,
+    LoginAttempts, LoginThrottle,
+};
+
+/// First delay after a failure, doubles with every next one
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// One ip may try a few usernames before it's locked
+const IP_FACTOR: u32 = 4;
+
+/// Verified when the username doesn't exist, so both paths spend an argon2 round
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    AuthController::hash_password("silly-dummy-password").unwrap_or_default()
+});
+
+impl LoginThrottle {
+    pub fn new(max_attempts: u32, lockout: Duration) -> Self {
+        /* Hash it now, not on the first unknown username */
+        LazyLock::force(&DUMMY_HASH);
+        LoginThrottle {
+            max_attempts: max_attempts.max(1),
+            lockout,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn keys(username: &str, ip: Option<&str>) -> Vec<(String, u32)> {
+        let mut keys = vec![(format!("user:{}", username.to_lowercase()), 1)];
+        if let Some(ip) = ip {
+            keys.push((format!("ip:{}", ip), IP_FACTOR));
+        }
+        keys
+    }
+
+    /// `Err(seconds)` while locked or inside the progressive delay
+    pub fn check(&self, username: &str, ip: Option<&str>) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut wait = Duration::ZERO;
+
+        for (key, _) in Self::keys(username, ip) {
+            let Some(entry) = entries.get(&key) else { continue };
+
+            if let Some(until) = entry.locked_until {
+                if until > now {
+                    wait = wait.max(until - now);
+                } else {
+                    /* Lock served, start over */
+                    entries.remove(&key);
+                }
+                continue;
+            }
+
+            let delay = BASE_DELAY
+                .saturating_mul(1 << entry.failures.saturating_sub(1).min(16))
+                .min(MAX_DELAY);
+            let next = entry.last_failure + delay;
+            if next > now {
+                wait = wait.max(next - now);
+            }
+        }
+
+        if wait.is_zero() {
+            Ok(())
+        } else {
+            Err(wait.as_secs().max(1))
+        }
+    }
+
+    pub fn failure(&self, username: &str, ip: Option<&str>) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        /* Forget the quiet ones, keeps the map small */
+        let lockout = self.lockout;
+        entries.retain(|_, e| match e.locked_until {
+            Some(until) => until > now,
+            None => now.duration_since(e.last_failure) < lockout,
+        });
+
+        for (key, factor) in Self::keys(username, ip) {
+            let entry = entries.entry(key.clone()).or_insert(LoginAttempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            entry.failures += 1;
+            entry.last_failure = now;
+
+            if entry.locked_until.is_none() && entry.failures >= self.max_attempts * factor {
+                entry.locked_until = Some(now + self.lockout);
+                warn!(
+                    "Locked out '{}' for {}s after {} failed logins (last username '{}', ip {})",
+                    key, self.lockout.as_secs(), entry.failures, username, ip.unwrap_or("unknown"),
+                );
+            }
+        }
+    }
+
+    pub fn success(&self, username: &str, ip: Option<&str>) {
+        let mut entries = self.entries.lock().unwrap();
+        for (key, _) in Self::keys(username, ip) {
+            entries.remove(&key);
+        }
+    }
+
+    /// `target` is a username or `ip:<addr>`, true if something was cleared
+    pub fn unlock(&self, target: &str) -> bool {
+        let key = if target.starts_with("ip:") {
+            target.to_string()
+        } else {
+            format!("user:{}", target.to_lowercase())
+        };
+        self.entries.lock().unwrap().remove(&key).is_some()
+    }
+
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let now = Instant::now();
+        self.entries.lock().unwrap()
+            .iter()
+            .filter_map(|(key, e)| {
+                let until = e.locked_until.filter(|u| *u > now)?;
+                Some(Lockout {
+                    key: key.clone(),
+                    failures: e.failures,
+                    retry_after: (until - now).as_secs().max(1),
+                })
+            })
+            .collect()
+    }
+}
+
+impl AuthController {
+    /// Same cost whether the user exists or not
+    pub(super) fn burn_dummy_verify(password: &str) {
+        let _ = Self::verify_password(password, &DUMMY_HASH);
+    }
+
+    /// `verify_credentials` behind the throttle, every password login goes through this
+    pub async fn attempt_credentials(
+        pool: &Pool<Sqlite>,
+        throttle: &LoginThrottle,
+        username: &str,
+        password: &str,
+        ip: Option<&str>,
+    ) -> Result<AuthenticatedUser, AuthError> {
+        throttle.check(username, ip).map_err(AuthError::TooManyAttempts)?;
+
+        match Self::verify_credentials(pool, username, password).await {
+            Ok(user) => {
+                /* With a second factor only `login_totp` clears it, else a known password resets the code guessing */
+                if !Self::has_totp(pool, user.id).await? {
+                    throttle.success(username, ip);
+                }
+                Ok(user)
+            }
+            Err(AuthError::InvalidCredentials) => {
+                throttle.failure(username, ip);
+                Err(AuthError::InvalidCredentials)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Admin only
+    pub fn unlock_login(
+        throttle: &LoginThrottle,
+        requester: &AuthenticatedUser,
+        target: &str,
+    ) -> Result<bool, AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let cleared = throttle.unlock(target);
+        if cleared {
+            info!("Admin '{}' unlocked logins of '{}'", requester.username, target);
+        }
+        Ok(cleared)
+    }
+}
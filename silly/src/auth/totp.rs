@@ -0,0 +1,290 @@
+use rand::Rng;
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::{info, warn};
+use sqlx::{Pool, Sqlite, Row};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::types::{
+    Permission,
+    AuthController, AuthError,
+    AuthenticatedUser, ApiKeyScope, ChallengeClaims,
+    JwtKeys, LoginThrottle,
+    SessionMeta, SessionTokens,
+};
+
+const TOTP_ISSUER: &str = "silly";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP: u64 = 30;
+/// One step of clock drift either way
+const TOTP_SKEW: u8 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODES: usize = 10;
+/// Time to type the code after the password
+pub(super) const CHALLENGE_DURATION: usize = 5 * 60;
+const CHALLENGE_PURPOSE: &str = "totp";
+
+impl AuthController {
+    fn totp(secret: &str, username: &str) -> Result<TOTP, AuthError> {
+        let bytes = Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .map_err(|_| AuthError::InvalidTotp)?;
+        TOTP::new(
+            Algorithm::SHA1,
+            TOTP_DIGITS,
+            TOTP_SKEW,
+            TOTP_STEP,
+            bytes,
+            Some(TOTP_ISSUER.to_string()),
+            /* `:` separates issuer and account in the uri */
+            username.replace(':', "_"),
+        )
+        .map_err(|_| AuthError::InvalidTotp)
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Step of the matching code, `None` if no step within the skew matches
+    fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+        let now = Self::now_secs();
+        let skew = TOTP_SKEW as i64;
+        (-skew..=skew)
+            .map(|d| now as i64 + d * TOTP_STEP as i64)
+            .filter(|t| *t >= 0)
+            .find(|t| totp.generate(*t as u64) == code)
+            .map(|t| t / TOTP_STEP as i64)
+    }
+
+    pub async fn has_totp(pool: &Pool<Sqlite>, user_id: i64) -> Result<bool, AuthError> {
+        let enabled: Option<bool> = sqlx::query_scalar("SELECT totp_enabled FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+        Ok(enabled.unwrap_or(false))
+    }
+
+    /// New pending secret, returns it with the `otpauth://` uri for the qr code
+    pub async fn totp_setup(
+        pool: &Pool<Sqlite>,
+        user: &AuthenticatedUser,
+    ) -> Result<(String, String), AuthError> {
+        if Self::has_totp(pool, user.id).await? {
+            return Err(AuthError::TotpAlreadyEnabled);
+        }
+
+        let mut bytes = vec![0u8; SECRET_BYTES];
+        rand::rng().fill(&mut bytes[..]);
+        let secret = Secret::Raw(bytes).to_encoded().to_string();
+        let url = Self::totp(&secret, &user.username)?.get_url();
+
+        sqlx::query("UPDATE users SET totp_secret = ?, totp_last_step = NULL WHERE id = ?")
+            .bind(&secret)
+            .bind(user.id)
+            .execute(pool)
+            .await?;
+
+        Ok((secret, url))
+    }
+
+    /// First valid code turns it on, returns the recovery codes (shown once)
+    pub async fn totp_confirm(
+        pool: &Pool<Sqlite>,
+        user: &AuthenticatedUser,
+        code: &str,
+    ) -> Result<Vec<String>, AuthError> {
+        let row = sqlx::query("SELECT totp_secret, totp_enabled FROM users WHERE id = ?")
+            .bind(user.id)
+            .fetch_one(pool)
+            .await?;
+
+        if row.get::<bool, _>("totp_enabled") {
+            return Err(AuthError::TotpAlreadyEnabled);
+        }
+        let secret: Option<String> = row.get("totp_secret");
+        let secret = secret.ok_or(AuthError::TotpNotEnabled)?;
+
+        let totp = Self::totp(&secret, &user.username)?;
+        let step = Self::matching_step(&totp, code.trim()).ok_or(AuthError::InvalidTotp)?;
+
+        sqlx::query("UPDATE users SET totp_enabled = 1, totp_last_step = ? WHERE id = ?")
+            .bind(step)
+            .bind(user.id)
+            .execute(pool)
+            .await?;
+
+        info!("User '{}' enabled two factor auth", user.username);
+        Self::new_recovery_codes(pool, user.id).await
+    }
+
+    /// Replaces every previous code
+    async fn new_recovery_codes(pool: &Pool<Sqlite>, user_id: i64) -> Result<Vec<String>, AuthError> {
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        let mut codes = Vec::with_capacity(RECOVERY_CODES);
+        for _ in 0..RECOVERY_CODES {
+            let raw = Self::random_string(10).to_lowercase();
+            let code = format!("{}-{}", &raw[..5], &raw[5..]);
+            sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)")
+                .bind(user_id)
+                .bind(Self::sha256(&code))
+                .execute(pool)
+                .await?;
+            codes.push(code);
+        }
+        Ok(codes)
+    }
+
+    /// Turn it off and drop the recovery codes
+    pub async fn totp_clear(pool: &Pool<Sqlite>, user_id: i64) -> Result<(), AuthError> {
+        sqlx::query(
+            "UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL WHERE id = ?"
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Self service, needs the password again, guesses count against the login throttle
+    pub async fn totp_disable(
+        pool: &Pool<Sqlite>,
+        throttle: &LoginThrottle,
+        user: &AuthenticatedUser,
+        password: &str,
+        ip: Option<&str>,
+    ) -> Result<(), AuthError> {
+        Self::attempt_credentials(pool, throttle, &user.username, password, ip).await?;
+        if !Self::has_totp(pool, user.id).await? {
+            return Err(AuthError::TotpNotEnabled);
+        }
+        Self::totp_clear(pool, user.id).await?;
+        info!("User '{}' disabled two factor auth", user.username);
+        Ok(())
+    }
+
+    /// Admin reset for a user who lost the device and the codes
+    pub async fn totp_reset(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        username: &str,
+    ) -> Result<(), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let user_id: i64 = sqlx::query_scalar("SELECT id FROM users WHERE username = ?")
+            .bind(username)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(AuthError::UserNotFound)?;
+
+        Self::totp_clear(pool, user_id).await?;
+        warn!("Admin '{}' reset two factor auth of '{}'", requester.username, username);
+        Ok(())
+    }
+
+    pub(super) fn totp_challenge(jwt: &JwtKeys, user: &AuthenticatedUser) -> Result<String, AuthError> {
+        jwt.sign(&ChallengeClaims {
+            uid: user.id,
+            sub: user.username.clone(),
+            exp: Self::now_secs() as usize + CHALLENGE_DURATION,
+            purpose: CHALLENGE_PURPOSE.to_string(),
+        })
+    }
+
+    /// A code of the current step or an unused recovery code
+    async fn verify_second_factor(pool: &Pool<Sqlite>, user_id: i64, username: &str, code: &str) -> Result<(), AuthError> {
+        let code = code.trim();
+        let secret: String = sqlx::query_scalar("SELECT totp_secret FROM users WHERE id = ? AND totp_enabled = 1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(AuthError::TotpNotEnabled)?;
+
+        if code.len() == TOTP_DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+            let totp = Self::totp(&secret, username)?;
+            let step = Self::matching_step(&totp, code).ok_or(AuthError::InvalidTotp)?;
+            /* Each code works once, a parallel login with the same code loses the update */
+            let res = sqlx::query(
+                "UPDATE users SET totp_last_step = ? WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)"
+            )
+            .bind(step)
+            .bind(user_id)
+            .bind(step)
+            .execute(pool)
+            .await?;
+
+            if res.rows_affected() == 0 {
+                return Err(AuthError::InvalidTotp);
+            }
+            return Ok(());
+        }
+
+        let hash = Self::sha256(&code.to_lowercase());
+        let res = sqlx::query(
+            "UPDATE recovery_codes SET used_at = CURRENT_TIMESTAMP WHERE user_id = ? AND code_hash = ? AND used_at IS NULL"
+        )
+        .bind(user_id)
+        .bind(hash)
+        .execute(pool)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(AuthError::InvalidTotp);
+        }
+        info!("User '{}' logged in with a recovery code", username);
+        Ok(())
+    }
+
+    /// Second login step, the challenge from the password step + a code
+    pub async fn login_totp(
+        pool: &Pool<Sqlite>,
+        throttle: &LoginThrottle,
+        jwt: &JwtKeys,
+        challenge: &str,
+        code: &str,
+        meta: &SessionMeta,
+        access_ttl: usize,
+    ) -> Result<(AuthenticatedUser, SessionTokens), AuthError> {
+        let claims: ChallengeClaims = jwt.verify(challenge)
+            .filter(|c: &ChallengeClaims| c.purpose == CHALLENGE_PURPOSE)
+            .ok_or(AuthError::InvalidCredentials)?;
+
+        let ip = meta.ip.as_deref();
+        throttle.check(&claims.sub, ip).map_err(AuthError::TooManyAttempts)?;
+
+        if let Err(e) = Self::verify_second_factor(pool, claims.uid, &claims.sub, code).await {
+            if matches!(e, AuthError::InvalidTotp) {
+                throttle.failure(&claims.sub, ip);
+            }
+            return Err(e);
+        }
+        throttle.success(&claims.sub, ip);
+
+        /* Fresh role and flags, not the ones of the challenge */
+        let row = sqlx::query("SELECT username, role, must_change_password FROM users WHERE id = ? AND disabled_at IS NULL")
+            .bind(claims.uid)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(AuthError::UserNotFound)?;
+        let user = AuthenticatedUser {
+            id: claims.uid,
+            username: row.get("username"),
+            role: row.get("role"),
+            session_id: None,
+            must_change_password: row.get("must_change_password"),
+            scope: ApiKeyScope::Full,
+        };
+
+        let tokens = Self::open_session(pool, &user, jwt, meta, access_ttl).await?;
+        info!("User '{}' logged in successfully with two factor auth", user.username);
+        Ok((user, tokens))
+    }
+}
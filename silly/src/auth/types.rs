@@ -1,3 +1,11 @@
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
 pub struct AuthController;
@@ -7,15 +15,27 @@ pub struct AuthController;
 pub enum Role {
     #[serde(rename = "admin")]
     Admin,
+    /// Manages everyone's downloads, not users or the server
+    #[serde(rename = "operator")]
+    Operator,
     #[serde(rename = "user")]
     User,
+    /// Adds downloads, can't pause or delete them
+    #[serde(rename = "uploader")]
+    Uploader,
+    /// Read only
+    #[serde(rename = "viewer")]
+    Viewer,
 } 
 
 impl std::fmt::Display for Role {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Role::Admin => write!(f, "admin"),
+            Role::Operator => write!(f, "operator"),
             Role::User => write!(f, "user"),
+            Role::Uploader => write!(f, "uploader"),
+            Role::Viewer => write!(f, "viewer"),
         }
     }
 }
@@ -25,12 +45,31 @@ impl TryFrom<&str> for Role {
     fn try_from(s: &str) -> Result<Self, Self::Error> {
         match s {
             "admin" => Ok(Role::Admin),
+            "operator" => Ok(Role::Operator),
             "user" => Ok(Role::User),
+            "uploader" => Ok(Role::Uploader),
+            "viewer" => Ok(Role::Viewer),
             _ => Err(()),
         }
     }
 }
 
+/// What a role may do, checked with `AuthenticatedUser::can`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Own history, status and details
+    ViewDownloads,
+    AddDownloads,
+    /// Pause, resume, move and remove own downloads
+    ManageDownloads,
+    /// Same on downloads of every user
+    ManageAllDownloads,
+    /// Global aria2 options, signing keys
+    ManageServer,
+    /// Accounts, password/totp resets, lockouts
+    ManageUsers,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct User {
     pub id: i64,
@@ -44,6 +83,8 @@ pub struct Claims {
     pub sub: String,  
     pub role: Role,
     pub exp: usize,  
+    /// `sessions.id`, token dies with its session
+    pub sid: String,
 }
 
 /// for Auth operations
@@ -60,6 +101,26 @@ pub enum AuthError {
     DbError(sqlx::Error),
     CannotDeleteLastAdmin,
     AdminAlreadyRegistered,
+    InvalidApiKey,
+    ApiKeyNotFound,
+    SessionNotFound,
+    InvalidRefreshToken,
+    SigningKeyPinned,
+    KeyStoreError(std::io::Error),
+    /// Why the policy rejected it
+    WeakPassword(String),
+    /// Throttled or locked out, seconds until the next try
+    TooManyAttempts(u64),
+    InvalidTotp,
+    TotpAlreadyEnabled,
+    TotpNotEnabled,
+    AccountDisabled,
+    CannotDemoteLastAdmin,
+    InvalidUsername,
+    InvalidSetupToken,
+    InvalidInvitation,
+    RegistrationClosed,
+    AccountPending,
 }
 
 impl From<sqlx::Error> for AuthError {
@@ -72,18 +133,26 @@ impl From<sqlx::Error> for AuthError {
 pub struct RegAdminRequest {
     pub username: String,
     pub password: String,
+    /// Printed in the log at startup
+    pub setup_token: String,
+}
+
+/// One time token guarding `reg_admin`, `None` once an admin exists
+#[derive(Debug, Default)]
+pub struct SetupToken {
+    pub token: Mutex<Option<String>>,
 }
 
 #[derive(Deserialize)]
 pub struct CreateUserReq {
     pub username: String,
     pub password: String,
-    pub requester_username: String,
+    /// `user` when omitted
+    pub role: Option<Role>,
 }
 
 #[derive(Deserialize)]
 pub struct DeleteUserReq {
-    pub requester_username: String,
     pub target_username: String,
 }
 
@@ -98,4 +167,306 @@ pub struct AuthenticatedUser {
     pub id: i64,
     pub username: String,
     pub role: String,
+    /// Only for cookie sessions, `None` for api keys and basic auth
+    pub session_id: Option<String>,
+    /// Temporary password from an admin reset, only the change is allowed
+    pub must_change_password: bool,
+    /// `Full` unless it came with an api key, checked again by routes that multiplex actions
+    pub scope: ApiKeyScope,
+}
+
+/// What an api key is allowed to touch
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default, sqlx::Type)]
+#[sqlx(type_name = "TEXT")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// Same rights as the cookie session
+    #[default]
+    Full,
+    /// Only `GET`s and aria2 details
+    ReadOnly,
+    /// Read only + adding uris/torrents, for *arr like tools
+    AddOnly,
+}
+
+/// Never expose the `key_hash`
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub id: i64,
+    pub label: String,
+    pub prefix: String,
+    pub scope: ApiKeyScope,
+    pub created_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyReq {
+    pub label: String,
+    #[serde(default)]
+    pub scope: ApiKeyScope,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeApiKeyReq {
+    pub id: i64,
+}
+
+/// Who is logging in, stored with the session
+#[derive(Debug, Clone, Default)]
+pub struct SessionMeta {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// `refresh` is `None` when a just rotated token was replayed, keep the current cookie
+#[derive(Debug, Clone)]
+pub struct SessionTokens {
+    pub access: String,
+    pub refresh: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: NaiveDateTime,
+    /// Is it the one making the request
+    #[sqlx(skip)]
+    pub current: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionReq {
+    pub id: String,
+}
+
+/// One jwt signing secret, `kid` goes in the token header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SigningKey {
+    pub kid: String,
+    pub secret: String,
+    pub created_at: i64,
+    pub retired_at: Option<i64>,
+}
+
+/// What `jwt_keys.json` holds
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyRing {
+    pub current: Option<SigningKey>,
+    /// Retired keys, still verify until their grace ends
+    #[serde(default)]
+    pub previous: Vec<SigningKey>,
+}
+
+/// Persistent signing keys, shared through the state
+#[derive(Debug)]
+pub struct JwtKeys {
+    pub path: PathBuf,
+    /// Set by `--jwt-secret`, rotating it at runtime would be undone on restart
+    pub pinned: bool,
+    pub ring: RwLock<KeyRing>,
+}
+
+/// Applies to every new password, existing ones are left alone
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub require_mixed_case: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordReq {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordReq {
+    pub username: String,
+}
+
+/// Failed logins of one username or ip
+#[derive(Debug, Clone)]
+pub struct LoginAttempts {
+    pub failures: u32,
+    pub last_failure: Instant,
+    pub locked_until: Option<Instant>,
+}
+
+/// In memory, keyed by `user:<name>` and `ip:<addr>`
+#[derive(Debug)]
+pub struct LoginThrottle {
+    pub max_attempts: u32,
+    pub lockout: Duration,
+    pub entries: Mutex<HashMap<String, LoginAttempts>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockout {
+    /// `user:<name>` or `ip:<addr>`
+    pub key: String,
+    pub failures: u32,
+    pub retry_after: u64,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockReq {
+    /// Username or `ip:<addr>`
+    pub username: String,
+}
+
+/// Password was right, `TotpRequired` carries the challenge for the second step
+#[derive(Debug)]
+pub enum LoginOutcome {
+    Session(AuthenticatedUser, SessionTokens),
+    TotpRequired(String),
+}
+
+/// Short lived, proves the password step, never accepted as a session token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeClaims {
+    pub uid: i64,
+    pub sub: String,
+    pub exp: usize,
+    /// Always "totp"
+    pub purpose: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpLoginReq {
+    pub challenge: String,
+    /// Current totp code or a recovery code
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpConfirmReq {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpDisableReq {
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetTotpReq {
+    pub username: String,
+}
+
+/// One `--trusted-proxies` entry, a plain ip is a /32 (/128)
+#[derive(Debug, Clone)]
+pub struct TrustedProxy {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+/// Identity from an auth proxy in front of silly (Authelia, oauth2-proxy..)
+#[derive(Debug, Clone, Default)]
+pub struct ProxyAuth {
+    /// Empty turns the mode off
+    pub trusted: Vec<TrustedProxy>,
+    pub user_header: String,
+    pub groups_header: String,
+    /// Create unknown users on first sight
+    pub auto_provision: bool,
+    /// Highest role first, users get the first one they have a group of
+    /// Users with none of these groups keep their stored role
+    pub role_groups: Vec<(Role, Vec<String>)>,
+}
+
+/// One row of the admin user list
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSummary {
+    pub id: i64,
+    pub username: String,
+    pub role: String,
+    pub created_at: Option<NaiveDateTime>,
+    pub last_login_at: Option<NaiveDateTime>,
+    pub disabled_at: Option<NaiveDateTime>,
+    /// Signed up on their own, waiting for an admin
+    pub pending_approval: bool,
+    pub totp_enabled: bool,
+    /// Rows in `download_history`
+    pub downloads: i64,
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleReq {
+    pub username: String,
+    pub role: Role,
+}
+
+/// Disable, enable and approve
+#[derive(Deserialize)]
+pub struct DisableUserReq {
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct RenameUserReq {
+    pub username: String,
+    pub new_username: String,
+}
+
+#[derive(Deserialize)]
+pub struct TransferDownloadsReq {
+    pub from: String,
+    pub to: String,
+}
+
+/// Never expose the `token_hash`
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct Invitation {
+    pub id: i64,
+    pub hint: String,
+    pub role: String,
+    pub max_uses: i64,
+    pub uses: i64,
+    /// Username of the admin, `None` once deleted
+    pub created_by: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateInvitationReq {
+    /// `user` when omitted
+    pub role: Option<Role>,
+    /// 1 when omitted
+    pub max_uses: Option<i64>,
+    /// A week when omitted
+    pub expires_in_hours: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeInvitationReq {
+    pub id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterReq {
+    pub username: String,
+    pub password: String,
+    /// Without one it's an open sign up, if allowed
+    pub invitation: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RegistrationSettingsReq {
+    pub open: bool,
 }
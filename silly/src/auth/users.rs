@@ -0,0 +1,190 @@
+use tracing::{info, warn};
+use sqlx::{Pool, Sqlite};
+use super::types::{
+    AuthController, AuthError,
+    AuthenticatedUser, Permission,
+    Role, UserSummary,
+};
+
+impl AuthController {
+    async fn user_id(pool: &Pool<Sqlite>, username: &str) -> Result<i64, AuthError> {
+        sqlx::query_scalar("SELECT id FROM users WHERE username = ?")
+            .bind(username)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(AuthError::UserNotFound)
+    }
+
+    /// Is `username` the only enabled admin, demoting or disabling it locks everyone out
+    pub(super) async fn is_last_admin(pool: &Pool<Sqlite>, username: &str) -> Result<bool, AuthError> {
+        let (is_admin, others): (bool, i64) = sqlx::query_as(
+            r#"
+            SELECT
+                EXISTS(SELECT 1 FROM users WHERE username = ? AND role = 'admin' AND disabled_at IS NULL),
+                (SELECT COUNT(*) FROM users WHERE username != ? AND role = 'admin' AND disabled_at IS NULL)
+            "#
+        )
+        .bind(username)
+        .bind(username)
+        .fetch_one(pool)
+        .await?;
+        Ok(is_admin && others == 0)
+    }
+
+    pub async fn list_users(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+    ) -> Result<Vec<UserSummary>, AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let users = sqlx::query_as::<_, UserSummary>(
+            r#"
+            SELECT
+                u.id, u.username, u.role, u.created_at, u.last_login_at, u.disabled_at, u.pending_approval, u.totp_enabled,
+                (SELECT COUNT(*) FROM download_history h WHERE h.user_id = u.id) AS downloads
+            FROM users u
+            ORDER BY u.username
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(users)
+    }
+
+    /// Sessions keep going, the role is read fresh on every request
+    pub async fn set_role(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        username: &str,
+        role: Role,
+    ) -> Result<(), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let user_id = Self::user_id(pool, username).await?;
+        if role != Role::Admin && Self::is_last_admin(pool, username).await? {
+            return Err(AuthError::CannotDemoteLastAdmin);
+        }
+
+        sqlx::query("UPDATE users SET role = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(role.to_string())
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        info!("Admin '{}' changed the role of '{}' to {}", requester.username, username, role);
+        Ok(())
+    }
+
+    /// Logged out everywhere and refused until enabled, downloads and history stay
+    pub async fn disable_user(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        username: &str,
+    ) -> Result<(), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        if requester.username == username {
+            return Err(AuthError::CannotDeleteSelf);
+        }
+        let user_id = Self::user_id(pool, username).await?;
+        if Self::is_last_admin(pool, username).await? {
+            return Err(AuthError::CannotDemoteLastAdmin);
+        }
+
+        sqlx::query(
+            "UPDATE users SET disabled_at = COALESCE(disabled_at, CURRENT_TIMESTAMP), updated_at = CURRENT_TIMESTAMP WHERE id = ?"
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+        Self::revoke_sessions(pool, user_id, None).await?;
+
+        warn!("Admin '{}' disabled '{}'", requester.username, username);
+        Ok(())
+    }
+
+    /// Also approves a pending sign up
+    pub async fn enable_user(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        username: &str,
+    ) -> Result<(), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let user_id = Self::user_id(pool, username).await?;
+
+        sqlx::query("UPDATE users SET disabled_at = NULL, pending_approval = 0, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        info!("Admin '{}' enabled '{}'", requester.username, username);
+        Ok(())
+    }
+
+    pub async fn rename_user(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        username: &str,
+        new_username: &str,
+    ) -> Result<(), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let new_username = new_username.trim();
+        if new_username.is_empty() {
+            return Err(AuthError::InvalidUsername);
+        }
+        let user_id = Self::user_id(pool, username).await?;
+
+        let res = sqlx::query("UPDATE users SET username = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(new_username)
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .map_err(AuthError::from);
+
+        match res {
+            Ok(_) => {
+                info!("Admin '{}' renamed '{}' to '{}'", requester.username, username, new_username);
+                Ok(())
+            }
+            Err(AuthError::DbError(sqlx::Error::Database(db_err)))
+            if db_err.message().contains("UNIQUE") => {
+                Err(AuthError::UserAlreadyExists)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /*
+      * Hand every download of `from` to `to`, before deleting a departing user
+      * Categories move too, unless `to` already has one with the same name
+      * Returns the number of downloads moved
+    */
+    /// How many moved and the ids of both users
+    pub async fn transfer_downloads(
+        pool: &Pool<Sqlite>,
+        requester: &AuthenticatedUser,
+        from: &str,
+        to: &str,
+    ) -> Result<(u64, i64, i64), AuthError> {
+        requester.require(Permission::ManageUsers)?;
+        let from_id = Self::user_id(pool, from).await?;
+        let to_id = Self::user_id(pool, to).await?;
+        if from_id == to_id {
+            return Ok((0, from_id, to_id));
+        }
+
+        let mut tx = pool.begin().await?;
+        let moved = sqlx::query("UPDATE download_history SET user_id = ? WHERE user_id = ?")
+            .bind(to_id)
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("UPDATE OR IGNORE categories SET user_id = ? WHERE user_id = ?")
+            .bind(to_id)
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        info!("Admin '{}' moved {} download(s) from '{}' to '{}'", requester.username, moved, from, to);
+        Ok((moved, from_id, to_id))
+    }
+}
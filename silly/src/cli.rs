@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 /// Inlcude random letters as short so they don't look unaligned :'(
@@ -37,8 +37,96 @@ pub struct Args {
     #[arg(short='c', long, env = "SILLY_SSL_CERT")]
     pub ssl: Option<PathBuf>,
 
-    /// JWT secret, Optional but only be define in enviroment variable
-    #[arg(short='j', long, env = "SILLY_JWT_SECRET", hide_env_values = false)]
+    /// JWT secret, pins the signing key instead of the generated one in data dir
+    #[arg(short='j', long, env = "SILLY_JWT_SECRET", hide_env_values = true)]
     pub jwt_secret: Option<String>,
+
+    /// Generate a new JWT signing key, tokens of the old one stay valid for a while
+    #[arg(short='r', long)]
+    pub rotate_jwt_key: bool,
+
+    /// Minimum length of new passwords
+    #[arg(long, env = "SILLY_PASSWORD_MIN_LENGTH", default_value_t = 8)]
+    pub password_min_length: usize,
+
+    /// New passwords need a digit
+    #[arg(long, env = "SILLY_PASSWORD_REQUIRE_DIGIT")]
+    pub password_require_digit: bool,
+
+    /// New passwords need a symbol
+    #[arg(long, env = "SILLY_PASSWORD_REQUIRE_SYMBOL")]
+    pub password_require_symbol: bool,
+
+    /// New passwords need upper and lower case letters
+    #[arg(long, env = "SILLY_PASSWORD_REQUIRE_MIXED_CASE")]
+    pub password_require_mixed_case: bool,
+
+    /// Failed logins of a username before it's locked out (an ip gets 4x)
+    #[arg(long, env = "SILLY_LOGIN_MAX_ATTEMPTS", default_value_t = 5)]
+    pub login_max_attempts: u32,
+
+    /// Lockout duration in seconds
+    #[arg(long, env = "SILLY_LOGIN_LOCKOUT_SECS", default_value_t = 900)]
+    pub login_lockout_secs: u64,
+
+    /// Trust the auth proxy header from these ips/cidrs (comma separated), off when empty
+    #[arg(long, env = "SILLY_TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Vec<String>,
+
+    /// Header carrying the username set by the auth proxy
+    #[arg(long, env = "SILLY_PROXY_USER_HEADER", default_value = "Remote-User")]
+    pub proxy_user_header: String,
+
+    /// Header carrying the comma separated groups of the user
+    #[arg(long, env = "SILLY_PROXY_GROUPS_HEADER", default_value = "Remote-Groups")]
+    pub proxy_groups_header: String,
+
+    /// Create unknown proxy users instead of refusing them
+    #[arg(long, env = "SILLY_PROXY_AUTO_PROVISION")]
+    pub proxy_auto_provision: bool,
+
+    /// Proxy groups mapped to the admin role (comma separated)
+    #[arg(long, env = "SILLY_PROXY_ADMIN_GROUPS", value_delimiter = ',')]
+    pub proxy_admin_groups: Vec<String>,
+
+    /// Proxy groups mapped to the operator role, a higher role's groups win
+    #[arg(long, env = "SILLY_PROXY_OPERATOR_GROUPS", value_delimiter = ',')]
+    pub proxy_operator_groups: Vec<String>,
+
+    /// Proxy groups mapped to the user role
+    #[arg(long, env = "SILLY_PROXY_USER_GROUPS", value_delimiter = ',')]
+    pub proxy_user_groups: Vec<String>,
+
+    /// Proxy groups mapped to the uploader role
+    #[arg(long, env = "SILLY_PROXY_UPLOADER_GROUPS", value_delimiter = ',')]
+    pub proxy_uploader_groups: Vec<String>,
+
+    /// Proxy groups mapped to the viewer role, users in none of the mapped groups keep their role
+    #[arg(long, env = "SILLY_PROXY_VIEWER_GROUPS", value_delimiter = ',')]
+    pub proxy_viewer_groups: Vec<String>,
+
+    /// Websocket connections to aria2, the last one does the polling when there's more than one
+    #[arg(long, env = "SILLY_ARIA2_CONNECTIONS", default_value_t = 2)]
+    pub aria2_connections: usize,
+
+    /// Seconds between saves of the download progress, all in one transaction
+    #[arg(long, env = "SILLY_PROGRESS_FLUSH_SECS", default_value_t = 5)]
+    pub progress_flush_secs: u64,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Offline maintenance, runs against the database in data dir and exits
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Reset a user's password, for locked out operators
+    ResetPassword {
+        username: String,
+
+        /// New password, a temporary one is generated and printed if omitted
+        #[arg(long)]
+        password: Option<String>,
+    },
 }
 
@@ -2,7 +2,7 @@ use tracing::info;
 use std::path::Path;
 use std::str::FromStr;
 use sqlx::{
-    Pool, Sqlite,
+    Connection, ConnectOptions, Pool, Sqlite,
     sqlite::{
         SqliteJournalMode,
         SqlitePoolOptions,
@@ -23,14 +23,30 @@ pub async fn init_db(data_dir: &Path) -> Result<Pool<Sqlite>, sqlx::Error> {
         .journal_mode(SqliteJournalMode::Wal)
         .foreign_keys(true);
 
+    /*
+      * Migrations get their own connection with foreign keys off, rebuilding a table
+      * (the only way to change a CHECK) would cascade deletes into everything referencing it
+      * Can't be switched inside the migration, sqlx runs each one in a transaction
+    */
+    let mut conn = conc_options.clone()
+        .foreign_keys(false)
+        .connect()
+        .await?;
+    sqlx::migrate!("./migrations")
+        .run(&mut conn)
+        .await?;
+    let broken = sqlx::query("PRAGMA foreign_key_check")
+        .fetch_all(&mut conn)
+        .await?;
+    if !broken.is_empty() {
+        return Err(sqlx::Error::Protocol(format!("{} row(s) break foreign keys after migrating", broken.len())));
+    }
+    conn.close().await?;
+
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
         .connect_with(conc_options)
         .await?;
-
-    sqlx::migrate!("./migrations")
-        .run(&pool)
-        .await?;
     
     info!("Database initialized successfully! (WAL mode, Foreign keys enabled)");
 
@@ -45,3 +61,25 @@ pub async fn admin_exists(pool: &Pool<Sqlite>) -> Result<bool, sqlx::Error> {
     
     Ok(count.0 != 0)
 }
+
+/// Value of a row in the `settings` table
+pub async fn get_setting(pool: &Pool<Sqlite>, key: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT value FROM settings WHERE key = ?")
+        .bind(key)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn set_setting(pool: &Pool<Sqlite>, key: &str, value: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO settings (key, value) VALUES (?, ?)
+        ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = CURRENT_TIMESTAMP
+        "#
+    )
+    .bind(key)
+    .bind(value)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -1,22 +1,27 @@
 use axum::{
     Json,
     extract::{State, Query},
-    response::{IntoResponse},
+    response::{IntoResponse, Response},
 };
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
+use tokio::sync::Notify;
+use std::sync::{Arc, Mutex};
 use std::path::Path;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use tokio::sync::broadcast;
-use serde_json::{Value, json};
+use serde_json::{Map, Value, json};
 use tracing::{info, error, warn};
 use url::Url;
 use serde::{Serialize, Deserialize};
 use tracing::debug;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use crate::{
     AppState,
-    auth::types::AuthenticatedUser,
+    registry::Download,
+    middleware::require_permission,
+    audit::{Audit, AuditAction, AuditOutcome},
+    auth::types::{AuthenticatedUser, Permission, SessionMeta},
     aria2::types::Aria2JsonRpcResp
 };
 
@@ -24,6 +29,80 @@ pub struct History;
 pub struct Extraction;
 pub struct HistoryService;
 
+/// Poll this fast while something is downloading
+const FAST_TICK: Duration = Duration::from_millis(500);
+/// Backs off up to this when nothing is
+const SLOW_TICK: Duration = Duration::from_secs(5);
+/// Users with nothing active still get a tick this often, so their speed drops to zero
+const IDLE_TICK: Duration = Duration::from_secs(5);
+/// File paths are fetched again this often, they barely change
+const FILES_EVERY: Duration = Duration::from_secs(10);
+/// Sockets get whole tasks this often, deltas in between
+const FULL_TICK_EVERY: Duration = Duration::from_secs(30);
+
+/// Enough for the progress bars, `files` and `bittorrent` are the heavy ones
+const PROGRESS_KEYS: &[&str] = &[
+    "gid", "status", "totalLength", "completedLength", "uploadLength",
+    "downloadSpeed", "uploadSpeed", "connections", "numSeeders", "seeder",
+    "errorCode", "errorMessage", "followedBy",
+];
+/// Only fetched every `FILES_EVERY`, never sent on ticks
+const FILES_KEYS: &[&str] = &["files"];
+
+/// Who has a history socket open, the poller only works for them
+#[derive(Debug, Default)]
+pub struct Watchers {
+    users: Mutex<HashMap<i64, Watcher>>,
+    /// Wakes the poller, first socket or a new download
+    wake: Notify,
+}
+
+#[derive(Debug)]
+struct Watcher {
+    sockets: usize,
+    /// Gets the instance wide stats
+    admin: bool,
+}
+
+/// Drop it when the socket closes
+pub struct WatchGuard {
+    watchers: Arc<Watchers>,
+    user_id: i64,
+}
+
+impl Watchers {
+    pub fn watch(self: &Arc<Self>, user: &AuthenticatedUser) -> WatchGuard {
+        self.users.lock().unwrap()
+            .entry(user.id)
+            .or_insert(Watcher { sockets: 0, admin: user.can(Permission::ManageServer) })
+            .sockets += 1;
+        self.wake.notify_one();
+        WatchGuard { watchers: self.clone(), user_id: user.id }
+    }
+
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Watching users and if any of them is an admin
+    fn snapshot(&self) -> (Vec<i64>, bool) {
+        let users = self.users.lock().unwrap();
+        (users.keys().copied().collect(), users.values().any(|w| w.admin))
+    }
+}
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        let mut users = self.watchers.users.lock().unwrap();
+        if let Some(w) = users.get_mut(&self.user_id) {
+            w.sockets -= 1;
+            if w.sockets == 0 {
+                users.remove(&self.user_id);
+            }
+        }
+    }
+}
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BitTorrentInfo {
@@ -94,8 +173,21 @@ pub enum GidStatus {
     Complete,
 }
 
+impl GidStatus {
+    /// Status an aria2 notification implies, stop can be `Removed` or `Stopped` so `None`
+    pub fn from_notification(method: &str) -> Option<Self> {
+        match method {
+            "aria2.onDownloadStart" => Some(GidStatus::Active),
+            "aria2.onDownloadPause" => Some(GidStatus::Paused),
+            "aria2.onDownloadComplete" | "aria2.onBtDownloadComplete" => Some(GidStatus::Complete),
+            "aria2.onDownloadError" => Some(GidStatus::Error),
+            _ => None,
+        }
+    }
+}
+
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct GlobalStat {
     #[serde(rename = "downloadSpeed")]
     pub download_speed: String,
@@ -111,6 +203,35 @@ pub struct GlobalStat {
     pub num_stopped_total: String,
 }
 
+/// How many downloads of a user are in each of aria2's lists
+#[derive(Debug)]
+pub struct UserCounts {
+    pub user_id: i64,
+    pub active: i64,
+    pub waiting: i64,
+    pub stopped: i64,
+}
+
+impl GlobalStat {
+    /// Same numbers as `getGlobalStat` but only over one user's downloads
+    fn of_user(tasks: &[Value], counts: &UserCounts) -> Self {
+        let sum = |key: &str| {
+            tasks.iter()
+                .filter_map(|t| t.get(key)?.as_str()?.parse::<u64>().ok())
+                .sum::<u64>()
+                .to_string()
+        };
+        GlobalStat {
+            download_speed: sum("downloadSpeed"),
+            upload_speed: sum("uploadSpeed"),
+            num_active: counts.active.to_string(),
+            num_waiting: counts.waiting.to_string(),
+            num_stopped: counts.stopped.to_string(),
+            num_stopped_total: counts.stopped.to_string(),
+        }
+    }
+}
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -143,6 +264,9 @@ pub struct Aria2Res {
     pub num_seeders: Option<String>,
     /// aria2 res is string: 'true' | 'false'
     pub seeder: Option<String>,
+    /// Magnet metadata gid points to the actual download gid
+    #[serde(rename = "followedBy")]
+    pub followed_by: Option<Vec<String>>,
 }
 
 /// These structs are defined based on the `1DM Manager android`
@@ -193,17 +317,359 @@ pub enum DdlWsMessage {
     Tick {
         #[serde(skip)]
         user_id: i64,
+        /// Only the user's own downloads
         global: GlobalStat,
-        tasks: Vec<Aria2Res>,
+        /// Whole aria2 instance, only admins get it
+        #[serde(skip)]
+        instance: GlobalStat,
+        /// `tellStatus` with only the `PROGRESS_KEYS`
+        tasks: Vec<Value>,
     },
     #[serde(rename = "event")]
     Event {
+        /// From `EventLog`, grows by one per event
+        seq: u64,
         #[serde(skip)]
         user_id: i64,
         data: ItemMetaData,
     }
 }
 
+/// Latest progress of a gid, waiting to be saved
+#[derive(Debug, Default)]
+pub struct Progress {
+    completed_length: Option<String>,
+    total_length: Option<String>,
+    uploaded_length: Option<String>,
+    /// Json array of the paths, only when they were fetched
+    files: Option<String>,
+}
+
+impl Progress {
+    fn from_status(obj: &Map<String, Value>) -> Self {
+        let text = |key: &str| obj.get(key).and_then(Value::as_str).map(str::to_string);
+        let files = obj.get("files")
+            .and_then(|f| serde_json::from_value::<Vec<Aria2File>>(f.clone()).ok())
+            .map(|files| {
+                let paths: Vec<String> = files.into_iter()
+                    .map(|f| f.path)
+                    .filter(|p| !p.is_empty())
+                    .collect();
+                serde_json::to_string(&paths).unwrap_or_default()
+            });
+        Progress {
+            completed_length: text("completedLength"),
+            total_length: text("totalLength"),
+            uploaded_length: text("uploadLength"),
+            files,
+        }
+    }
+
+    /// `newer` wins, except for what it didn't fetch
+    fn merge(&mut self, newer: Progress) {
+        self.completed_length = newer.completed_length.or(self.completed_length.take());
+        self.total_length = newer.total_length.or(self.total_length.take());
+        self.uploaded_length = newer.uploaded_length.or(self.uploaded_length.take());
+        self.files = newer.files.or(self.files.take());
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WriterStats {
+    pub flushes: u64,
+    pub failed_flushes: u64,
+    pub rows_written: u64,
+    pub last_flush_rows: usize,
+    pub last_flush_ms: u128,
+    pub max_flush_ms: u128,
+    /// Gids waiting right now
+    pub backlog: usize,
+    pub max_backlog: usize,
+}
+
+/*
+  * Ticks only queue progress here, one gid is one row however often it ticks
+  * A single transaction saves all of it every `interval`
+*/
+#[derive(Debug)]
+pub struct ProgressWriter {
+    pending: Mutex<HashMap<String, Progress>>,
+    stats: Mutex<WriterStats>,
+    interval: Duration,
+}
+
+impl ProgressWriter {
+    pub fn new(interval: Duration) -> Self {
+        ProgressWriter {
+            pending: Mutex::new(HashMap::new()),
+            stats: Mutex::new(WriterStats::default()),
+            interval,
+        }
+    }
+
+    pub fn queue(&self, gid: &str, progress: Progress) {
+        let backlog = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get_mut(gid) {
+                Some(old) => old.merge(progress),
+                None => {
+                    pending.insert(gid.to_string(), progress);
+                }
+            }
+            pending.len()
+        };
+        let mut stats = self.stats.lock().unwrap();
+        stats.max_backlog = stats.max_backlog.max(backlog);
+    }
+
+    /// Drops what's queued for `gid`, a full write or a delete beat it
+    pub fn forget(&self, gid: &str) {
+        self.pending.lock().unwrap().remove(gid);
+    }
+
+    pub fn stats(&self) -> WriterStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.backlog = self.pending.lock().unwrap().len();
+        stats
+    }
+
+    pub async fn run(self: Arc<Self>, pool: sqlx::SqlitePool) {
+        let mut interval = time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            self.flush(&pool).await;
+        }
+    }
+
+    /// Also once more on shutdown, nothing queued is lost
+    pub async fn flush(&self, pool: &sqlx::SqlitePool) {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.is_empty() {
+            return;
+        }
+        let started = Instant::now();
+
+        let res: Result<(), sqlx::Error> = async {
+            let mut tx = pool.begin().await?;
+            for (gid, p) in &batch {
+                sqlx::query(
+                    r#"
+                    UPDATE download_history SET
+                        completed_length = COALESCE(?, completed_length),
+                        total_length = COALESCE(?, total_length),
+                        uploaded_length = COALESCE(?, uploaded_length),
+                        files = COALESCE(?, files)
+                    WHERE gid = ? AND status = 'active'
+                    "#
+                )
+                .bind(&p.completed_length)
+                .bind(&p.total_length)
+                .bind(&p.uploaded_length)
+                .bind(&p.files)
+                .bind(gid)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await
+        }.await;
+
+        let took = started.elapsed().as_millis();
+        let rows = batch.len();
+        let mut stats = self.stats.lock().unwrap();
+        match res {
+            Ok(()) => {
+                stats.flushes += 1;
+                stats.rows_written += rows as u64;
+                stats.last_flush_rows = rows;
+                stats.last_flush_ms = took;
+                stats.max_flush_ms = stats.max_flush_ms.max(took);
+                debug!("Saved progress of {} gid(s) in {}ms", rows, took);
+            }
+            Err(e) => {
+                stats.failed_flushes += 1;
+                drop(stats);
+                warn!("Failed to save progress of {} gid(s), retrying next flush: {}", rows, e);
+                // Newer progress queued meanwhile wins
+                let mut pending = self.pending.lock().unwrap();
+                for (gid, p) in batch {
+                    pending.entry(gid).or_insert(p);
+                }
+            }
+        }
+    }
+}
+
+/// What a socket got last, so ticks only carry what changed
+#[derive(Debug, Default)]
+pub struct TickDeltas {
+    tasks: HashMap<String, Map<String, Value>>,
+    stats: Value,
+    last_full: Option<Instant>,
+}
+
+impl TickDeltas {
+    /*
+      * The tick to send, `None` if nothing changed since the last one
+      * Changed tasks only carry the `gid` and the changed keys
+      * Every `FULL_TICK_EVERY` it's whole tasks again with `full: true`
+    */
+    pub fn next(&mut self, stats: Value, tasks: Vec<Value>) -> Option<Value> {
+        let full = self.last_full.is_none_or(|t| t.elapsed() >= FULL_TICK_EVERY);
+        if full {
+            self.last_full = Some(Instant::now());
+            self.tasks.clear();
+        }
+
+        let mut changed = Vec::new();
+        let mut sent = HashMap::new();
+        for task in tasks {
+            let Value::Object(task) = task else { continue };
+            let Some(gid) = task.get("gid").and_then(Value::as_str).map(str::to_string) else { continue };
+
+            match self.tasks.get(&gid) {
+                Some(prev) => {
+                    let mut diff: Map<String, Value> = task.iter()
+                        .filter(|(k, v)| prev.get(*k) != Some(v))
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect();
+                    if !diff.is_empty() {
+                        diff.insert("gid".into(), json!(gid));
+                        changed.push(Value::Object(diff));
+                    }
+                }
+                None => changed.push(Value::Object(task.clone())),
+            }
+            sent.insert(gid, task);
+        }
+        // Gone ones are forgotten, they come back whole
+        self.tasks = sent;
+
+        if !full && changed.is_empty() && self.stats == stats {
+            return None;
+        }
+        self.stats = stats.clone();
+
+        let mut tick = stats;
+        tick["type"] = json!("tick");
+        tick["full"] = json!(full);
+        tick["tasks"] = json!(changed);
+        Some(tick)
+    }
+}
+
+/// History events kept for sockets that reconnect or fall behind
+const REPLAY_EVENTS: usize = 1024;
+/// Rows in a snapshot, for sockets too far behind to replay
+const SNAPSHOT_ROWS: usize = 100;
+
+#[derive(Debug)]
+struct LogInner {
+    /// Seq of the newest event
+    last: u64,
+    /// Events up to this are gone
+    floor: u64,
+    events: VecDeque<(u64, i64, ItemMetaData)>,
+}
+
+/*
+  * Numbers history events and keeps the last `REPLAY_EVENTS` of them
+  * Seqs start at the start time in micros, so a seq from an older run is always too old
+*/
+#[derive(Debug)]
+pub struct EventLog {
+    inner: Mutex<LogInner>,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        let start = Utc::now().timestamp_micros().max(0) as u64;
+        Self {
+            inner: Mutex::new(LogInner { last: start, floor: start, events: VecDeque::new() }),
+        }
+    }
+}
+
+impl EventLog {
+    /// Numbers, keeps and sends it under one lock, so seqs go out in order
+    pub fn publish(&self, tx: &broadcast::Sender<DdlWsMessage>, user_id: i64, data: ItemMetaData) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last += 1;
+        let seq = inner.last;
+        if inner.events.len() >= REPLAY_EVENTS
+            && let Some((dropped, _, _)) = inner.events.pop_front()
+        {
+            inner.floor = dropped;
+        }
+        inner.events.push_back((seq, user_id, data.clone()));
+        let _ = tx.send(DdlWsMessage::Event { seq, user_id, data });
+    }
+
+    pub fn last(&self) -> u64 {
+        self.inner.lock().unwrap().last
+    }
+
+    /// Events of `user_id` after `since`, `None` if some were dropped already or `since` makes no sense
+    pub fn since(&self, user_id: i64, since: u64) -> Option<Vec<(u64, ItemMetaData)>> {
+        let inner = self.inner.lock().unwrap();
+        if since < inner.floor || since > inner.last {
+            return None;
+        }
+        Some(inner.events.iter()
+            .filter(|(seq, owner, _)| *seq > since && *owner == user_id)
+            .map(|(seq, _, data)| (*seq, data.clone()))
+            .collect())
+    }
+}
+
+/// Where a history socket is, so nothing is sent twice or missed
+#[derive(Debug)]
+pub struct EventCursor {
+    user_id: i64,
+    seq: u64,
+}
+
+impl EventCursor {
+    /*
+      * Subscribe to `history_tx` before this, events that race in are skipped by seq
+      * Without `since` it starts at the newest event and says so with a `sync`
+    */
+    pub fn resume(state: &AppState, user_id: i64, since: Option<u64>) -> (Self, Vec<Value>) {
+        let mut cursor = Self { user_id, seq: since.unwrap_or(0) };
+        let msgs = match since {
+            Some(_) => cursor.catch_up(state),
+            None => {
+                cursor.seq = state.history_log.last();
+                vec![json!({ "type": "sync", "seq": cursor.seq })]
+            }
+        };
+        (cursor, msgs)
+    }
+
+    /// A live event, `None` if it isn't ours or was already replayed
+    pub fn event(&mut self, seq: u64, user_id: i64, data: &ItemMetaData) -> Option<Value> {
+        if user_id != self.user_id || seq <= self.seq {
+            return None;
+        }
+        self.seq = seq;
+        Some(json!({ "type": "event", "seq": seq, "data": data }))
+    }
+
+    /// Whatever came after our seq, a snapshot of the newest rows if that's gone
+    pub fn catch_up(&mut self, state: &AppState) -> Vec<Value> {
+        if let Some(events) = state.history_log.since(self.user_id, self.seq) {
+            self.seq = events.last().map_or(self.seq, |(seq, _)| *seq);
+            return events.into_iter()
+                .map(|(seq, data)| json!({ "type": "event", "seq": seq, "data": data }))
+                .collect();
+        }
+        // Seq first, an event racing in is at worst sent twice
+        self.seq = state.history_log.last();
+        let (total, data) = state.registry.page(self.user_id, 0, SNAPSHOT_ROWS);
+        vec![json!({ "type": "snapshot", "seq": self.seq, "total": total, "data": data })]
+    }
+}
+
 impl Extraction {
     pub fn extract(json: &Value, gid: &str) -> ItemMetaData {
         let info: Aria2Res = match serde_json::from_value(json.clone()) {
@@ -357,7 +823,7 @@ impl History {
         debug!("meta from `uri_his`: {:?}", meta);
 
         // Insert initial record
-        Self::insert_initial(&state.db, user_id, &meta).await
+        Self::insert_initial(state, user_id, meta).await
     }
 
     pub async fn torrent_his(
@@ -370,15 +836,139 @@ impl History {
             .await.unwrap_or(serde_json::json!({}));
         
         let meta = Extraction::extract(&json, gid);
-        Self::insert_initial(&state.db, user_id, &meta).await
+        Self::insert_initial(state, user_id, meta).await
+    }
+
+    /// `tellStatus` of every gid, missing ones are purged from aria2
+    pub async fn live_status(state: &AppState, gids: &[String]) -> HashMap<String, Aria2Res> {
+        let mut live = HashMap::new();
+
+        for chunk in gids.chunks(100) {
+            let calls: Vec<Value> = chunk.iter().map(|gid| {
+                json!({ "methodName": "aria2.tellStatus", "params": [gid] })
+            }).collect();
+
+            let Ok(response) = state.aria2.call("system.multicall", vec![json!(calls)]).await else {
+                continue;
+            };
+
+            for (gid, result) in chunk.iter().zip(response.as_array().into_iter().flatten()) {
+                /* Success: [ {...} ], error: { "code": ... } */
+                if let Some(res) = result.as_array()
+                    .and_then(|arr| arr.first())
+                    .and_then(|obj| serde_json::from_value::<Aria2Res>(obj.clone()).ok())
+                {
+                    live.insert(gid.clone(), res);
+                }
+            }
+        }
+
+        live
+    }
+
+    /*
+      * Magnets download the metadata first, aria2 then spawns
+      * the real download gid in `followedBy`, nobody registered it yet
+      * Returns true if any new gid got a history row
+    */
+    pub async fn adopt_followed(
+        state: &AppState,
+        user_id: i64,
+        parent_gid: &str,
+        followed: &[String],
+    ) -> bool {
+        let mut adopted = false;
+
+        for gid in followed {
+            if state.registry.contains(gid) {
+                continue;
+            }
+
+            debug!("adopting followed gid {} of {}", gid, parent_gid);
+            if let Err(e) = Self::torrent_his(state, gid, user_id).await {
+                error!("Failed to create history for followed gid {}: {}", gid, e);
+                continue;
+            }
+
+            /* Keep the parent's category */
+            let _ = sqlx::query(
+                r#"
+                UPDATE download_history
+                SET category = (SELECT category FROM download_history WHERE gid = ?)
+                WHERE gid = ?
+                "#
+            )
+            .bind(parent_gid)
+            .bind(gid)
+            .execute(&state.db)
+            .await;
+            adopted = true;
+        }
+
+        adopted
+    }
+
+    /// Empty category clears it
+    pub async fn set_category(state: &AppState, user_id: i64, gid: &str, category: &str) {
+        let category = (!category.is_empty()).then_some(category);
+        let _ = sqlx::query("UPDATE download_history SET category = ? WHERE gid = ? AND user_id = ?")
+            .bind(category)
+            .bind(gid)
+            .bind(user_id)
+            .execute(&state.db)
+            .await
+            .inspect_err(|e| error!("Failed to set category of {}: {}", gid, e));
+    }
+
+    /// Drop the history row, the files on disk if asked and aria2's result
+    /// `false` when `user_id` has no such row
+    pub async fn delete(
+        state: &AppState,
+        gid: &str,
+        user_id: i64,
+        delete_file: bool,
+    ) -> bool {
+        if delete_file {
+            let record = state.registry.get(gid)
+                .filter(|d| d.meta.user_id == user_id);
+
+            if let Some(rec) = record {
+                let dir = rec.meta.dir.unwrap_or_default();
+                let name = rec.meta.name.unwrap_or_default();
+
+                if !dir.is_empty() && !name.is_empty() {
+                    let path = Path::new(&dir).join(&name);
+                    let _ = std::fs::remove_dir_all(&path); 
+                    let _ = std::fs::remove_file(&path);
+                    let _ = std::fs::remove_file(format!("{}.aria2", path.display()));
+                }
+            }
+        }
+
+        /* Ensure `user_id` matches history */
+        let deleted = sqlx::query!(
+            "DELETE FROM download_history WHERE gid = ? AND user_id = ?", 
+            gid, user_id
+        )
+        .execute(&state.db)
+        .await
+        .is_ok_and(|r| r.rows_affected() > 0);
+        if deleted {
+            state.registry.remove(gid);
+            state.progress.forget(gid);
+        }
+
+        // Free aria2 memory
+        let _ = state.aria2.call("removeDownloadResult", vec![json!(gid)]).await;
+        deleted
     }
 
     async fn insert_initial(
-        pool: &sqlx::SqlitePool,
+        state: &AppState,
         user_id: i64,
-        meta: &ItemMetaData
+        meta: ItemMetaData
     ) -> Result<(), sqlx::Error> {
-        sqlx::query!(
+        let res = sqlx::query!(
             r#"
             INSERT INTO download_history (
                 gid, user_id, name, status, dir, files, 
@@ -393,8 +983,13 @@ impl History {
             meta.total_length, meta.completed_length, meta.uploaded_length,
             meta.source_uri, meta.info_hash, meta.is_torrent, meta.error_code, meta.error_message
         )
-        .execute(pool)
+        .execute(&state.db)
         .await?;
+
+        // Already known gids keep their owner
+        if res.rows_affected() > 0 {
+            state.registry.insert(meta, user_id);
+        }
         Ok(())
     }
 }
@@ -425,6 +1020,8 @@ impl HistoryService {
                         if let Some(gid) = gid {
                             debug!("Event '{}' for 'gid' {}", method, gid);
                             Self::refresh_gid(&state_e, gid).await;
+                            // Something started, stop backing off
+                            state_e.watchers.wake();
                         } else {
                             warn!("Received '{}' event but couldn't extract the gid", method);
                         }
@@ -433,92 +1030,128 @@ impl HistoryService {
             }
         });
 
-        // i can't find any better way to avoid using polling, if you're reading this please fix it!!
-        // for active ddls
+        /*
+          * Progress of active ddls, aria2 has no event for it so it's polled
+          * Only while someone has a history socket open and only their gids
+          * 500ms while something downloads, backing off to 5s when nothing does
+        */
         let state_p = state.clone();
         tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_millis(500));
+            let mut delay = FAST_TICK;
+            let mut last_idle = Instant::now() - IDLE_TICK;
+            let mut last_files = Instant::now() - FILES_EVERY;
             loop {
-                interval.tick().await;
-                Self::tick(&state_p).await;
+                let (users, admin) = state_p.watchers.snapshot();
+                if users.is_empty() {
+                    debug!("nobody is watching, polling paused");
+                    state_p.watchers.wake.notified().await;
+                    delay = FAST_TICK;
+                    continue;
+                }
+
+                let idle_due = last_idle.elapsed() >= IDLE_TICK;
+                if idle_due {
+                    last_idle = Instant::now();
+                }
+                let with_files = last_files.elapsed() >= FILES_EVERY;
+                if with_files {
+                    last_files = Instant::now();
+                }
+
+                let busy = Self::tick(&state_p, &users, admin, idle_due, with_files).await;
+                delay = if busy { FAST_TICK } else { (delay * 2).min(SLOW_TICK) };
+
+                tokio::select! {
+                    _ = time::sleep(delay) => {}
+                    _ = state_p.watchers.wake.notified() => delay = FAST_TICK,
+                }
             }
         });
     }
 
-    async fn tick(state: &AppState) {
-        // Get globalStats
-        let global_stat = match state.aria2.call("getGlobalStat", vec![]).await {
-            Ok(json) => serde_json::from_value::<GlobalStat>(json).unwrap_or_default(),
-            Err(_) => return,
+    /// One round for the watching `users`, returns if anything is active
+    async fn tick(
+        state: &AppState,
+        users: &[i64],
+        admin: bool,
+        idle_due: bool,
+        with_files: bool,
+    ) -> bool {
+        // Get globalStats, only when an admin is there to see it
+        let instance = if admin {
+            match state.aria2.call_background("getGlobalStat", vec![]).await {
+                Ok(json) => serde_json::from_value::<GlobalStat>(json).unwrap_or_default(),
+                Err(_) => return false,
+            }
+        } else {
+            GlobalStat::default()
         };
+        // Active downloads of the watching users
+        let active_rows = state.registry.active(users);
 
-        // Get active downloads from db
-        let active_rows = sqlx::query!(
-        "SELECT gid, user_id FROM download_history WHERE status = 'active'"
-        )
-        .fetch_all(&state.db)
-        .await
-        .unwrap_or_default();
+        // bundle them group result to the `user_id`
+        let mut updates_by_user: HashMap<i64, Vec<Value>> = HashMap::new();
 
-        if active_rows.is_empty() { 
-            // Even if no downloads, send global stats
-            debug!("no active ddls, returning generic glob stats");
-            // But who to send to? skip or send a generic broadcast.
-            return; 
+        // Fetch only what's needed of the gid
+        let mut keys = PROGRESS_KEYS.to_vec();
+        if with_files {
+            keys.extend_from_slice(FILES_KEYS);
         }
-
-        // Fetch details of gid
-        let calls: Vec<serde_json::Value> = active_rows.iter().map(|row| {
-            serde_json::json!({ "methodName": "aria2.tellStatus", "params": [row.gid] })
+        let calls: Vec<serde_json::Value> = active_rows.iter().map(|(gid, _)| {
+            serde_json::json!({ "methodName": "aria2.tellStatus", "params": [gid, keys] })
         }).collect();
 
-        if let Ok(response) = state.aria2.call("system.multicall", vec![serde_json::json!(calls)]).await {
-            if let Some(results) = response.as_array() {
-                // bundle them group result to the `user_id`
-                let mut updates_by_user: HashMap<i64, Vec<Aria2Res>> = HashMap::new();
-
-                for (i, result) in results.iter().enumerate() {
-                    let user_id = active_rows[i].user_id;
-                    if let Some(status_array) = result.as_array() {
-                        if let Some(json_obj) = status_array.first() {
-                            if let Ok(res) = serde_json::from_value::<Aria2Res>(json_obj.clone()) {
-                                updates_by_user.entry(user_id).or_default().push(res.clone());
-                                let state_db = state.clone();
-                                let gid_ref = active_rows[i].gid.clone();
-                                tokio::spawn(async move {
-                                    // just upating the progress
-                                    Self::update_progress(&state_db, &gid_ref, &res).await;
-                                });
-                            }
+        if !calls.is_empty()
+            && let Ok(response) = state.aria2.call_background("system.multicall", vec![serde_json::json!(calls)]).await
+            && let Some(results) = response.as_array() {
+            for (i, result) in results.iter().enumerate() {
+                let (gid, user_id) = &active_rows[i];
+                if let Some(status_array) = result.as_array() {
+                    if let Some(Value::Object(obj)) = status_array.first() {
+                        let mut obj = obj.clone();
+                        state.registry.modify(gid, |d| Self::apply_progress(d, &obj));
+                        // Saved later with everyone else's
+                        state.progress.queue(gid, Progress::from_status(&obj));
+                        for key in FILES_KEYS {
+                            obj.remove(*key);
                         }
+                        updates_by_user.entry(*user_id).or_default().push(Value::Object(obj));
                     }
                 }
-
-                // forward bundles
-                for (user_id, tasks) in updates_by_user {
-                    let msg = DdlWsMessage::Tick {
-                        user_id,
-                        global: global_stat.clone(),
-                        tasks,
-                    };
-                    let _ = state.history_tx.send(msg);
-                }
             }
         }
+
+        let busy = !updates_by_user.is_empty();
+        if !busy && !idle_due {
+            return false;
+        }
+
+        for counts in users.iter().map(|&u| state.registry.counts(u)) {
+            let tasks = match updates_by_user.remove(&counts.user_id) {
+                Some(tasks) => tasks,
+                None if idle_due => Vec::new(),
+                None => continue,
+            };
+            // forward bundles
+            let msg = DdlWsMessage::Tick {
+                user_id: counts.user_id,
+                global: GlobalStat::of_user(&tasks, &counts),
+                instance: instance.clone(),
+                tasks,
+            };
+            let _ = state.history_tx.send(msg);
+        }
+        busy
     }
 
-    async fn update_progress(state: &AppState, gid: &str, res: &Aria2Res) {
-        let file_paths: Vec<String> = res.files.iter()
-            .map(|f| f.path.clone())
-            .filter(|p| !p.is_empty())
-            .collect();
-        let files_json = serde_json::to_string(&file_paths).ok();
-        let _ = sqlx::query!(
-            "UPDATE download_history SET files = ?, completed_length = ?, total_length = ?, uploaded_length = ? WHERE gid = ?",
-            files_json, res.completed_length, res.total_length, res.upload_length, gid
-        )
-            .execute(&state.db)
-        .await;
+    /// Polled progress into the registry, status is left to the events
+    fn apply_progress(d: &mut Download, obj: &Map<String, Value>) {
+        let text = |key: &str| obj.get(key).and_then(Value::as_str).map(str::to_string);
+        if let Some(v) = text("completedLength") { d.meta.completed_length = Some(v); }
+        if let Some(v) = text("totalLength") { d.meta.total_length = Some(v); }
+        if let Some(v) = text("uploadLength") { d.meta.uploaded_length = Some(v); }
+        if let Some(v) = text("downloadSpeed") { d.download_speed = v; }
+        if let Some(v) = text("uploadSpeed") { d.upload_speed = v; }
     }
 
     async fn sync_init(
@@ -549,7 +1182,7 @@ impl HistoryService {
                 })
             }).collect();
 
-            match state.aria2.call("system.multicall", vec![serde_json::json!(calls)]).await {
+            match state.aria2.call_background("system.multicall", vec![serde_json::json!(calls)]).await {
                 Ok(response) => {
                     if let Some(results) = response.as_array() {
                         for (i, result) in results.iter().enumerate() {
@@ -571,6 +1204,11 @@ impl HistoryService {
                                 match error_code {
                                     Some(1) => {
                                         warn!("gid '{}' not found in aria2 (code 1). marking as error", gid);
+                                        state.registry.modify(gid, |d| {
+                                            d.meta.status = GidStatus::Error;
+                                            d.meta.error_code = Some(1);
+                                            d.meta.error_message = Some("Session lost".into());
+                                        });
                                         let _ = sqlx::query!(
                                             "UPDATE download_history SET status = 'error', error_code = 1, error_message = 'Session lost' WHERE gid = ?", 
                                             gid
@@ -605,47 +1243,43 @@ impl HistoryService {
         }
     }
 
+    /// Registry first and the event out, the row is written behind
     async fn upsert_db(state: &AppState, meta: &mut ItemMetaData) {
-        // name gets insert at the `add_uris` or `add_torrents_batch`
-        // FIX: later refactor it
-        let res = sqlx::query!(
-            r#"
-            UPDATE download_history 
-            SET 
-                name = CASE WHEN ?1 = '<Untitled>' THEN name ELSE COALESCE(?1, name) END,
-                status = ?, dir = ?, files = ?, 
-                total_length = ?, completed_length = ?, uploaded_length = ?,
-                info_hash = ?, is_torrent = ?, error_code = ?, error_message = ?,
-                completed_at = CASE WHEN ? = 'complete' THEN COALESCE(completed_at, CURRENT_TIMESTAMP) ELSE NULL END,
-                updated_at = CURRENT_TIMESTAMP
-            WHERE gid = ?
-            RETURNING user_id, created_at, completed_at
-            "#,
-            meta.name, meta.status, meta.dir, meta.files,
-            meta.total_length, meta.completed_length, meta.uploaded_length,
-            meta.info_hash, meta.is_torrent, meta.error_code, meta.error_message,
-            meta.status,
-            meta.gid
-        )
-        .fetch_optional(&state.db)
-        .await;
-        match res {
-            Ok(Some(row)) => {
-                meta.user_id = row.user_id;
-                meta.created_at = row.created_at;
-                meta.completed_at = row.completed_at;
-                // send on global channel
-                let msg = DdlWsMessage::Event {
-                    user_id: row.user_id,
-                    data: meta.clone(),
-                };
-                let _ = state.history_tx.send(msg);
-            },
-            Ok(None) => {
-                warn!("Received aria2 update for unknown gid: {:?}, download: {:?} weird error", meta.gid, meta.name);
-            },
-            Err(e) => error!("Database update failed: {}", e),
+        if !state.registry.update(meta) {
+            warn!("Received aria2 update for unknown gid: {:?}, download: {:?} weird error", meta.gid, meta.name);
+            return;
         }
+        // Queued progress is older, it would overwrite the final lengths below
+        state.progress.forget(&meta.gid);
+        // send on global channel
+        state.history_log.publish(&state.history_tx, meta.user_id, meta.clone());
+
+        let state = state.clone();
+        let meta = meta.clone();
+        tokio::spawn(async move {
+            let res = sqlx::query!(
+                r#"
+                UPDATE download_history 
+                SET 
+                    name = ?, status = ?, dir = ?, files = ?, 
+                    total_length = ?, completed_length = ?, uploaded_length = ?,
+                    info_hash = ?, is_torrent = ?, error_code = ?, error_message = ?,
+                    completed_at = ?,
+                    updated_at = CURRENT_TIMESTAMP
+                WHERE gid = ?
+                "#,
+                meta.name, meta.status, meta.dir, meta.files,
+                meta.total_length, meta.completed_length, meta.uploaded_length,
+                meta.info_hash, meta.is_torrent, meta.error_code, meta.error_message,
+                meta.completed_at,
+                meta.gid
+            )
+            .execute(&state.db)
+            .await;
+            if let Err(e) = res {
+                error!("Database update failed: {}", e);
+            }
+        });
     }
 }
 
@@ -653,56 +1287,17 @@ pub async fn get_history(
     State(state): State<AppState>,
     user: AuthenticatedUser,
     Query(params): Query<PaginationQuery>,
-) -> impl IntoResponse {
-    let page = params.page.unwrap_or(1);
-    let limit = params.limit.unwrap_or(20);
+) -> Response {
+    if let Err(e) = require_permission(&user, Permission::ViewDownloads) {
+        return e.into_response();
+    }
+    let page = params.page.unwrap_or(1).max(1);
+    let limit = params.limit.unwrap_or(20).max(1);
     let offset = (page - 1) * limit;
 
-    // 1. Get Total Count
-    let total_items = sqlx::query!(
-        "SELECT COUNT(*) as count FROM download_history WHERE user_id = ?",
-        user.id
-    )
-    .fetch_one(&state.db)
-    .await
-    .map(|row| row.count)
-    .unwrap_or(0);
-
+    let (total_items, history) = state.registry.page(user.id, offset as usize, limit as usize);
     let total_pages = (total_items as f64 / limit as f64).ceil() as u32;
 
-    let history = sqlx::query_as!(
-        ItemMetaData,
-        r#"
-        SELECT 
-            gid, 
-            name, 
-            user_id,
-            status as "status: GidStatus", 
-            total_length, 
-            completed_length, 
-            uploaded_length,
-            dir,
-            files,
-            source_uri,
-            info_hash,
-            error_code,
-            error_message,
-            is_torrent,
-            created_at as "created_at!",
-            completed_at as "completed_at"
-        FROM download_history 
-        WHERE user_id = ? 
-        ORDER BY created_at DESC 
-        LIMIT ? OFFSET ?
-        "#,
-        user.id,
-        limit,
-        offset
-    )
-    .fetch_all(&state.db)
-    .await
-    .unwrap_or_default();
-
     Json(json!({
         "data": history,
         "meta": {
@@ -711,7 +1306,7 @@ pub async fn get_history(
             "totalItems": total_items,
             "totalPages": total_pages
         }
-    }))
+    })).into_response()
 }
 
 #[derive(Deserialize)]
@@ -723,42 +1318,36 @@ pub struct DeleteHistoryRequest {
 pub async fn delete_history(
     State(state): State<AppState>,
     user: AuthenticatedUser,
+    meta: SessionMeta,
     Json(payload): Json<DeleteHistoryRequest>,
-) -> impl IntoResponse {
+) -> Response {
+    let detail = payload.delete_file.then_some("with files");
+    if let Err(e) = require_permission(&user, Permission::ManageDownloads) {
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::DeleteHistory, None, AuditOutcome::Denied, detail).await;
+        return e.into_response();
+    }
     for gid in payload.gids {
-        if payload.delete_file {
-            let record = sqlx::query!(
-                "SELECT dir, name FROM download_history WHERE gid = ? AND user_id = ?", 
-                gid, user.id
-            )
-            .fetch_optional(&state.db)
-            .await
-            .unwrap_or_default();
-            
-            if let Some(rec) = record {
-                let dir = rec.dir;
-                let name = rec.name;
-
-                if !dir.is_empty() && !name.is_empty() {
-                    let path = Path::new(&dir).join(&name);
-                    let _ = std::fs::remove_dir_all(&path); 
-                    let _ = std::fs::remove_file(&path);
-                    let _ = std::fs::remove_file(format!("{}.aria2", path.display()));
-                }
-            }
-        }
+        let outcome = if History::delete(&state, &gid, user.id, payload.delete_file).await {
+            AuditOutcome::Ok
+        } else {
+            AuditOutcome::Failed
+        };
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::DeleteHistory, Some(&gid), outcome, detail).await;
+    }
 
-        /* Ensure `user_id` matches history */
-        let _ = sqlx::query!(
-            "DELETE FROM download_history WHERE gid = ? AND user_id = ?", 
-            gid, user.id
-        )
-        .execute(&state.db)
-        .await;
+    Json(json!({ "success": true })).into_response()
+}
 
-        // Free aria2 memory
-        let _ = state.aria2.call("removeDownloadResult", vec![json!(gid)]).await;
+/// How the progress writer keeps up, admin only
+pub async fn get_writer_stats(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> Response {
+    if let Err(e) = require_permission(&user, Permission::ManageServer) {
+        return e.into_response();
     }
-
-    Json(json!({ "success": true }))
+    Json(json!({
+        "intervalMs": state.progress.interval.as_millis(),
+        "stats": state.progress.stats(),
+    })).into_response()
 }
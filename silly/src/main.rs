@@ -1,7 +1,7 @@
-use rand::Rng;
 use cli::Args;
 use clap::Parser;
 use std::sync::Arc;
+use std::net::SocketAddr;
 use tokio::sync::watch;
 use std::time::Duration;
 use tokio::sync::broadcast;
@@ -12,18 +12,28 @@ mod db;
 mod cli;
 mod web;
 mod api;
+mod ws;
+mod sse;
 mod app;
 mod auth;
 mod his;
+mod registry;
+mod audit;
 mod logs;
 mod aria2;
+mod qbit;
+mod transmission;
 mod addrs;
 mod middleware;
 
 use crate::{
     app::AppState,
     api::SysStatus,
-    his::HistoryService,
+    his::{EventLog, HistoryService, ProgressWriter, Watchers},
+    registry::DownloadRegistry,
+    audit::Audit,
+    auth::types::{AuthController, AuthError, JwtKeys, LoginThrottle, PasswordPolicy, ProxyAuth, Role, SetupToken, TrustedProxy},
+    cli::Command,
     aria2::types::Aria2Client,
     db::{init_db, admin_exists},
 };
@@ -45,18 +55,83 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let aria2_url = format!("{}:{}/jsonrpc", args.aria2_host, args.aria2_port);
 
-    // Generate a random 32-char secret if not provided
-    let jwt_secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| {
-        rand::rng()
-            .sample_iter(&rand::distr::Alphanumeric)
-            .take(32)
-            .map(char::from)
-            .collect()
-    });
+    // Persisted in data dir unless pinned, `JWT_SECRET` kept for older setups
+    let pinned_secret = args.jwt_secret.clone()
+        .or_else(|| std::env::var("JWT_SECRET").ok())
+        .filter(|s| !s.is_empty());
+    let jwt_keys = match JwtKeys::load(&args.data_dir, pinned_secret, args.rotate_jwt_key) {
+        Ok(keys) => keys,
+        Err(AuthError::KeyStoreError(e)) => {
+            error!("Failed to store jwt signing keys in {:?}", args.data_dir);
+            return Err(e.into());
+        }
+        Err(e) => return Err(format!("Failed to load jwt signing keys: {:?}", e).into()),
+    };
 
     let db_pool = init_db(&args.data_dir).await?;
 
+    let password_policy = PasswordPolicy {
+        min_length: args.password_min_length,
+        require_digit: args.password_require_digit,
+        require_symbol: args.password_require_symbol,
+        require_mixed_case: args.password_require_mixed_case,
+    };
+
+    let trusted = args.trusted_proxies.iter()
+        .filter(|p| !p.trim().is_empty())
+        .map(|p| TrustedProxy::try_from(p.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let proxy_auth = ProxyAuth {
+        trusted,
+        user_header: args.proxy_user_header.to_lowercase(),
+        groups_header: args.proxy_groups_header.to_lowercase(),
+        auto_provision: args.proxy_auto_provision,
+        role_groups: [
+            (Role::Admin, &args.proxy_admin_groups),
+            (Role::Operator, &args.proxy_operator_groups),
+            (Role::User, &args.proxy_user_groups),
+            (Role::Uploader, &args.proxy_uploader_groups),
+            (Role::Viewer, &args.proxy_viewer_groups),
+        ]
+        .into_iter()
+        .map(|(role, groups)| (role, groups.iter()
+            .map(|g| g.trim().to_string())
+            .filter(|g| !g.is_empty())
+            .collect::<Vec<_>>()))
+        .filter(|(_, groups)| !groups.is_empty())
+        .collect(),
+    };
+
+    if let Some(command) = args.command.clone() {
+        return run_command(&db_pool, &password_policy, command).await;
+    }
+
+    match AuthController::prune_sessions(&db_pool).await {
+        Ok(n) if n > 0 => info!("Pruned {} dead session(s)", n),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to prune sessions: {:?}", e),
+    }
+
+    /* Now and then once a day, the server rarely restarts */
+    let audit_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            match Audit::prune(&audit_pool).await {
+                Ok(n) if n > 0 => info!("Pruned {} old audit log row(s)", n),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to prune the audit log: {:?}", e),
+            }
+        }
+    });
+
     let admin_exists = admin_exists(&db_pool).await?;
+    let setup_token = if admin_exists {
+        SetupToken::default()
+    } else {
+        SetupToken::generate()
+    };
     
     let initial_status = SysStatus {
         version: env!("CARGO_PKG_VERSION").to_string(),
@@ -70,18 +145,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let aria2_client = Aria2Client::new(
         aria2_url.clone(),
         args.aria2_secret.clone(),
+        args.aria2_connections,
         status_tx.clone(),
         resp_tx.clone()
     );
 
+    let progress = Arc::new(ProgressWriter::new(Duration::from_secs(args.progress_flush_secs.max(1))));
+
     let state = AppState { 
         db: db_pool.clone(),
-        jwt_secret: jwt_secret,
+        jwt: Arc::new(jwt_keys),
+        password_policy,
+        login_throttle: Arc::new(LoginThrottle::new(
+            args.login_max_attempts,
+            Duration::from_secs(args.login_lockout_secs),
+        )),
+        proxy_auth: Arc::new(proxy_auth),
+        setup_token: Arc::new(setup_token),
         status_tx: status_tx,
         aria2: Arc::new(aria2_client.clone()),
         history_tx: Arc::new(history_tx_rw),
+        history_log: Arc::new(EventLog::default()),
+        watchers: Arc::new(Watchers::default()),
+        progress: progress.clone(),
+        registry: Arc::new(DownloadRegistry::default()),
     };
 
+    let known = state.registry.load(&state.db).await?;
+    info!("Loaded {} download(s) into the registry", known);
+    tokio::spawn(progress.clone().run(state.db.clone()));
     info!("Starting history service");
     
     HistoryService::init(state.clone(), resp_tx.subscribe()).await;
@@ -89,7 +181,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     if !admin_exists {
         warn!("Initializing database: No users found");
-        info!("Open the web ui and register for admin");
     } else {
         info!("Database is initialized");
     }
@@ -100,9 +191,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addrs_listens = addrs::print_listening(&args, &all_addrs)?;
 
     info!("{}", addrs_listens);
+    if let Some(token) = state.setup_token.get() {
+        warn!("Open the web ui and register for admin with the setup token: {}", token);
+    }
     info!("Silly version: '{}'", env!("CARGO_PKG_VERSION"));
     info!("Data directory: '{}'", args.data_dir.to_string_lossy());
 
+    if state.proxy_auth.enabled() {
+        warn!("Trusting the '{}' header from {}", args.proxy_user_header, args.trusted_proxies.join(", "));
+    }
+
     if args.aria2_secret.is_some() {
         info!("Aria2 secret token loaded");
     } else {
@@ -122,7 +220,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addr = format!("{}:{}", args.host, args.port);
     let listener = TcpListener::bind(&addr).await?;
     tokio::select! {
-        result = axum::serve(listener, app) => {
+        result = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()) => {
             if let Err(e) = result {
                 error!("Server error: {}", e);
             }
@@ -131,7 +229,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             info!("Ctrl+C received, shutting down...");
         }
     }
+    progress.flush(&db_pool).await;
 
     Ok(())
 }
 
+
+/// Subcommands, no server, no aria2
+async fn run_command(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    policy: &PasswordPolicy,
+    command: Command,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::ResetPassword { username, password } => {
+            /* Typed by the operator, still the same rules */
+            let (password, must_change) = match password {
+                Some(p) => {
+                    if let Err(AuthError::WeakPassword(msg)) = policy.check(&username, &p) {
+                        return Err(msg.into());
+                    }
+                    (p, false)
+                }
+                None => (AuthController::temp_password(), true),
+            };
+
+            match AuthController::force_password(pool, &username, &password, must_change).await {
+                Ok(_) => {
+                    info!("Password of '{}' reset from the command line", username);
+                    if must_change {
+                        println!("Temporary password for '{}': {}", username, password);
+                        println!("It must be changed on the next login");
+                    } else {
+                        println!("Password of '{}' updated", username);
+                    }
+                    Ok(())
+                }
+                Err(AuthError::UserNotFound) => Err(format!("No user named '{}'", username).into()),
+                Err(e) => Err(format!("Failed to reset the password: {:?}", e).into()),
+            }
+        }
+    }
+}
@@ -1,42 +1,233 @@
+use std::net::SocketAddr;
 use axum::{
-    extract::{Request, State},
-    http::StatusCode,
+    extract::{ConnectInfo, OriginalUri, Request, State},
+    http::{Extensions, HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
     Json,
 };
 use axum_extra::extract::CookieJar;
 use serde_json::json;
-use jsonwebtoken::{decode, Validation, DecodingKey};
-use crate::{AppState, auth::types::Claims};
+use tracing::debug;
+use crate::{
+    AppState,
+    auth::{
+        handler::session_cookies,
+        sessions::{ACCESS_COOKIE, REFRESH_COOKIE},
+        types::{ApiKeyScope, AuthController, AuthError, AuthenticatedUser, Claims, Permission},
+    },
+};
 
-/// Just check the cookie, gaurd ☕︎
-pub async fn auth_guard(
-    State(state): State<AppState>,
-    jar: CookieJar,
-    req: Request,
-    next: Next,
-) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
-    let tok = jar.get("auth_token")
+pub type AuthRejection = (StatusCode, Json<serde_json::Value>);
+
+/// Still reachable with a temporary password
+const PASSWORD_CHANGE_PATHS: &[&str] = &["/api/auth/password/change", "/api/auth/sessions"];
+
+/// Nested routers strip the prefix, scopes need the full path
+pub fn full_path(extensions: &Extensions, uri: &Uri) -> String {
+    extensions.get::<OriginalUri>()
+        .map(|u| u.path().to_string())
+        .unwrap_or_else(|| uri.path().to_string())
+}
+
+/// Api key from `X-Api-Key` or `Authorization: Bearer`
+fn api_key_from_headers(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(key.trim());
+    }
+    headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim())
+}
+
+/*
+  * Resolve the caller and what it may do, api key headers first, then the
+  * auth proxy header when `peer` is a trusted proxy, then the `auth_token` cookie
+*/
+pub async fn resolve(
+    state: &AppState,
+    headers: &HeaderMap,
+    jar: &CookieJar,
+    peer: Option<&str>,
+) -> Result<(AuthenticatedUser, ApiKeyScope), AuthRejection> {
+    if let Some(key) = api_key_from_headers(headers) {
+        return AuthController::verify_api_key(&state.db, key)
+            .await
+            .map_err(|_| (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "Invalid api key" }))
+            ));
+    }
+
+    if let Some(user) = proxy_user(state, headers, peer).await? {
+        return Ok((user, ApiKeyScope::Full));
+    }
+
+    let tok = jar.get(ACCESS_COOKIE)
         .map(|c| c.value())
         .ok_or((
-            StatusCode::UNAUTHORIZED, 
+            StatusCode::UNAUTHORIZED,
             Json(json!({ "error": "Missing authentication cookie" }))
         ))?;
 
-    let val = Validation::default();
-    let key = DecodingKey::from_secret(state.jwt_secret.as_bytes());
-
-    match decode::<Claims>(tok, &key, &val) {
-        Ok(_) => {
-            Ok(next.run(req).await)
-        },
-        Err(_) => {
-            Err((
-                StatusCode::UNAUTHORIZED, 
-                Json(json!({ "error": "Invalid or expired token" }))
-            ))
+    user_from_token(state, tok)
+        .await
+        .map(|user| (user, ApiKeyScope::Full))
+        .ok_or((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Invalid or expired token" }))
+        ))
+}
+
+/// Resolve the caller and check the api key scope against the route
+pub async fn authenticate(
+    state: &AppState,
+    headers: &HeaderMap,
+    jar: &CookieJar,
+    peer: Option<&str>,
+    method: &Method,
+    path: &str,
+) -> Result<AuthenticatedUser, AuthRejection> {
+    let (user, scope) = resolve(state, headers, jar, peer).await?;
+
+    /* Temporary password, nothing but changing it */
+    if user.must_change_password && !PASSWORD_CHANGE_PATHS.contains(&path) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Password change required" }))
+        ));
+    }
+
+    if !scope.allows(method, path) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Api key scope does not allow this action" }))
+        ));
+    }
+    Ok(user)
+}
+
+/// `Ok(None)` when proxy auth is off or the request didn't come through the proxy
+pub async fn proxy_user(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: Option<&str>,
+) -> Result<Option<AuthenticatedUser>, AuthRejection> {
+    let Some((username, groups)) = state.proxy_auth.identity(headers, peer) else {
+        return Ok(None);
+    };
+
+    match AuthController::proxy_user(&state.db, &state.proxy_auth, &username, groups.as_deref()).await {
+        Ok(user) => Ok(Some(user)),
+        Err(AuthError::UserNotFound) => Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": format!("No silly user named '{}'", username) }))
+        )),
+        Err(AuthError::AccountDisabled) => Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Account disabled" }))
+        )),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) }))
+        )),
+    }
+}
+
+/// 403 unless the caller's role has `perm`, every handler checks through this
+pub fn require_permission(user: &AuthenticatedUser, perm: Permission) -> Result<(), AuthRejection> {
+    if user.can(perm) {
+        return Ok(());
+    }
+    Err((
+        StatusCode::FORBIDDEN,
+        Json(json!({ "error": format!("Role '{}' can't {}", user.role, perm.as_str()) }))
+    ))
+}
+
+/// Signature and expiry only, no db
+pub fn decode_claims(state: &AppState, tok: &str) -> Option<Claims> {
+    state.jwt.verify(tok)
+}
+
+/// Decode a session jwt, `None` if invalid, expired or its session was revoked
+pub async fn user_from_token(state: &AppState, tok: &str) -> Option<AuthenticatedUser> {
+    let claims = decode_claims(state, tok)?;
+    AuthController::session_user(&state.db, &claims.sid)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Peer address of the connection
+pub fn client_ip(extensions: &Extensions) -> Option<String> {
+    extensions.get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+/*
+  * Access tokens live for minutes, when it's gone or expired swap the refresh cookie
+  * for a new pair, rewrite the request cookies so guards and extractors see the fresh
+  * token and send the new cookies back with the response
+*/
+pub async fn session_refresh(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let access_ok = jar.get(ACCESS_COOKIE)
+        .and_then(|c| decode_claims(&state, c.value()))
+        .is_some();
+    let Some(refresh) = jar.get(REFRESH_COOKIE).map(|c| c.value().to_string()) else {
+        return next.run(req).await;
+    };
+    /* Api key and proxy requests are logged in without it, leave the cookie alone */
+    let peer = client_ip(req.extensions());
+    if access_ok
+        || api_key_from_headers(req.headers()).is_some()
+        || state.proxy_auth.identity(req.headers(), peer.as_deref()).is_some() {
+        return next.run(req).await;
+    }
+
+    let tokens = match AuthController::refresh_session(&state.db, &state.jwt, &refresh).await {
+        Ok(tokens) => tokens,
+        /* Only a dead token logs out, a db hiccup shouldn't */
+        Err(AuthError::InvalidRefreshToken) => {
+            debug!("Session refresh failed: invalid refresh token");
+            return (jar.remove(REFRESH_COOKIE), next.run(req).await).into_response();
         }
+        Err(e) => {
+            debug!("Session refresh failed: {:?}", e);
+            return next.run(req).await;
+        }
+    };
+
+    let cookies: Vec<String> = jar.iter()
+        .filter(|c| c.name() != ACCESS_COOKIE)
+        .map(|c| format!("{}={}", c.name(), c.value()))
+        .chain(std::iter::once(format!("{}={}", ACCESS_COOKIE, tokens.access)))
+        .collect();
+    if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+        req.headers_mut().insert(header::COOKIE, value);
     }
+
+    (session_cookies(CookieJar::new(), &tokens), next.run(req).await).into_response()
 }
 
+/// Just check the cookie or api key, gaurd ☕︎
+pub async fn auth_guard(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AuthRejection> {
+    let path = full_path(req.extensions(), req.uri());
+    let peer = client_ip(req.extensions());
+    let user = authenticate(&state, req.headers(), &jar, peer.as_deref(), req.method(), &path).await?;
+
+    /* Handlers extracting `AuthenticatedUser` reuse it, no second argon2 round */
+    req.extensions_mut().insert(user);
+    Ok(next.run(req).await)
+}
@@ -0,0 +1,525 @@
+use axum::{
+    Form,
+    extract::{Json, Query, Request, State, Multipart},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::{
+    Cookie,
+    SameSite,
+    CookieJar
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde_json::{json, Map, Value};
+use tracing::{info, error, warn};
+
+use crate::{
+    app::AppState,
+    his::History,
+    audit::{Audit, AuditAction, AuditOutcome},
+    middleware::{authenticate, client_ip, decode_claims, full_path, user_from_token},
+    auth::{
+        sessions::QBIT_SID_DURATION,
+        types::{AuthController, AuthError, AuthenticatedUser, SessionMeta, LoginOutcome, Permission},
+    },
+};
+use super::types::{
+    QbitController,
+    QbitCategory,
+    LoginForm,
+    HashQuery,
+    HashesForm,
+    DeleteForm,
+    CategoryForm,
+    SetCategoryForm,
+    ShareLimitsForm,
+    AddTorrentsForm,
+    TorrentsInfoQuery,
+    RemoveCategoriesForm,
+    QBIT_VERSION,
+    QBIT_WEBAPI_VERSION,
+};
+
+/// qBittorrent keeps its session in the `SID` cookie
+const QBIT_COOKIE: &str = "SID";
+
+/// `SID` cookie first, then the regular silly auth (api keys, `auth_token`)
+pub async fn qbit_guard(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let sid_user = match jar.get(QBIT_COOKIE) {
+        Some(c) => user_from_token(&state, c.value()).await,
+        None => None,
+    };
+    let user = match sid_user {
+        Some(user) if user.must_change_password => return Err((StatusCode::FORBIDDEN, "Forbidden")),
+        Some(user) => user,
+        None => {
+            let path = full_path(req.extensions(), req.uri());
+            let peer = client_ip(req.extensions());
+            authenticate(&state, req.headers(), &jar, peer.as_deref(), req.method(), &path)
+                .await
+                .map_err(|_| (StatusCode::FORBIDDEN, "Forbidden"))?
+        }
+    };
+
+    if let Some(perm) = route_permission(req.method(), &full_path(req.extensions(), req.uri()))
+        && !user.can(perm) {
+        return Err((StatusCode::FORBIDDEN, "Forbidden"));
+    }
+
+    req.extensions_mut().insert(user);
+    Ok(next.run(req).await)
+}
+
+/// Role permission behind a qbit route, reads are GETs
+fn route_permission(method: &Method, path: &str) -> Option<Permission> {
+    if path.ends_with("/auth/logout") {
+        None
+    } else if *method == Method::GET {
+        Some(Permission::ViewDownloads)
+    } else if path.ends_with("/torrents/add") {
+        Some(Permission::AddDownloads)
+    } else {
+        Some(Permission::ManageDownloads)
+    }
+}
+
+/*
+  * qbit answers `Ok.`/`Fails.` with 200 either way
+  * Clients never refresh, `SID` is short and dies with its session, a 403 makes them log in again
+*/
+pub async fn login(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    meta: SessionMeta,
+    Form(payload): Form<LoginForm>,
+) -> impl IntoResponse {
+    match AuthController::login(
+        &state.db,
+        &state.login_throttle,
+        &payload.username,
+        &payload.password,
+        &state.jwt,
+        &meta,
+        QBIT_SID_DURATION,
+    ).await {
+        Ok(LoginOutcome::Session(user, _)) if user.must_change_password => {
+            warn!("qbit login refused for '{}', password change pending", payload.username);
+            (StatusCode::OK, jar, "Fails.")
+        },
+        Err(AuthError::TooManyAttempts(_)) => (
+            StatusCode::FORBIDDEN,
+            jar,
+            "User's IP is banned for too many failed login attempts",
+        ),
+        /* No second step in the qbit api */
+        Ok(LoginOutcome::TotpRequired(_)) => {
+            warn!("qbit login refused for '{}', two factor auth is on", payload.username);
+            (StatusCode::OK, jar, "Fails.")
+        },
+        Ok(LoginOutcome::Session(_, tokens)) => {
+            let cookie = Cookie::build((QBIT_COOKIE, tokens.access))
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .max_age(time::Duration::seconds(QBIT_SID_DURATION as i64))
+                .build();
+            (StatusCode::OK, jar.add(cookie), "Ok.")
+        },
+        Err(_) => {
+            warn!("qbit login failed for '{}'", payload.username);
+            (StatusCode::OK, jar, "Fails.")
+        },
+    }
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    let sid = jar.get(QBIT_COOKIE)
+        .and_then(|c| decode_claims(&state, c.value()))
+        .map(|claims| claims.sid);
+    if let Some(sid) = sid {
+        let _ = AuthController::revoke_session(&state.db, user.id, &sid).await;
+    }
+    (StatusCode::OK, jar.remove(Cookie::from(QBIT_COOKIE)), "Ok.")
+}
+
+pub async fn app_version() -> &'static str {
+    QBIT_VERSION
+}
+
+pub async fn webapi_version() -> &'static str {
+    QBIT_WEBAPI_VERSION
+}
+
+/// Only the fields *arr tools read, `save_path` is aria2's global `dir`
+pub async fn preferences(State(state): State<AppState>) -> impl IntoResponse {
+    let save_path = state.aria2.call("getGlobalOption", vec![])
+        .await
+        .ok()
+        .and_then(|opts| opts.get("dir").and_then(|d| d.as_str()).map(|d| d.to_string()))
+        .unwrap_or_default();
+
+    Json(json!({
+        "save_path": save_path,
+        "temp_path_enabled": false,
+        "queueing_enabled": true,
+        "max_ratio_enabled": false,
+        "max_ratio": -1,
+        "max_ratio_act": 0,
+        "max_seeding_time_enabled": false,
+        "max_seeding_time": -1,
+        "dht": true,
+    }))
+}
+
+pub async fn torrents_info(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Query(query): Query<TorrentsInfoQuery>,
+) -> impl IntoResponse {
+    Json(QbitController::torrents(&state, user.id, &query).await)
+}
+
+pub async fn torrents_properties(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Query(query): Query<HashQuery>,
+) -> Response {
+    let Some(t) = QbitController::find(&state, user.id, &query.hash).await else {
+        return (StatusCode::NOT_FOUND, "Torrent hash was not found").into_response();
+    };
+
+    Json(json!({
+        "save_path": t.save_path,
+        "total_size": t.total_size,
+        "total_downloaded": t.downloaded,
+        "total_uploaded": t.uploaded,
+        "share_ratio": t.ratio,
+        "dl_speed": t.dlspeed,
+        "up_speed": t.upspeed,
+        "eta": t.eta,
+        "seeds": t.num_seeds,
+        "addition_date": t.added_on,
+        "completion_date": t.completion_on,
+        "seeding_time": t.seeding_time,
+        "time_elapsed": (chrono::Utc::now().timestamp() - t.added_on).max(0),
+    })).into_response()
+}
+
+pub async fn torrents_files(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Query(query): Query<HashQuery>,
+) -> Response {
+    match QbitController::files(&state, user.id, &query.hash).await {
+        Some(files) => Json(files).into_response(),
+        None => (StatusCode::NOT_FOUND, "Torrent hash was not found").into_response(),
+    }
+}
+
+/// Multipart form with `urls` (newline separated) and/or `torrents` files
+pub async fn torrents_add(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    let mut form = AddTorrentsForm::default();
+
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "torrents" => match field.bytes().await {
+                Ok(bytes) => form.torrents.push(STANDARD.encode(&bytes)),
+                Err(e) => error!("qbit failed to read torrent file: {}", e),
+            },
+            _ => {
+                let Ok(text) = field.text().await else { continue };
+                match name.as_str() {
+                    "urls" => form.urls.extend(
+                        text.lines()
+                            .map(|l| l.trim().to_string())
+                            .filter(|l| !l.is_empty())
+                    ),
+                    "savepath" if !text.trim().is_empty() => form.savepath = Some(text),
+                    "category" if !text.trim().is_empty() => form.category = Some(text),
+                    /* qbit v5 renamed `paused` to `stopped` */
+                    "paused" | "stopped" => form.paused = text == "true",
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    if form.urls.is_empty() && form.torrents.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Fails.");
+    }
+
+    let mut options = Map::new();
+    let dir = match (&form.savepath, &form.category) {
+        (Some(path), _) => Some(path.clone()),
+        (None, Some(category)) => QbitController::category_path(&state, user.id, category).await,
+        _ => None,
+    };
+    if let Some(dir) = dir {
+        options.insert("dir".into(), json!(dir));
+    }
+    if form.paused {
+        options.insert("pause".into(), json!("true"));
+    }
+    let options = Value::Object(options);
+
+    let mut added = 0;
+    for uri in &form.urls {
+        match state.aria2.call("addUri", vec![json!([uri]), options.clone()]).await {
+            Ok(gid) => {
+                let Some(gid) = gid.as_str() else { continue };
+                if let Err(e) = History::uri_his(&state, gid, user.id).await {
+                    error!("Failed to create history for user {}, role {}: {}", user.username, user.role, e);
+                }
+                if let Some(category) = &form.category {
+                    History::set_category(&state, user.id, gid, category).await;
+                }
+                added += 1;
+            },
+            Err(e) => error!("qbit failed to add uri: {}", e),
+        }
+    }
+
+    for torrent in &form.torrents {
+        match state.aria2.call("addTorrent", vec![json!(torrent), json!([]), options.clone()]).await {
+            Ok(gid) => {
+                let Some(gid) = gid.as_str() else { continue };
+                if let Err(e) = History::torrent_his(&state, gid, user.id).await {
+                    error!("Failed to create history for user {}, role {}: {}", user.username, user.role, e);
+                }
+                if let Some(category) = &form.category {
+                    History::set_category(&state, user.id, gid, category).await;
+                }
+                added += 1;
+            },
+            Err(e) => error!("qbit failed to add torrent: {}", e),
+        }
+    }
+
+    info!("qbit added {} downloads for user {}", added, user.username);
+    if added == 0 {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Fails.")
+    } else {
+        (StatusCode::OK, "Ok.")
+    }
+}
+
+pub async fn torrents_delete(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    Form(payload): Form<DeleteForm>,
+) -> impl IntoResponse {
+    let detail = payload.delete_files.then_some("with files");
+    for gid in QbitController::gids(&state, user.id, &payload.hashes).await {
+        /* Stop aria2 from writing before the files go away */
+        let _ = state.aria2.call("forceRemove", vec![json!(gid)]).await;
+        let outcome = if History::delete(&state, &gid, user.id, payload.delete_files).await {
+            AuditOutcome::Ok
+        } else {
+            AuditOutcome::Failed
+        };
+        Audit::record(&state.db, Some(&user), &meta, AuditAction::DeleteHistory, Some(&gid), outcome, detail).await;
+    }
+    StatusCode::OK
+}
+
+pub async fn torrents_pause(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<HashesForm>,
+) -> impl IntoResponse {
+    for gid in QbitController::gids(&state, user.id, &payload.hashes).await {
+        let _ = state.aria2.call("pause", vec![json!(gid)]).await;
+    }
+    StatusCode::OK
+}
+
+pub async fn torrents_resume(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<HashesForm>,
+) -> impl IntoResponse {
+    for gid in QbitController::gids(&state, user.id, &payload.hashes).await {
+        let _ = state.aria2.call("unpause", vec![json!(gid)]).await;
+    }
+    StatusCode::OK
+}
+
+pub async fn torrents_top_prio(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<HashesForm>,
+) -> impl IntoResponse {
+    for gid in QbitController::gids(&state, user.id, &payload.hashes).await {
+        let _ = state.aria2.call("changePosition", vec![json!(gid), json!(0), json!("POS_SET")]).await;
+    }
+    StatusCode::OK
+}
+
+pub async fn torrents_bottom_prio(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<HashesForm>,
+) -> impl IntoResponse {
+    for gid in QbitController::gids(&state, user.id, &payload.hashes).await {
+        let _ = state.aria2.call("changePosition", vec![json!(gid), json!(0), json!("POS_END")]).await;
+    }
+    StatusCode::OK
+}
+
+/// Maps onto aria2's `seed-ratio` and `seed-time`, -2 keeps aria2's global option
+pub async fn torrents_set_share_limits(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<ShareLimitsForm>,
+) -> impl IntoResponse {
+    let mut options = Map::new();
+    if payload.ratio_limit == -1.0 {
+        /* 0.0 means seed regardless of the ratio */
+        options.insert("seed-ratio".into(), json!("0.0"));
+    } else if payload.ratio_limit >= 0.0 {
+        options.insert("seed-ratio".into(), json!(payload.ratio_limit.to_string()));
+    }
+    if payload.seeding_time_limit >= 0 {
+        options.insert("seed-time".into(), json!(payload.seeding_time_limit.to_string()));
+    }
+
+    if !options.is_empty() {
+        for gid in QbitController::gids(&state, user.id, &payload.hashes).await {
+            if let Err(e) = state.aria2.call("changeOption", vec![json!(gid), json!(options)]).await {
+                warn!("qbit failed to set share limits of {}: {}", gid, e);
+            }
+        }
+    }
+    StatusCode::OK
+}
+
+pub async fn categories(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> impl IntoResponse {
+    let categories = sqlx::query_as::<_, QbitCategory>(
+        "SELECT name, save_path FROM categories WHERE user_id = ? ORDER BY name"
+    )
+    .bind(user.id)
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+
+    /* qbit returns an object keyed by name */
+    let map: Map<String, Value> = categories.into_iter()
+        .map(|c| (c.name.clone(), json!(c)))
+        .collect();
+    Json(Value::Object(map))
+}
+
+pub async fn create_category(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<CategoryForm>,
+) -> impl IntoResponse {
+    if payload.category.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Invalid category name");
+    }
+
+    let res = sqlx::query(
+        "INSERT INTO categories (user_id, name, save_path) VALUES (?, ?, ?)"
+    )
+    .bind(user.id)
+    .bind(payload.category.trim())
+    .bind(payload.save_path.trim())
+    .execute(&state.db)
+    .await;
+
+    match res {
+        Ok(_) => (StatusCode::OK, ""),
+        Err(sqlx::Error::Database(db_err)) if db_err.message().contains("UNIQUE") => {
+            (StatusCode::CONFLICT, "Category already exists")
+        },
+        Err(e) => {
+            error!("qbit failed to create category: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "")
+        },
+    }
+}
+
+pub async fn edit_category(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<CategoryForm>,
+) -> impl IntoResponse {
+    let res = sqlx::query("UPDATE categories SET save_path = ? WHERE user_id = ? AND name = ?")
+        .bind(payload.save_path.trim())
+        .bind(user.id)
+        .bind(payload.category.trim())
+        .execute(&state.db)
+        .await;
+
+    match res {
+        Ok(r) if r.rows_affected() > 0 => StatusCode::OK,
+        Ok(_) => StatusCode::CONFLICT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn remove_categories(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<RemoveCategoriesForm>,
+) -> impl IntoResponse {
+    for name in payload.categories.lines().map(str::trim).filter(|n| !n.is_empty()) {
+        let _ = sqlx::query("DELETE FROM categories WHERE user_id = ? AND name = ?")
+            .bind(user.id)
+            .bind(name)
+            .execute(&state.db)
+            .await;
+        let _ = sqlx::query("UPDATE download_history SET category = NULL WHERE user_id = ? AND category = ?")
+            .bind(user.id)
+            .bind(name)
+            .execute(&state.db)
+            .await;
+    }
+    StatusCode::OK
+}
+
+pub async fn set_category(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Form(payload): Form<SetCategoryForm>,
+) -> impl IntoResponse {
+    let category = payload.category.trim();
+    if !category.is_empty() {
+        let exists: Option<i64> = sqlx::query_scalar(
+            "SELECT id FROM categories WHERE user_id = ? AND name = ?"
+        )
+        .bind(user.id)
+        .bind(category)
+        .fetch_optional(&state.db)
+        .await
+        .unwrap_or_default();
+
+        if exists.is_none() {
+            return StatusCode::CONFLICT;
+        }
+    }
+
+    for gid in QbitController::gids(&state, user.id, &payload.hashes).await {
+        History::set_category(&state, user.id, &gid, category).await;
+    }
+    StatusCode::OK
+}
@@ -0,0 +1,240 @@
+use std::path::Path;
+use tracing::error;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    app::AppState,
+    his::{Aria2Res, History},
+};
+use super::types::{
+    QbitController,
+    QbitHistoryRow,
+    QbitTorrent,
+    QbitFile,
+    TorrentsInfoQuery,
+    QBIT_ETA_INF,
+};
+
+impl QbitController {
+    /// `None` means every torrent, qbit sends `all`
+    pub fn split_hashes(hashes: &str) -> Option<Vec<String>> {
+        if hashes.trim() == "all" {
+            return None;
+        }
+        Some(hashes.split('|')
+            .map(|h| h.trim().to_lowercase())
+            .filter(|h| !h.is_empty())
+            .collect())
+    }
+
+    fn num(value: Option<&str>) -> i64 {
+        value.and_then(|v| v.parse().ok()).unwrap_or(0)
+    }
+
+    /// Only torrents, *arr tools don't care about plain ddls
+    async fn rows(
+        state: &AppState,
+        user_id: i64,
+        category: Option<&str>,
+    ) -> Vec<QbitHistoryRow> {
+        sqlx::query_as::<_, QbitHistoryRow>(
+            r#"
+            SELECT
+                gid, name, status, dir,
+                total_length, completed_length, uploaded_length,
+                info_hash, category, created_at, completed_at
+            FROM download_history
+            WHERE user_id = ?1
+                AND info_hash IS NOT NULL
+                AND (?2 IS NULL OR COALESCE(category, '') = ?2)
+            ORDER BY created_at
+            "#
+        )
+        .bind(user_id)
+        .bind(category)
+        .fetch_all(&state.db)
+        .await
+        .inspect_err(|e| error!("Failed to fetch qbit torrents: {}", e))
+        .unwrap_or_default()
+    }
+
+    /// Register followers of magnet metadata gids
+    async fn adopt_followed(
+        state: &AppState,
+        user_id: i64,
+        rows: &[QbitHistoryRow],
+        live: &HashMap<String, Aria2Res>,
+    ) -> bool {
+        let mut adopted = false;
+        for row in rows {
+            if let Some(followed) = live.get(&row.gid).and_then(|l| l.followed_by.as_ref()) {
+                adopted |= History::adopt_followed(state, user_id, &row.gid, followed).await;
+            }
+        }
+        adopted
+    }
+
+    /// Torrents of the user in qbit's shape
+    pub async fn torrents(
+        state: &AppState,
+        user_id: i64,
+        query: &TorrentsInfoQuery,
+    ) -> Vec<QbitTorrent> {
+        let category = query.category.as_deref();
+        let mut rows = Self::rows(state, user_id, category).await;
+        let mut live = History::live_status(
+            state,
+            &rows.iter().map(|r| r.gid.clone()).collect::<Vec<_>>()
+        ).await;
+
+        if Self::adopt_followed(state, user_id, &rows, &live).await {
+            rows = Self::rows(state, user_id, category).await;
+            live = History::live_status(
+                state,
+                &rows.iter().map(|r| r.gid.clone()).collect::<Vec<_>>()
+            ).await;
+        }
+
+        let hashes = query.hashes.as_deref().and_then(Self::split_hashes);
+        let listed: HashSet<&str> = rows.iter().map(|r| r.gid.as_str()).collect();
+
+        rows.iter()
+            .filter(|row| hashes.as_ref()
+                .is_none_or(|h| h.contains(&row.info_hash.to_lowercase())))
+            /* Hide the metadata gid once its follower is listed */
+            .filter(|row| !live.get(&row.gid)
+                .and_then(|l| l.followed_by.as_ref())
+                .is_some_and(|f| f.iter().any(|g| listed.contains(g.as_str()))))
+            .map(|row| Self::to_torrent(row, live.get(&row.gid)))
+            .collect()
+    }
+
+    /// Single torrent by info hash
+    pub async fn find(state: &AppState, user_id: i64, hash: &str) -> Option<QbitTorrent> {
+        let query = TorrentsInfoQuery {
+            category: None,
+            hashes: Some(hash.to_string()),
+        };
+        Self::torrents(state, user_id, &query).await.into_iter().next()
+    }
+
+    /// Every gid behind the hashes, metadata gids included
+    pub async fn gids(state: &AppState, user_id: i64, hashes: &str) -> Vec<String> {
+        let hashes = Self::split_hashes(hashes);
+        Self::rows(state, user_id, None).await
+            .into_iter()
+            .filter(|row| hashes.as_ref()
+                .is_none_or(|h| h.contains(&row.info_hash.to_lowercase())))
+            .map(|row| row.gid)
+            .collect()
+    }
+
+    pub async fn files(state: &AppState, user_id: i64, hash: &str) -> Option<Vec<QbitFile>> {
+        let torrent = Self::find(state, user_id, hash).await?;
+        let live = History::live_status(state, std::slice::from_ref(&torrent.gid)).await;
+        let res = live.get(&torrent.gid)?;
+
+        let prefix = format!("{}/", res.dir.trim_end_matches('/'));
+        let files = res.files.iter().map(|f| {
+            let size = Self::num(Some(&f.length));
+            let completed = Self::num(Some(&f.completed_length));
+            let progress = if size > 0 { completed as f64 / size as f64 } else { 0.0 };
+            QbitFile {
+                index: Self::num(Some(&f.index)) - 1,
+                name: f.path.strip_prefix(&prefix).unwrap_or(&f.path).to_string(),
+                size,
+                progress,
+                priority: if f.selected == "true" { 1 } else { 0 },
+                is_seed: progress >= 1.0,
+            }
+        }).collect();
+
+        Some(files)
+    }
+
+    /// Save path of a category, `None` if unknown or aria2's default
+    pub async fn category_path(state: &AppState, user_id: i64, category: &str) -> Option<String> {
+        sqlx::query_scalar::<_, String>(
+            "SELECT save_path FROM categories WHERE user_id = ? AND name = ?"
+        )
+        .bind(user_id)
+        .bind(category)
+        .fetch_optional(&state.db)
+        .await
+        .ok()
+        .flatten()
+        .filter(|p| !p.is_empty())
+    }
+
+    fn to_torrent(row: &QbitHistoryRow, live: Option<&Aria2Res>) -> QbitTorrent {
+        let status = live.map(|l| l.status.as_str()).unwrap_or(&row.status);
+        let size = Self::num(live.map(|l| l.total_length.as_str()).or(row.total_length.as_deref()));
+        let downloaded = Self::num(live.map(|l| l.completed_length.as_str()).or(row.completed_length.as_deref()));
+        let uploaded = Self::num(live.map(|l| l.upload_length.as_str()).or(row.uploaded_length.as_deref()));
+        let dlspeed = Self::num(live.map(|l| l.download_speed.as_str()));
+        let upspeed = Self::num(live.map(|l| l.upload_speed.as_str()));
+        let save_path = live.map(|l| l.dir.clone()).unwrap_or_else(|| row.dir.clone());
+
+        let complete = size > 0 && downloaded >= size;
+        let seeder = live.and_then(|l| l.seeder.as_deref()) == Some("true");
+        let metadata = live.is_some_and(|l| l.followed_by.is_some()) || (size == 0 && status == "active");
+        let amount_left = (size - downloaded).max(0);
+
+        let state = match status {
+            "active" if metadata => "metaDL",
+            "active" if seeder || complete => "uploading",
+            "active" if dlspeed == 0 => "stalledDL",
+            "active" => "downloading",
+            "waiting" => "queuedDL",
+            "paused" if complete => "pausedUP",
+            "paused" => "pausedDL",
+            "complete" if !metadata => "pausedUP",
+            "error" => "error",
+            "removed" if !complete => "missingFiles",
+            _ if complete => "pausedUP",
+            _ => "pausedDL",
+        };
+
+        let eta = if dlspeed > 0 {
+            amount_left / dlspeed
+        } else if complete {
+            0
+        } else {
+            QBIT_ETA_INF
+        };
+
+        let completion_on = row.completed_at.map(|t| t.and_utc().timestamp()).unwrap_or(-1);
+        let seeding_time = if completion_on > 0 {
+            (chrono::Utc::now().timestamp() - completion_on).max(0)
+        } else {
+            0
+        };
+
+        QbitTorrent {
+            gid: row.gid.clone(),
+            hash: row.info_hash.to_lowercase(),
+            name: row.name.clone(),
+            size,
+            total_size: size,
+            progress: if size > 0 { downloaded as f64 / size as f64 } else { 0.0 },
+            dlspeed,
+            upspeed,
+            eta,
+            state: state.to_string(),
+            category: row.category.clone().unwrap_or_default(),
+            tags: String::new(),
+            content_path: Path::new(&save_path).join(&row.name).to_string_lossy().into_owned(),
+            save_path,
+            downloaded,
+            uploaded,
+            amount_left,
+            ratio: if downloaded > 0 { uploaded as f64 / downloaded as f64 } else { 0.0 },
+            num_seeds: Self::num(live.and_then(|l| l.num_seeders.as_deref())),
+            added_on: row.created_at.map(|t| t.and_utc().timestamp()).unwrap_or(0),
+            completion_on,
+            seeding_time,
+            ratio_limit: -2.0,
+            seeding_time_limit: -2,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+/// qBittorrent WebUI API emulation
+/// Ref: https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)
+pub struct QbitController;
+
+/// Pretend to be a recent 4.x, *arr tools check these
+pub const QBIT_VERSION: &str = "v4.6.7";
+pub const QBIT_WEBAPI_VERSION: &str = "2.9.3";
+
+/// qBittorrent's "infinity" for eta
+pub const QBIT_ETA_INF: i64 = 8640000;
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TorrentsInfoQuery {
+    pub category: Option<String>,
+    /// `|` separated info hashes
+    pub hashes: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HashQuery {
+    pub hash: String,
+}
+
+#[derive(Deserialize)]
+pub struct HashesForm {
+    /// `|` separated info hashes or `all`
+    pub hashes: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteForm {
+    pub hashes: String,
+    #[serde(default)]
+    pub delete_files: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CategoryForm {
+    pub category: String,
+    #[serde(default)]
+    pub save_path: String,
+}
+
+#[derive(Deserialize)]
+pub struct RemoveCategoriesForm {
+    /// newline separated category names
+    pub categories: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetCategoryForm {
+    pub hashes: String,
+    pub category: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLimitsForm {
+    pub hashes: String,
+    /// -2 global limit, -1 no limit
+    pub ratio_limit: f64,
+    /// Minutes, -2 global limit, -1 no limit
+    pub seeding_time_limit: i64,
+}
+
+/// Collected from `torrents/add` multipart form
+#[derive(Debug, Default)]
+pub struct AddTorrentsForm {
+    pub urls: Vec<String>,
+    /// Base64 encoded `.torrent` files
+    pub torrents: Vec<String>,
+    pub savepath: Option<String>,
+    pub category: Option<String>,
+    pub paused: bool,
+}
+
+#[derive(Serialize, Debug, Clone, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct QbitCategory {
+    pub name: String,
+    pub save_path: String,
+}
+
+/// Torrent rows of `download_history`
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct QbitHistoryRow {
+    pub gid: String,
+    pub name: String,
+    pub status: String,
+    pub dir: String,
+    pub total_length: Option<String>,
+    pub completed_length: Option<String>,
+    pub uploaded_length: Option<String>,
+    pub info_hash: String,
+    pub category: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+/// Subset of qBittorrent's torrent object used by Sonarr/Radarr/Lidarr
+#[derive(Serialize, Debug, Clone)]
+pub struct QbitTorrent {
+    /// Internal only, the aria2 gid backing this torrent
+    #[serde(skip)]
+    pub gid: String,
+    pub hash: String,
+    pub name: String,
+    pub size: i64,
+    pub total_size: i64,
+    pub progress: f64,
+    pub dlspeed: i64,
+    pub upspeed: i64,
+    pub eta: i64,
+    pub state: String,
+    pub category: String,
+    pub tags: String,
+    pub save_path: String,
+    pub content_path: String,
+    pub downloaded: i64,
+    pub uploaded: i64,
+    pub amount_left: i64,
+    pub ratio: f64,
+    pub num_seeds: i64,
+    pub added_on: i64,
+    pub completion_on: i64,
+    pub seeding_time: i64,
+    pub ratio_limit: f64,
+    pub seeding_time_limit: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct QbitFile {
+    pub index: i64,
+    pub name: String,
+    pub size: i64,
+    pub progress: f64,
+    pub priority: i64,
+    pub is_seed: bool,
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use chrono::Utc;
+use sqlx::SqlitePool;
+
+use crate::his::{GidStatus, ItemMetaData, UserCounts};
+
+/// A known gid, its `download_history` row plus what only polling knows
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub meta: ItemMetaData,
+    pub download_speed: String,
+    pub upload_speed: String,
+}
+
+/*
+  * Every known gid, loaded from `download_history` on start
+  * aria2 events and polling keep it current, sqlite gets written behind it
+  * Reads of downloads come here instead of aria2 or the db
+*/
+#[derive(Debug, Default)]
+pub struct DownloadRegistry {
+    downloads: RwLock<HashMap<String, Download>>,
+}
+
+impl DownloadRegistry {
+    /// Replaces everything with what the db has
+    pub async fn load(&self, pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+        let rows = sqlx::query_as::<_, ItemMetaData>(
+            r#"
+            SELECT
+                gid, name, user_id, status, total_length, completed_length, uploaded_length,
+                dir, files, source_uri, info_hash, error_code, error_message, is_torrent,
+                created_at, completed_at
+            FROM download_history
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut downloads = self.downloads.write().unwrap();
+        downloads.clear();
+        for meta in rows {
+            downloads.insert(meta.gid.clone(), Download {
+                meta,
+                download_speed: "0".into(),
+                upload_speed: "0".into(),
+            });
+        }
+        Ok(downloads.len())
+    }
+
+    pub fn get(&self, gid: &str) -> Option<Download> {
+        self.downloads.read().unwrap().get(gid).cloned()
+    }
+
+    pub fn contains(&self, gid: &str) -> bool {
+        self.downloads.read().unwrap().contains_key(gid)
+    }
+
+    pub fn owner(&self, gid: &str) -> Option<i64> {
+        self.downloads.read().unwrap().get(gid).map(|d| d.meta.user_id)
+    }
+
+    pub fn owned(&self, user_id: i64) -> Vec<String> {
+        self.downloads.read().unwrap()
+            .values()
+            .filter(|d| d.meta.user_id == user_id)
+            .map(|d| d.meta.gid.clone())
+            .collect()
+    }
+
+    /// Active gids of these users and whose they are
+    pub fn active(&self, users: &[i64]) -> Vec<(String, i64)> {
+        self.downloads.read().unwrap()
+            .values()
+            .filter(|d| d.meta.status == GidStatus::Active && users.contains(&d.meta.user_id))
+            .map(|d| (d.meta.gid.clone(), d.meta.user_id))
+            .collect()
+    }
+
+    /// Same split as aria2's lists, paused ones wait
+    pub fn counts(&self, user_id: i64) -> UserCounts {
+        let mut counts = UserCounts { user_id, active: 0, waiting: 0, stopped: 0 };
+        for d in self.downloads.read().unwrap().values().filter(|d| d.meta.user_id == user_id) {
+            match d.meta.status {
+                GidStatus::Active => counts.active += 1,
+                GidStatus::Waiting | GidStatus::Paused => counts.waiting += 1,
+                _ => counts.stopped += 1,
+            }
+        }
+        counts
+    }
+
+    /// Newest first, returns the total too
+    pub fn page(&self, user_id: i64, offset: usize, limit: usize) -> (usize, Vec<ItemMetaData>) {
+        let downloads = self.downloads.read().unwrap();
+        let mut own: Vec<&Download> = downloads.values()
+            .filter(|d| d.meta.user_id == user_id)
+            .collect();
+        own.sort_by(|a, b| b.meta.created_at.cmp(&a.meta.created_at).then_with(|| b.meta.gid.cmp(&a.meta.gid)));
+
+        let page = own.iter()
+            .skip(offset)
+            .take(limit)
+            .map(|d| d.meta.clone())
+            .collect();
+        (own.len(), page)
+    }
+
+    /// A new download, the row is already in the db
+    pub fn insert(&self, mut meta: ItemMetaData, user_id: i64) {
+        meta.user_id = user_id;
+        meta.created_at.get_or_insert_with(|| Utc::now().naive_utc());
+        self.downloads.write().unwrap().insert(meta.gid.clone(), Download {
+            meta,
+            download_speed: "0".into(),
+            upload_speed: "0".into(),
+        });
+    }
+
+    /*
+      * Fresh `tellStatus` of a known gid, `meta` gets the owner and the dates back
+      * A '<Untitled>' name never replaces a real one
+      * Returns false for a gid nobody added
+    */
+    pub fn update(&self, meta: &mut ItemMetaData) -> bool {
+        let mut downloads = self.downloads.write().unwrap();
+        let Some(known) = downloads.get_mut(&meta.gid) else {
+            return false;
+        };
+
+        if meta.name.as_deref().is_none_or(|n| n == "<Untitled>") {
+            meta.name = known.meta.name.clone();
+        }
+        meta.user_id = known.meta.user_id;
+        meta.created_at = known.meta.created_at;
+        meta.source_uri = meta.source_uri.take().or(known.meta.source_uri.take());
+        meta.completed_at = match meta.status {
+            GidStatus::Complete => known.meta.completed_at.or_else(|| Some(Utc::now().naive_utc())),
+            _ => None,
+        };
+        if meta.status != GidStatus::Active {
+            known.download_speed = "0".into();
+            known.upload_speed = "0".into();
+        }
+        known.meta = meta.clone();
+        true
+    }
+
+    /// Changes a known gid in place, false if there's none
+    pub fn modify(&self, gid: &str, f: impl FnOnce(&mut Download)) -> bool {
+        match self.downloads.write().unwrap().get_mut(gid) {
+            Some(d) => {
+                f(d);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&self, gid: &str) -> Option<Download> {
+        self.downloads.write().unwrap().remove(gid)
+    }
+
+    /// The owner is gone, returns the gids that went with them
+    pub fn remove_owned(&self, user_id: i64) -> Vec<String> {
+        let mut gone = Vec::new();
+        self.downloads.write().unwrap().retain(|gid, d| {
+            let keep = d.meta.user_id != user_id;
+            if !keep {
+                gone.push(gid.clone());
+            }
+            keep
+        });
+        gone
+    }
+
+    /// Downloads moved to another user, live status and speeds stay
+    pub fn reassign(&self, from: i64, to: i64) {
+        for d in self.downloads.write().unwrap().values_mut().filter(|d| d.meta.user_id == from) {
+            d.meta.user_id = to;
+        }
+    }
+}
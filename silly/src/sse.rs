@@ -0,0 +1,137 @@
+use std::convert::Infallible;
+use serde_json::{Value, json};
+use futures_util::{Stream, StreamExt, stream};
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Duration;
+use tracing::warn;
+
+use crate::{
+    AppState,
+    his::{DdlWsMessage, EventCursor, TickDeltas},
+    middleware::require_permission,
+    auth::types::{AuthenticatedUser, Permission},
+};
+
+/// Comment lines this often, proxies keep the stream open and closed clients get noticed
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+fn sse<S>(stream: S) -> Response
+where
+    S: Stream<Item = Result<Event, Infallible>> + Send + 'static,
+{
+    Sse::new(stream)
+        .keep_alive(KeepAlive::new().interval(KEEPALIVE).text("keepalive"))
+        .into_response()
+}
+
+/// Same as `/api/ws/silly/status`, public too, the current one first
+pub async fn status_sse(State(state): State<AppState>) -> Response {
+    let rx = state.status_tx.subscribe();
+    let stream = stream::unfold((rx, true), |(mut rx, first)| async move {
+        if !first && rx.changed().await.is_err() {
+            return None;
+        }
+        let data = serde_json::to_string(&*rx.borrow_and_update()).unwrap();
+        Some((Ok(Event::default().event("status").data(data)), (rx, false)))
+    });
+    sse(stream)
+}
+
+/// Ticks of `/api/ws/dl/history`, whole tasks first then deltas
+pub async fn ticks_sse(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> Response {
+    if let Err(e) = require_permission(&user, Permission::ViewDownloads) {
+        return e.into_response();
+    }
+    let rx = state.history_tx.subscribe();
+    // Dropped with the stream, polling stops for a closed client
+    let watch = state.watchers.watch(&user);
+    let deltas = TickDeltas::default();
+
+    let stream = stream::unfold((rx, deltas, watch, user), |(mut rx, mut deltas, watch, user)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(DdlWsMessage::Tick { user_id, global, instance, tasks }) if user_id == user.id => {
+                    let mut stats = json!({ "global": global });
+                    if user.can(Permission::ManageServer) {
+                        stats["instance"] = json!(instance);
+                    }
+                    if let Some(tick) = deltas.next(stats, tasks) {
+                        let event = Event::default().event("tick").data(tick.to_string());
+                        return Some((Ok(event), (rx, deltas, watch, user)));
+                    }
+                }
+                Ok(_) => {}
+                // Whole tasks again, nothing else to catch up on
+                Err(RecvError::Lagged(_)) => deltas = TickDeltas::default(),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    sse(stream)
+}
+
+/*
+  * History events of `/api/ws/dl/history`, the seq is the event id
+  * `EventSource` sends it back as `Last-Event-ID` on reconnect and gets what it missed,
+  * or a `snapshot` when that's gone
+*/
+pub async fn history_sse(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = require_permission(&user, Permission::ViewDownloads) {
+        return e.into_response();
+    }
+    let since = headers.get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    let rx = state.history_tx.subscribe();
+    let (cursor, missed) = EventCursor::resume(&state, user.id, since);
+
+    let live = stream::unfold((rx, cursor, state, user), |(mut rx, mut cursor, state, user)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(DdlWsMessage::Event { seq, user_id, data }) => {
+                    if let Some(msg) = cursor.event(seq, user_id, &data) {
+                        return Some((vec![msg], (rx, cursor, state, user)));
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => {
+                    warn!("history stream of '{}' lagged {} messages", user.username, n);
+                    let msgs = cursor.catch_up(&state);
+                    return Some((msgs, (rx, cursor, state, user)));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .flat_map(stream::iter);
+
+    let stream = stream::iter(missed).chain(live).map(history_event);
+    sse(stream)
+}
+
+/// `sync`, `event` or `snapshot`, same json as the websocket
+fn history_event(msg: Value) -> Result<Event, Infallible> {
+    let mut event = Event::default()
+        .event(msg["type"].as_str().unwrap_or("event"))
+        .data(msg.to_string());
+    if let Some(seq) = msg["seq"].as_u64() {
+        event = event.id(seq.to_string());
+    }
+    Ok(event)
+}
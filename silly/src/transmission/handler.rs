@@ -0,0 +1,368 @@
+use rand::Rng;
+use std::sync::LazyLock;
+use axum::{
+    body::Bytes,
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde_json::{json, Map, Value};
+use tracing::{info, error, warn};
+
+use crate::{
+    app::AppState,
+    his::History,
+    audit::{Audit, AuditAction, AuditOutcome},
+    middleware::resolve,
+    auth::{
+        api_keys::API_KEY_TAG,
+        types::{ApiKeyScope, AuthController, AuthenticatedUser, Permission, SessionMeta},
+    },
+};
+use super::types::{
+    TransmissionController,
+    RpcReq,
+    RpcResp,
+    TorrentGetArgs,
+    TorrentIdsArgs,
+    TorrentAddArgs,
+    TorrentRemoveArgs,
+    TR_VERSION,
+    TR_RPC_VERSION,
+    TR_RPC_VERSION_MINIMUM,
+    TR_SESSION_HEADER,
+    TR_STATUS_DOWNLOAD,
+    TR_STATUS_SEED,
+};
+
+/// Clients fetch it from the 409 and resend, one per process is enough
+static SESSION_ID: LazyLock<String> = LazyLock::new(|| {
+    rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect()
+});
+
+/// Basic auth (password can be an api key), then the regular silly auth
+async fn rpc_user(
+    state: &AppState,
+    headers: &HeaderMap,
+    jar: &CookieJar,
+    ip: Option<&str>,
+) -> Option<(AuthenticatedUser, ApiKeyScope)> {
+    let basic = headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v.trim()).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+
+    let Some(basic) = basic else {
+        return resolve(state, headers, jar, ip).await.ok();
+    };
+
+    let (username, password) = basic.split_once(':')?;
+    if password.starts_with(API_KEY_TAG) {
+        return AuthController::verify_api_key(&state.db, password).await.ok();
+    }
+    let user = AuthController::attempt_credentials(&state.db, &state.login_throttle, username, password, ip)
+        .await
+        .ok()
+        .filter(|user| !user.must_change_password)?;
+    /* A password alone isn't enough with two factor auth, use an api key */
+    if AuthController::has_totp(&state.db, user.id).await.unwrap_or(true) {
+        return None;
+    }
+    Some((user, ApiKeyScope::Full))
+}
+
+/// Api key scopes per rpc method
+fn allowed(scope: ApiKeyScope, method: &str) -> bool {
+    let read = matches!(method, "torrent-get" | "session-get" | "session-stats");
+    match scope {
+        ApiKeyScope::Full => true,
+        ApiKeyScope::ReadOnly => read,
+        ApiKeyScope::AddOnly => read || method == "torrent-add",
+    }
+}
+
+/// Role permission per rpc method
+fn permission(method: &str) -> Permission {
+    match method {
+        "torrent-get" | "session-get" | "session-stats" => Permission::ViewDownloads,
+        "torrent-add" => Permission::AddDownloads,
+        "session-set" => Permission::ManageServer,
+        _ => Permission::ManageDownloads,
+    }
+}
+
+/// `/transmission/rpc`
+pub async fn rpc(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    meta: SessionMeta,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some((user, scope)) = rpc_user(&state, &headers, &jar, meta.ip.as_deref()).await else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"Transmission\"")],
+            "401: Unauthorized",
+        ).into_response();
+    };
+
+    /* CSRF protection of transmission, clients expect the handshake */
+    let session_ok = headers.get(TR_SESSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v == SESSION_ID.as_str());
+    if !session_ok {
+        return (
+            StatusCode::CONFLICT,
+            [(TR_SESSION_HEADER, SESSION_ID.as_str())],
+            format!("<h1>409: Conflict</h1><p><code>{}: {}</code></p>", TR_SESSION_HEADER, SESSION_ID.as_str()),
+        ).into_response();
+    }
+
+    let req = match serde_json::from_slice::<RpcReq>(&body) {
+        Ok(req) => req,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid rpc request: {}", e)).into_response(),
+    };
+
+    let result = if !allowed(scope, &req.method) {
+        Err("api key scope does not allow this method".to_string())
+    } else if !user.can(permission(&req.method)) {
+        Err(format!("role '{}' can't {}", user.role, permission(&req.method).as_str()))
+    } else {
+        let args = Value::Object(req.arguments);
+        match req.method.as_str() {
+            "session-get" => session_get(&state).await,
+            "session-set" => session_set(&state, &user, args).await,
+            "session-stats" => session_stats(&state, &user).await,
+            "torrent-get" => torrent_get(&state, &user, args).await,
+            "torrent-add" => torrent_add(&state, &user, args).await,
+            "torrent-start" | "torrent-start-now" => torrent_action(&state, &user, args, "unpause").await,
+            "torrent-stop" => torrent_action(&state, &user, args, "pause").await,
+            "torrent-remove" => torrent_remove(&state, &user, &meta, args).await,
+            _ => Err("method name not recognized".to_string()),
+        }
+    };
+
+    let resp = match result {
+        Ok(arguments) => RpcResp {
+            result: "success".into(),
+            arguments,
+            tag: req.tag,
+        },
+        Err(e) => {
+            warn!("transmission rpc '{}' failed for {}: {}", req.method, user.username, e);
+            RpcResp {
+                result: e,
+                arguments: json!({}),
+                tag: req.tag,
+            }
+        }
+    };
+    Json(resp).into_response()
+}
+
+fn parse_args<T: serde::de::DeserializeOwned>(args: Value) -> Result<T, String> {
+    serde_json::from_value(args).map_err(|e| format!("invalid arguments: {}", e))
+}
+
+/// aria2 speeds are bytes, transmission speaks KB/s
+fn kbps(opts: &Value, key: &str) -> i64 {
+    opts.get(key)
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0) / 1024
+}
+
+async fn session_get(state: &AppState) -> Result<Value, String> {
+    let opts = state.aria2.call("getGlobalOption", vec![]).await?;
+    let opt = |key: &str| opts.get(key).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+
+    let down = kbps(&opts, "max-overall-download-limit");
+    let up = kbps(&opts, "max-overall-upload-limit");
+    let seed_ratio = opt("seed-ratio").parse::<f64>().unwrap_or(0.0);
+
+    Ok(json!({
+        "version": TR_VERSION,
+        "rpc-version": TR_RPC_VERSION,
+        "rpc-version-minimum": TR_RPC_VERSION_MINIMUM,
+        "session-id": SESSION_ID.as_str(),
+        "download-dir": opt("dir"),
+        "speed-limit-down": down,
+        "speed-limit-down-enabled": down > 0,
+        "speed-limit-up": up,
+        "speed-limit-up-enabled": up > 0,
+        "seedRatioLimit": seed_ratio,
+        "seedRatioLimited": seed_ratio > 0.0,
+        "download-queue-enabled": true,
+        "download-queue-size": opt("max-concurrent-downloads").parse::<i64>().unwrap_or(5),
+        "peer-port": opt("listen-port").parse::<i64>().unwrap_or(0),
+        "dht-enabled": opt("enable-dht") == "true",
+        "encryption": "preferred",
+    }))
+}
+
+/// Global aria2 options, affects everyone so admins only
+async fn session_set(state: &AppState, user: &AuthenticatedUser, args: Value) -> Result<Value, String> {
+    if !user.can(Permission::ManageServer) {
+        return Err("session-set requires an admin account".to_string());
+    }
+
+    let mut options = Map::new();
+    if let Some(dir) = args.get("download-dir").and_then(|v| v.as_str()) {
+        options.insert("dir".into(), json!(dir));
+    }
+    for (limit, enabled, option) in [
+        ("speed-limit-down", "speed-limit-down-enabled", "max-overall-download-limit"),
+        ("speed-limit-up", "speed-limit-up-enabled", "max-overall-upload-limit"),
+    ] {
+        if args.get(enabled).and_then(|v| v.as_bool()) == Some(false) {
+            options.insert(option.into(), json!("0"));
+        } else if let Some(kb) = args.get(limit).and_then(|v| v.as_i64()) {
+            options.insert(option.into(), json!(format!("{}K", kb)));
+        }
+    }
+    if args.get("seedRatioLimited").and_then(|v| v.as_bool()) == Some(false) {
+        options.insert("seed-ratio".into(), json!("0.0"));
+    } else if let Some(ratio) = args.get("seedRatioLimit").and_then(|v| v.as_f64()) {
+        options.insert("seed-ratio".into(), json!(ratio.to_string()));
+    }
+    if let Some(size) = args.get("download-queue-size").and_then(|v| v.as_i64()) {
+        options.insert("max-concurrent-downloads".into(), json!(size.to_string()));
+    }
+
+    if !options.is_empty() {
+        state.aria2.call("changeGlobalOption", vec![json!(options)]).await?;
+        info!("transmission session-set by {}: {:?}", user.username, options);
+    }
+    Ok(json!({}))
+}
+
+/// Stats of the caller's torrents only
+async fn session_stats(state: &AppState, user: &AuthenticatedUser) -> Result<Value, String> {
+    let torrents = TransmissionController::torrents(state, user.id, None).await;
+    let objects: Vec<Map<String, Value>> = torrents.iter()
+        .map(|(row, live)| TransmissionController::to_object(row, live.as_ref()))
+        .collect();
+
+    let sum = |key: &str| objects.iter()
+        .filter_map(|o| o.get(key).and_then(|v| v.as_i64()))
+        .sum::<i64>();
+    let active = objects.iter()
+        .filter(|o| matches!(o.get("status").and_then(|v| v.as_i64()), Some(TR_STATUS_DOWNLOAD | TR_STATUS_SEED)))
+        .count();
+
+    Ok(json!({
+        "torrentCount": objects.len(),
+        "activeTorrentCount": active,
+        "pausedTorrentCount": objects.len() - active,
+        "downloadSpeed": sum("rateDownload"),
+        "uploadSpeed": sum("rateUpload"),
+    }))
+}
+
+async fn torrent_get(state: &AppState, user: &AuthenticatedUser, args: Value) -> Result<Value, String> {
+    let args: TorrentGetArgs = parse_args(args)?;
+    let torrents: Vec<Map<String, Value>> = TransmissionController::torrents(state, user.id, args.ids.as_ref())
+        .await
+        .iter()
+        .map(|(row, live)| TransmissionController::pick(
+            TransmissionController::to_object(row, live.as_ref()),
+            &args.fields,
+        ))
+        .collect();
+
+    Ok(json!({ "torrents": torrents }))
+}
+
+async fn torrent_add(state: &AppState, user: &AuthenticatedUser, args: Value) -> Result<Value, String> {
+    let args: TorrentAddArgs = parse_args(args)?;
+
+    let mut options = Map::new();
+    if let Some(dir) = args.download_dir.as_deref().filter(|d| !d.is_empty()) {
+        options.insert("dir".into(), json!(dir));
+    }
+    if args.paused {
+        options.insert("pause".into(), json!("true"));
+    }
+    let options = Value::Object(options);
+
+    let gid = match (&args.metainfo, &args.filename) {
+        (Some(metainfo), _) => {
+            let gid = state.aria2.call("addTorrent", vec![json!(metainfo), json!([]), options]).await?;
+            let gid = gid.as_str().unwrap_or_default().to_string();
+            if let Err(e) = History::torrent_his(state, &gid, user.id).await {
+                error!("Failed to create history for user {}, role {}: {}", user.username, user.role, e);
+            }
+            gid
+        },
+        (None, Some(filename)) => {
+            let gid = state.aria2.call("addUri", vec![json!([filename]), options]).await?;
+            let gid = gid.as_str().unwrap_or_default().to_string();
+            if let Err(e) = History::uri_his(state, &gid, user.id).await {
+                error!("Failed to create history for user {}, role {}: {}", user.username, user.role, e);
+            }
+            gid
+        },
+        (None, None) => return Err("no filename or metainfo specified".to_string()),
+    };
+
+    if let Some(label) = args.labels.first() {
+        History::set_category(state, user.id, &gid, label).await;
+    }
+
+    let row: Option<(i64, String, Option<String>)> = sqlx::query_as(
+        "SELECT id, name, info_hash FROM download_history WHERE gid = ? AND user_id = ?"
+    )
+    .bind(&gid)
+    .bind(user.id)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let (id, name, hash) = row.ok_or("failed to register the torrent".to_string())?;
+    info!("transmission added {} for user {}", gid, user.username);
+    Ok(json!({
+        "torrent-added": {
+            "id": id,
+            "name": name,
+            "hashString": hash.unwrap_or_default().to_lowercase(),
+        }
+    }))
+}
+
+/// `pause`/`unpause` on every selected torrent
+async fn torrent_action(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    args: Value,
+    method: &str,
+) -> Result<Value, String> {
+    let args: TorrentIdsArgs = parse_args(args)?;
+    for gid in TransmissionController::gids(state, user.id, args.ids.as_ref()).await {
+        let _ = state.aria2.call(method, vec![json!(gid)]).await;
+    }
+    Ok(json!({}))
+}
+
+async fn torrent_remove(state: &AppState, user: &AuthenticatedUser, meta: &SessionMeta, args: Value) -> Result<Value, String> {
+    let args: TorrentRemoveArgs = parse_args(args)?;
+    let detail = args.delete_local_data.then_some("with files");
+    for gid in TransmissionController::gids(state, user.id, args.ids.as_ref()).await {
+        /* Stop aria2 from writing before the files go away */
+        let _ = state.aria2.call("forceRemove", vec![json!(gid)]).await;
+        let outcome = if History::delete(state, &gid, user.id, args.delete_local_data).await {
+            AuditOutcome::Ok
+        } else {
+            AuditOutcome::Failed
+        };
+        Audit::record(&state.db, Some(user), meta, AuditAction::DeleteHistory, Some(&gid), outcome, detail).await;
+    }
+    Ok(json!({}))
+}
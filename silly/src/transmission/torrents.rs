@@ -0,0 +1,230 @@
+use serde_json::{json, Map, Value};
+use tracing::error;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    app::AppState,
+    his::{Aria2Res, History},
+};
+use super::types::{
+    TransmissionController,
+    TrHistoryRow,
+    TR_STAT_OK,
+    TR_STAT_LOCAL_ERROR,
+    TR_STATUS_STOPPED,
+    TR_STATUS_DOWNLOAD_WAIT,
+    TR_STATUS_DOWNLOAD,
+    TR_STATUS_SEED,
+};
+
+impl TransmissionController {
+    fn num(value: Option<&str>) -> i64 {
+        value.and_then(|v| v.parse().ok()).unwrap_or(0)
+    }
+
+    /// Only torrents, transmission can't show plain ddls
+    async fn rows(state: &AppState, user_id: i64) -> Vec<TrHistoryRow> {
+        sqlx::query_as::<_, TrHistoryRow>(
+            r#"
+            SELECT
+                id, gid, name, status, dir,
+                total_length, completed_length, uploaded_length,
+                info_hash, category, error_message, created_at, completed_at
+            FROM download_history
+            WHERE user_id = ? AND info_hash IS NOT NULL
+            ORDER BY id
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(&state.db)
+        .await
+        .inspect_err(|e| error!("Failed to fetch transmission torrents: {}", e))
+        .unwrap_or_default()
+    }
+
+    /// Does `ids` (number, hash, list of both or "recently-active") select this row
+    fn selected(ids: Option<&Value>, row: &TrHistoryRow) -> bool {
+        let matches = |id: &Value| match id {
+            Value::Number(n) => n.as_i64() == Some(row.id),
+            Value::String(h) => h.eq_ignore_ascii_case(&row.info_hash),
+            _ => false,
+        };
+
+        match ids {
+            None => true,
+            Some(Value::String(s)) if s == "recently-active" => row.status == "active",
+            Some(Value::Array(arr)) => arr.iter().any(matches),
+            Some(id) => matches(id),
+        }
+    }
+
+    /// Rows with their aria2 status, metadata gids hidden once the follower is known
+    pub async fn torrents(
+        state: &AppState,
+        user_id: i64,
+        ids: Option<&Value>,
+    ) -> Vec<(TrHistoryRow, Option<Aria2Res>)> {
+        let mut rows = Self::rows(state, user_id).await;
+        let mut live = History::live_status(
+            state,
+            &rows.iter().map(|r| r.gid.clone()).collect::<Vec<_>>()
+        ).await;
+
+        let mut adopted = false;
+        for row in &rows {
+            if let Some(followed) = live.get(&row.gid).and_then(|l| l.followed_by.as_ref()) {
+                adopted |= History::adopt_followed(state, user_id, &row.gid, followed).await;
+            }
+        }
+        if adopted {
+            rows = Self::rows(state, user_id).await;
+            live = History::live_status(
+                state,
+                &rows.iter().map(|r| r.gid.clone()).collect::<Vec<_>>()
+            ).await;
+        }
+
+        let listed: HashSet<String> = rows.iter().map(|r| r.gid.clone()).collect();
+        rows.into_iter()
+            .filter(|row| Self::selected(ids, row))
+            .filter_map(|row| {
+                let res = live.remove(&row.gid);
+                /* Hide the metadata gid once its follower is listed */
+                let superseded = res.as_ref()
+                    .and_then(|l| l.followed_by.as_ref())
+                    .is_some_and(|f| f.iter().any(|g| listed.contains(g)));
+                (!superseded).then_some((row, res))
+            })
+            .collect()
+    }
+
+    /// Every gid behind the ids, metadata gids of the same hash included
+    pub async fn gids(state: &AppState, user_id: i64, ids: Option<&Value>) -> Vec<String> {
+        let rows = Self::rows(state, user_id).await;
+        let hashes: HashSet<String> = rows.iter()
+            .filter(|row| Self::selected(ids, row))
+            .map(|row| row.info_hash.to_lowercase())
+            .collect();
+
+        rows.into_iter()
+            .filter(|row| hashes.contains(&row.info_hash.to_lowercase()))
+            .map(|row| row.gid)
+            .collect()
+    }
+
+    /// Full transmission torrent object, `torrent-get` picks the asked fields
+    pub fn to_object(row: &TrHistoryRow, live: Option<&Aria2Res>) -> Map<String, Value> {
+        let status = live.map(|l| l.status.as_str()).unwrap_or(&row.status);
+        let size = Self::num(live.map(|l| l.total_length.as_str()).or(row.total_length.as_deref()));
+        let downloaded = Self::num(live.map(|l| l.completed_length.as_str()).or(row.completed_length.as_deref()));
+        let uploaded = Self::num(live.map(|l| l.upload_length.as_str()).or(row.uploaded_length.as_deref()));
+        let rate_download = Self::num(live.map(|l| l.download_speed.as_str()));
+        let rate_upload = Self::num(live.map(|l| l.upload_speed.as_str()));
+        let dir = live.map(|l| l.dir.clone()).unwrap_or_else(|| row.dir.clone());
+
+        let complete = size > 0 && downloaded >= size;
+        let seeder = live.and_then(|l| l.seeder.as_deref()) == Some("true");
+        let left = (size - downloaded).max(0);
+
+        let tr_status = match status {
+            "active" if seeder || complete => TR_STATUS_SEED,
+            "active" => TR_STATUS_DOWNLOAD,
+            "waiting" => TR_STATUS_DOWNLOAD_WAIT,
+            _ => TR_STATUS_STOPPED,
+        };
+
+        let (error, error_string) = if status == "error" {
+            (TR_STAT_LOCAL_ERROR, row.error_message.clone().unwrap_or_default())
+        } else {
+            (TR_STAT_OK, String::new())
+        };
+
+        /* -1: not available, -2: unknown */
+        let eta = if rate_download > 0 {
+            left / rate_download
+        } else if complete {
+            -1
+        } else {
+            -2
+        };
+
+        let done_date = row.completed_at.map(|t| t.and_utc().timestamp()).unwrap_or(0);
+        let seconds_seeding = if done_date > 0 && tr_status == TR_STATUS_SEED {
+            (chrono::Utc::now().timestamp() - done_date).max(0)
+        } else {
+            0
+        };
+
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        let (files, file_stats): (Vec<Value>, Vec<Value>) = live
+            .map(|l| l.files.iter().map(|f| {
+                let length = Self::num(Some(&f.length));
+                let completed = Self::num(Some(&f.completed_length));
+                let wanted = f.selected == "true";
+                (
+                    json!({
+                        "name": f.path.strip_prefix(&prefix).unwrap_or(&f.path),
+                        "length": length,
+                        "bytesCompleted": completed,
+                    }),
+                    json!({
+                        "bytesCompleted": completed,
+                        "wanted": wanted,
+                        "priority": 0,
+                    }),
+                )
+            }).unzip())
+            .unwrap_or_default();
+
+        let labels: Vec<&str> = row.category.as_deref().into_iter().collect();
+
+        let obj = json!({
+            "id": row.id,
+            "hashString": row.info_hash.to_lowercase(),
+            "name": row.name,
+            "status": tr_status,
+            "error": error,
+            "errorString": error_string,
+            "totalSize": size,
+            "sizeWhenDone": size,
+            "leftUntilDone": left,
+            "haveValid": downloaded,
+            "percentDone": if size > 0 { downloaded as f64 / size as f64 } else { 0.0 },
+            "downloadedEver": downloaded,
+            "uploadedEver": uploaded,
+            "uploadRatio": if downloaded > 0 { uploaded as f64 / downloaded as f64 } else { -1.0 },
+            "rateDownload": rate_download,
+            "rateUpload": rate_upload,
+            "eta": eta,
+            "downloadDir": dir,
+            "addedDate": row.created_at.map(|t| t.and_utc().timestamp()).unwrap_or(0),
+            "doneDate": done_date,
+            "secondsSeeding": seconds_seeding,
+            "isFinished": complete && tr_status == TR_STATUS_STOPPED,
+            "isStalled": tr_status == TR_STATUS_DOWNLOAD && rate_download == 0,
+            "peersConnected": Self::num(live.and_then(|l| l.connection.as_deref())),
+            "peersSendingToUs": Self::num(live.and_then(|l| l.num_seeders.as_deref())),
+            "queuePosition": 0,
+            "labels": labels,
+            "files": files,
+            "fileStats": file_stats,
+            "seedRatioMode": 0,
+        });
+
+        match obj {
+            Value::Object(map) => map,
+            _ => Map::new(),
+        }
+    }
+
+    /// Only the asked `fields`, every field if none given
+    pub fn pick(obj: Map<String, Value>, fields: &[String]) -> Map<String, Value> {
+        if fields.is_empty() {
+            return obj;
+        }
+        let mut obj: HashMap<String, Value> = obj.into_iter().collect();
+        fields.iter()
+            .filter_map(|f| obj.remove_entry(f))
+            .collect()
+    }
+}
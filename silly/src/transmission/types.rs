@@ -0,0 +1,93 @@
+use chrono::NaiveDateTime;
+use serde_json::{Map, Value};
+use serde::{Deserialize, Serialize};
+
+/// Transmission RPC emulation
+/// Ref: https://github.com/transmission/transmission/blob/main/docs/rpc-spec.md
+pub struct TransmissionController;
+
+pub const TR_VERSION: &str = "4.0.5 (silly)";
+pub const TR_RPC_VERSION: i64 = 17;
+pub const TR_RPC_VERSION_MINIMUM: i64 = 14;
+pub const TR_SESSION_HEADER: &str = "X-Transmission-Session-Id";
+
+/// `tr_torrent_activity`
+pub const TR_STATUS_STOPPED: i64 = 0;
+pub const TR_STATUS_DOWNLOAD_WAIT: i64 = 3;
+pub const TR_STATUS_DOWNLOAD: i64 = 4;
+pub const TR_STATUS_SEED: i64 = 6;
+
+/// `tr_stat_errtype`, aria2 errors are always local
+pub const TR_STAT_OK: i64 = 0;
+pub const TR_STAT_LOCAL_ERROR: i64 = 3;
+
+#[derive(Deserialize, Debug)]
+pub struct RpcReq {
+    pub method: String,
+    #[serde(default)]
+    pub arguments: Map<String, Value>,
+    pub tag: Option<Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RpcResp {
+    /// "success" or the error string
+    pub result: String,
+    pub arguments: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TorrentGetArgs {
+    /// Number, hash, array of them or "recently-active"
+    pub ids: Option<Value>,
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TorrentIdsArgs {
+    pub ids: Option<Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TorrentRemoveArgs {
+    pub ids: Option<Value>,
+    #[serde(default)]
+    pub delete_local_data: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TorrentAddArgs {
+    /// Url or magnet
+    pub filename: Option<String>,
+    /// Base64 encoded `.torrent`
+    pub metainfo: Option<String>,
+    pub download_dir: Option<String>,
+    #[serde(default)]
+    pub paused: bool,
+    /// First label is stored as the category
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+/// Torrent rows of `download_history`, `id` is the transmission torrent id
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct TrHistoryRow {
+    pub id: i64,
+    pub gid: String,
+    pub name: String,
+    pub status: String,
+    pub dir: String,
+    pub total_length: Option<String>,
+    pub completed_length: Option<String>,
+    pub uploaded_length: Option<String>,
+    pub info_hash: String,
+    pub category: Option<String>,
+    pub error_message: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    pub completed_at: Option<NaiveDateTime>,
+}
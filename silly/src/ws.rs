@@ -0,0 +1,433 @@
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use axum::{
+    Json,
+    body::to_bytes,
+    extract::{
+        State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
+    },
+    http::{HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, MissedTickBehavior};
+use tracing::{debug, warn};
+
+use crate::{
+    AppState,
+    api::DownloadEvent,
+    aria2::{proxy, types::{Aria2Passthrough, AddUriReq, BatchAddTorrentRequest, GidRequest, GidOptionReq, GlobalOptionReq}},
+    his::{DdlWsMessage, EventCursor, TickDeltas, WatchGuard},
+    middleware::{decode_claims, require_permission, resolve},
+    auth::{
+        sessions::ACCESS_COOKIE,
+        types::{AuthenticatedUser, Permission, SessionMeta},
+    },
+};
+
+/// How often a subscribed gid's peers are sent
+const PEERS_EVERY: Duration = Duration::from_secs(2);
+/// Replies bigger than this are refused
+const MAX_REPLY_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Topic {
+    /// Same as `/api/ws/silly/status`
+    Status,
+    /// Progress of own active downloads, same as the ticks of `/api/ws/dl/history`
+    Ticks,
+    /// Own history rows as they change
+    History,
+    /// aria2 notifications, like `/api/ws/event`
+    Events,
+    /// Peers of one gid, needs `gid`
+    Peers,
+}
+
+/// What the client sends, `id` comes back on the reply
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum ClientMessage {
+    Subscribe {
+        id: Option<String>,
+        topic: Topic,
+        gid: Option<String>,
+        /// Events of every user, needs `ManageAllDownloads`
+        #[serde(default)]
+        all: bool,
+        /// History seq to resume after
+        since: Option<u64>,
+    },
+    Unsubscribe {
+        id: Option<String>,
+        topic: Topic,
+        gid: Option<String>,
+    },
+    Command {
+        id: Option<String>,
+        command: String,
+        #[serde(default)]
+        params: Value,
+    },
+}
+
+/// What a connection is subscribed to
+#[derive(Default)]
+struct Subscriptions {
+    status: bool,
+    ticks: Option<WatchGuard>,
+    history: Option<EventCursor>,
+    events: Option<bool>,
+    peers: HashSet<String>,
+}
+
+/// What the socket was opened with, checked again before every client message
+struct Credentials {
+    headers: HeaderMap,
+    jar: CookieJar,
+    /// Unix time the access cookie dies, `None` for api keys and the auth proxy
+    expires: Option<u64>,
+}
+
+impl Credentials {
+    /// The caller as the db sees it now, `None` once revoked, disabled or expired
+    async fn recheck(&self, state: &AppState, meta: &SessionMeta) -> Option<AuthenticatedUser> {
+        let (mut user, scope) = resolve(state, &self.headers, &self.jar, meta.ip.as_deref()).await.ok()?;
+        if user.must_change_password || !user.can(Permission::ViewDownloads) {
+            return None;
+        }
+        user.scope = scope;
+        Some(user)
+    }
+
+    /// Time left on the access cookie
+    fn lifetime(&self) -> Option<Duration> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.expires.map(|exp| Duration::from_secs(exp.saturating_sub(now)))
+    }
+}
+
+/*
+  * One socket for everything, after login
+  * {"op":"subscribe","id":"1","topic":"ticks"}
+  * {"op":"command","id":"2","command":"pause","params":{"gid":"..."}}
+  * Replies are {"type":"reply","id":"2","status":200,"ok":true,"data":{...}}
+  * Topic messages are {"topic":"ticks",...}
+*/
+pub async fn unified_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    headers: HeaderMap,
+    jar: CookieJar,
+) -> Response {
+    if let Err(e) = require_permission(&user, Permission::ViewDownloads) {
+        return e.into_response();
+    }
+    /* Only a session user came in with the cookie, api keys and the proxy win over it */
+    let expires = user.session_id.as_ref()
+        .and_then(|_| jar.get(ACCESS_COOKIE))
+        .and_then(|c| decode_claims(&state, c.value()))
+        .map(|claims| claims.exp as u64);
+    let creds = Credentials { headers, jar, expires };
+    ws.on_upgrade(move |socket| unified(socket, state, user, meta, creds))
+}
+
+async fn unified(
+    mut socket: WebSocket,
+    state: AppState,
+    mut user: AuthenticatedUser,
+    meta: SessionMeta,
+    creds: Credentials,
+) {
+    let mut status_rx = state.status_tx.subscribe();
+    let mut history_rx = state.history_tx.subscribe();
+    let mut events_rx = state.aria2.events.subscribe();
+    /* Commands run on their own, a slow add doesn't hold up the ticks */
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Value>(32);
+    let mut peers_every = time::interval(PEERS_EVERY);
+    peers_every.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let mut subs = Subscriptions::default();
+    let mut deltas = TickDeltas::default();
+    /* The cookie can't be refreshed on an open socket, the client reconnects */
+    let lifetime = creds.lifetime();
+    let expiry = time::sleep(lifetime.unwrap_or(Duration::ZERO));
+    tokio::pin!(expiry);
+
+    loop {
+        let out = tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(msg) => {
+                            /* Role, session and api key can change under an open socket */
+                            let Some(fresh) = creds.recheck(&state, &meta).await else {
+                                close(&mut socket, "Session ended").await;
+                                break;
+                            };
+                            user = fresh;
+                            handle(&state, &user, &meta, &mut subs, &mut deltas, &reply_tx, msg).await
+                        }
+                        Err(e) => vec![json!({ "type": "error", "error": e.to_string() })],
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => vec![],
+            },
+
+            Some(reply) = reply_rx.recv() => vec![reply],
+
+            _ = &mut expiry, if lifetime.is_some() => {
+                close(&mut socket, "Session expired").await;
+                break;
+            },
+
+            changed = status_rx.changed(), if subs.status => match changed {
+                Ok(()) => vec![json!({ "topic": "status", "data": *status_rx.borrow_and_update() })],
+                Err(_) => vec![],
+            },
+
+            msg = history_rx.recv() => match msg {
+                Ok(DdlWsMessage::Tick { user_id, global, instance, tasks }) if user_id == user.id && subs.ticks.is_some() => {
+                    let mut stats = json!({ "global": global });
+                    if user.can(Permission::ManageServer) {
+                        stats["instance"] = json!(instance);
+                    }
+                    deltas.next(stats, tasks).map(|mut tick| {
+                        tick["topic"] = json!("ticks");
+                        tick
+                    }).into_iter().collect()
+                }
+                Ok(DdlWsMessage::Event { seq, user_id, data }) => subs.history.as_mut()
+                    .and_then(|cursor| cursor.event(seq, user_id, &data))
+                    .map(history_topic)
+                    .into_iter().collect(),
+                Ok(_) => vec![],
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("unified socket of '{}' lagged {} history messages", user.username, n);
+                    deltas = TickDeltas::default();
+                    subs.history.as_mut()
+                        .map(|cursor| cursor.catch_up(&state).into_iter().map(history_topic).collect())
+                        .unwrap_or_default()
+                }
+                Err(_) => break,
+            },
+
+            msg = events_rx.recv() => match msg {
+                Ok(msg) => subs.events.and_then(|all| {
+                    let data = DownloadEvent::from_notification(&state, &msg)?;
+                    (all || data.user_id == Some(user.id))
+                        .then(|| json!({ "topic": "events", "data": data }))
+                }).into_iter().collect(),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("unified socket of '{}' lagged {} notifications", user.username, n);
+                    match subs.events {
+                        Some(_) => vec![json!({ "topic": "events", "type": "lagged", "missed": n })],
+                        None => vec![],
+                    }
+                }
+                Err(_) => break,
+            },
+
+            _ = peers_every.tick(), if !subs.peers.is_empty() => {
+                for gid in subs.peers.clone() {
+                    let data = state.aria2.call_background("getPeers", vec![json!(gid)]).await
+                        .unwrap_or_else(|e| json!({ "error": e }));
+                    let msg = json!({ "topic": "peers", "gid": gid, "data": data });
+                    if socket.send(Message::Text(msg.to_string().into())).await.is_err() {
+                        return;
+                    }
+                }
+                vec![]
+            },
+        };
+
+        for msg in out {
+            if socket.send(Message::Text(msg.to_string().into())).await.is_err() {
+                return;
+            }
+        }
+    }
+    debug!("unified socket of '{}' closed", user.username);
+}
+
+async fn close(socket: &mut WebSocket, reason: &'static str) {
+    let frame = CloseFrame { code: close_code::POLICY, reason: reason.into() };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
+
+fn history_topic(mut msg: Value) -> Value {
+    msg["topic"] = json!("history");
+    msg
+}
+
+/// Subscriptions answer right away, commands answer through `reply_tx`
+async fn handle(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    meta: &SessionMeta,
+    subs: &mut Subscriptions,
+    deltas: &mut TickDeltas,
+    reply_tx: &mpsc::Sender<Value>,
+    msg: ClientMessage,
+) -> Vec<Value> {
+    match msg {
+        ClientMessage::Subscribe { id, topic, gid, all, since } => {
+            let res = subscribe(state, user, subs, topic, gid, all).await;
+            if topic == Topic::Ticks {
+                // Whole tasks first
+                *deltas = TickDeltas::default();
+            }
+            let mut reply = vec![reply(id, res)];
+            if res.is_ok() && topic == Topic::Status {
+                reply[0]["data"] = json!(*state.status_tx.borrow());
+            }
+            if res.is_ok() && topic == Topic::History {
+                // What came after `since`, or where it starts from
+                let (cursor, missed) = EventCursor::resume(state, user.id, since);
+                subs.history = Some(cursor);
+                reply.extend(missed.into_iter().map(history_topic));
+            }
+            reply
+        }
+        ClientMessage::Unsubscribe { id, topic, gid } => {
+            match topic {
+                Topic::Status => subs.status = false,
+                Topic::Ticks => subs.ticks = None,
+                Topic::History => subs.history = None,
+                Topic::Events => subs.events = None,
+                Topic::Peers => {
+                    match gid {
+                        Some(gid) => subs.peers.remove(&gid),
+                        None => { subs.peers.clear(); true }
+                    };
+                }
+            }
+            vec![reply(id, Ok(()))]
+        }
+        ClientMessage::Command { id, command, params } => {
+            let state = state.clone();
+            let user = user.clone();
+            let meta = meta.clone();
+            let reply_tx = reply_tx.clone();
+            tokio::spawn(async move {
+                let res = command_response(state, user, meta, &command, params).await;
+                let (status, data) = match res {
+                    Some(res) => {
+                        let status = res.status();
+                        let body = to_bytes(res.into_body(), MAX_REPLY_BYTES).await.unwrap_or_default();
+                        (status.as_u16(), serde_json::from_slice(&body).unwrap_or(Value::Null))
+                    }
+                    None => (400, json!({ "error": format!("Unknown command '{}' or bad params", command) })),
+                };
+                let _ = reply_tx.send(json!({
+                    "type": "reply",
+                    "id": id,
+                    "status": status,
+                    "ok": (200..300).contains(&status),
+                    "data": data,
+                })).await;
+            });
+            vec![]
+        }
+    }
+}
+
+async fn subscribe(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    subs: &mut Subscriptions,
+    topic: Topic,
+    gid: Option<String>,
+    all: bool,
+) -> Result<(), (u16, &'static str)> {
+    match topic {
+        Topic::Status => subs.status = true,
+        Topic::Ticks => {
+            if subs.ticks.is_none() {
+                subs.ticks = Some(state.watchers.watch(user));
+            }
+        }
+        // The cursor is set by the caller, it needs `since`
+        Topic::History => {}
+        Topic::Events => {
+            if all && !user.can(Permission::ManageAllDownloads) {
+                return Err((403, "Not allowed to see everyone's events"));
+            }
+            subs.events = Some(all);
+        }
+        Topic::Peers => {
+            let Some(gid) = gid else {
+                return Err((400, "Peers need a gid"));
+            };
+            if !Aria2Passthrough::can_touch(state, user, &gid).await {
+                return Err((404, "GID is not found"));
+            }
+            subs.peers.insert(gid);
+        }
+    }
+    Ok(())
+}
+
+fn reply(id: Option<String>, res: Result<(), (u16, &'static str)>) -> Value {
+    match res {
+        Ok(()) => json!({ "type": "reply", "id": id, "status": 200, "ok": true }),
+        Err((status, error)) => json!({
+            "type": "reply",
+            "id": id,
+            "status": status,
+            "ok": false,
+            "data": { "error": error },
+        }),
+    }
+}
+
+/// The REST route each command stands for, api key scopes are checked against it
+fn command_path(command: &str) -> Option<&'static str> {
+    Some(match command {
+        "add" => "/api/aria2/add",
+        "addTorrents" => "/api/aria2/add/torrents",
+        "pause" => "/api/aria2/pause",
+        "resume" => "/api/aria2/resume",
+        "remove" => "/api/aria2/remove",
+        "changeOption" => "/api/aria2/option",
+        "changeGlobalOption" => "/api/aria2/global",
+        _ => return None,
+    })
+}
+
+/// Runs the REST handler of `command`, `None` if there's none or the params don't fit
+async fn command_response(
+    state: AppState,
+    user: AuthenticatedUser,
+    meta: SessionMeta,
+    command: &str,
+    params: Value,
+) -> Option<Response> {
+    // The upgrade was a GET, a read only key got in with it
+    if !user.scope.allows(&Method::POST, command_path(command)?) {
+        return Some((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Api key scope does not allow this action" })),
+        ).into_response());
+    }
+    let state = State(state);
+    let res = match command {
+        "add" => proxy::add_uris(state, user, meta, Json(serde_json::from_value::<AddUriReq>(params).ok()?)).await.into_response(),
+        "addTorrents" => proxy::add_torrents(state, user, meta, Json(serde_json::from_value::<BatchAddTorrentRequest>(params).ok()?)).await.into_response(),
+        "pause" => proxy::pause_download(state, user, Json(serde_json::from_value::<GidRequest>(params).ok()?)).await.into_response(),
+        "resume" => proxy::resume_download(state, user, Json(serde_json::from_value::<GidRequest>(params).ok()?)).await.into_response(),
+        "remove" => proxy::remove_download(state, user, meta, Json(serde_json::from_value::<GidRequest>(params).ok()?)).await.into_response(),
+        "changeOption" => proxy::change_option(state, user, Json(serde_json::from_value::<GidOptionReq>(params).ok()?)).await.into_response(),
+        "changeGlobalOption" => proxy::change_global_option(state, user, meta, Json(serde_json::from_value::<GlobalOptionReq>(params).ok()?)).await.into_response(),
+        _ => return None,
+    };
+    Some(res)
+}